@@ -1,6 +1,11 @@
+use nix::fcntl::{Flock, FlockArg};
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::io::Error;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::Path;
 use sys_info::{hostname, os_release, os_type};
 use users::{get_current_uid, get_user_by_uid};
 
@@ -16,19 +21,52 @@ pub struct Device {
     pub os_name: String,
     pub os_version: String,
     pub supports_encryption: bool,
+    #[serde(default)]
+    pub app_data: Value,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
 pub struct Sensor {
     #[serde(flatten)]
     pub state: SensorState,
     pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_class: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit_of_measurement: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_class: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<String>,
+    /// set once the sensor's monitor is gone from the config, and back to false if it returns
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disabled: Option<bool>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+impl Sensor {
+    pub fn is_disabled(&self) -> bool {
+        self.disabled == Some(true)
+    }
+
+    /// Whether Home Assistant knows the sensor as it is, leaving out its state.
+    fn same_registration(&self, other: &Sensor) -> bool {
+        self.name == other.name
+            && self.device_class == other.device_class
+            && self.unit_of_measurement == other.unit_of_measurement
+            && self.state_class == other.state_class
+            && self.entity_category == other.entity_category
+            && self.state.sensor_type == other.state.sensor_type
+            && self.state.icon == other.state.icon
+            && self.is_disabled() == other.is_disabled()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
 pub struct SensorState {
     #[serde(rename = "state")]
-    pub value: bool,
+    pub value: Value,
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub attributes: Map<String, Value>,
     pub unique_id: String,
     #[serde(rename = "type")]
     pub sensor_type: String,
@@ -43,12 +81,71 @@ pub struct WebhookInfo {
     pub webhook_id: Option<String>,
 }
 
+impl WebhookInfo {
+    /// How sensor updates reach Home Assistant, see `connection::webhook_url`.
+    pub fn route(&self) -> &'static str {
+        if self.cloudhook_url.is_some() {
+            "cloudhook"
+        } else if self.webhook_id.is_none() {
+            "none"
+        } else if self.remote_ui_url.is_some() {
+            "remote UI"
+        } else {
+            "local"
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ZoneAttributes {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// meters
+    pub radius: f64,
+    #[serde(default)]
+    pub passive: bool,
+    #[serde(default)]
+    pub friendly_name: Option<String>,
+}
+
+/// A zone as returned by the get_zones webhook, which is the zone entity's state.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Zone {
+    pub entity_id: String,
+    pub attributes: ZoneAttributes,
+}
+
+impl Zone {
+    /// The name a device tracker reports while in the zone, `home` for the home zone.
+    pub fn name(&self) -> String {
+        if self.entity_id == "zone.home" {
+            return "home".to_string();
+        }
+        self.attributes
+            .friendly_name
+            .clone()
+            .unwrap_or_else(|| self.entity_id.trim_start_matches("zone.").to_string())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct State {
     pub registered: bool,
     pub device: Device,
     pub webhook_info: WebhookInfo,
     pub sensors: Vec<Sensor>,
+    #[serde(default)]
+    pub zones: Vec<Zone>,
+    /// what Home Assistant was last sent per sensor, by unique id
+    #[serde(default)]
+    pub last_sent: BTreeMap<String, SentState>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SentState {
+    pub state: SensorState,
+    /// seconds since the epoch
+    pub sent_at: u64,
 }
 
 impl State {
@@ -64,20 +161,24 @@ impl State {
         let webcam_sensor = Sensor {
             name: "Webcam".to_string(),
             state: SensorState {
-                value: false,
+                value: json!(false),
                 unique_id: "webcam".to_string(),
                 sensor_type: "binary_sensor".to_string(),
                 icon: "mdi:webcam".to_string(),
+                ..Default::default()
             },
+            ..Default::default()
         };
         let microphone_sensor = Sensor {
             name: "Microphone".to_string(),
             state: SensorState {
-                value: false,
+                value: json!(false),
                 unique_id: "microphone".to_string(),
                 sensor_type: "binary_sensor".to_string(),
                 icon: "mdi:microphone".to_string(),
+                ..Default::default()
             },
+            ..Default::default()
         };
 
         Self {
@@ -93,6 +194,7 @@ impl State {
                 os_name,
                 os_version,
                 supports_encryption: false,
+                app_data: json!({ "push_websocket_channel": true }),
             },
             webhook_info: WebhookInfo {
                 cloudhook_url: None,
@@ -101,21 +203,47 @@ impl State {
                 webhook_id: None,
             },
             sensors: vec![webcam_sensor, microphone_sensor],
+            zones: vec![],
+            last_sent: BTreeMap::new(),
         }
     }
     //init AgentMetadata
-    pub fn init(state_path: &str) -> Self {
-        //if state_path is empty, return default values
+    pub fn init(state_path: &str) -> Result<Self, Error> {
+        // only a missing file means a new device, anything else would orphan its registration
         match Self::load_state(state_path) {
-            Ok(state) => state,
-            Err(_) => Self::new(),
+            Ok(state) => Ok(state),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::new()),
+            Err(e) => Err(Error::new(
+                e.kind(),
+                format!(
+                    "Failed to read {}: {}. Fix it, or remove it to register as a new device",
+                    state_path, e
+                ),
+            )),
         }
     }
 
+    /// Writes the state to a temporary file first, so a crash halfway leaves the old one intact.
+    /// Only the user can read it, as it holds the webhook id and secret.
     pub fn save_state(&self, path: &str) -> Result<(), Error> {
         let json = serde_json::to_string_pretty(&self)?;
-        fs::write(path, json)?;
-        println!("Saved state to {}", path);
+        let path = Path::new(path);
+        create_state_dir(path)?;
+        let temp_path = path.with_extension("json.tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temp_path)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        // the rename only sticks once the directory is on disk too
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            File::open(dir)?.sync_all()?;
+        }
+        println!("Saved state to {}", path.display());
         Ok(())
     }
 
@@ -133,11 +261,146 @@ impl State {
         }
         None
     }
+
+    /// Leaves out the states Home Assistant already has, as far as the agent knows.
+    pub fn unsent_states(&self, states: Vec<SensorState>) -> Vec<SensorState> {
+        states
+            .into_iter()
+            .filter(|state| {
+                self.last_sent
+                    .get(&state.unique_id)
+                    .is_none_or(|sent| sent.state != *state)
+            })
+            .collect()
+    }
+
+    pub fn mark_sent(&mut self, states: &[SensorState], sent_at: u64) {
+        for state in states {
+            let sent = SentState {
+                state: state.clone(),
+                sent_at,
+            };
+            self.last_sent.insert(state.unique_id.clone(), sent);
+        }
+    }
+
+    /// The states sent when the agent stops: the webcam and microphone are off, everything else
+    /// is unknown until it's back.
+    pub fn offline_states(&self) -> Vec<SensorState> {
+        self.sensors
+            .iter()
+            .filter(|sensor| !sensor.is_disabled())
+            .map(|sensor| SensorState {
+                value: match sensor.state.unique_id.as_str() {
+                    "webcam" | "microphone" => json!(false),
+                    _ => Value::Null,
+                },
+                attributes: Map::new(),
+                ..sensor.state.clone()
+            })
+            .collect()
+    }
+
+    //returns the sensors that are new or registered differently, adding or updating them in the state
+    pub fn add_missing_sensors(&mut self, sensors: Vec<Sensor>) -> Vec<Sensor> {
+        let changed = self.unregistered_sensors(sensors);
+        self.update_sensors(&changed);
+        changed
+    }
+
+    /// The sensors that are new or registered differently, to register before they're added to
+    /// the state with `update_sensors`.
+    pub fn unregistered_sensors(&self, sensors: Vec<Sensor>) -> Vec<Sensor> {
+        let mut changed = vec![];
+        for mut sensor in sensors {
+            match self.get_sensor_by_unique_id(&sensor.state.unique_id) {
+                None => changed.push(sensor),
+                Some(known) if !known.same_registration(&sensor) => {
+                    if known.is_disabled() {
+                        sensor.disabled = Some(false);
+                    }
+                    changed.push(sensor);
+                }
+                Some(_) => {}
+            }
+        }
+        changed
+    }
+
+    /// The sensors that aren't among `sensors` any more, disabled, to register before they're
+    /// updated in the state with `update_sensors`.
+    pub fn removed_sensors(&self, sensors: &[Sensor]) -> Vec<Sensor> {
+        self.sensors
+            .iter()
+            .filter(|known| {
+                !known.is_disabled()
+                    && !sensors
+                        .iter()
+                        .any(|sensor| sensor.state.unique_id == known.state.unique_id)
+            })
+            .map(|known| Sensor {
+                disabled: Some(true),
+                ..known.clone()
+            })
+            .collect()
+    }
+
+    /// Adds or replaces sensors once Home Assistant accepted their registration.
+    pub fn update_sensors(&mut self, sensors: &[Sensor]) {
+        for sensor in sensors {
+            // registering sends the sensor's default state, the real one has to follow
+            self.last_sent.remove(&sensor.state.unique_id);
+            match self
+                .sensors
+                .iter_mut()
+                .find(|known| known.state.unique_id == sensor.state.unique_id)
+            {
+                Some(known) => *known = sensor.clone(),
+                None => self.sensors.push(sensor.clone()),
+            }
+        }
+    }
+}
+
+fn create_state_dir(path: &Path) -> Result<(), Error> {
+    match path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        Some(dir) => DirBuilder::new().recursive(true).mode(0o700).create(dir),
+        None => Ok(()),
+    }
+}
+
+/// Keeps a second agent from using the same state file, which would have them overwrite each
+/// other's registration. Held until dropped.
+pub struct StateLock {
+    _file: Flock<File>,
+}
+
+impl StateLock {
+    pub fn acquire(state_path: &str) -> Result<Self, Error> {
+        let path = Path::new(state_path).with_extension("json.lock");
+        create_state_dir(&path)?;
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(&path)?;
+        match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(file) => Ok(StateLock { _file: file }),
+            Err((_, nix::errno::Errno::EWOULDBLOCK)) => Err(Error::new(
+                ErrorKind::WouldBlock,
+                format!("Another agent is already using {}", state_path),
+            )),
+            Err((_, errno)) => Err(errno.into()),
+        }
+    }
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
+    use std::os::unix::fs::PermissionsExt;
     use tempfile::tempdir;
 
     #[test]
@@ -155,7 +418,7 @@ mod tests {
 
     #[test]
     fn test_init_state_with_empty_path() {
-        let state = State::init("");
+        let state = State::init("").unwrap();
 
         assert_eq!(state.registered, false);
         assert_eq!(state.device.device_id.contains('@'), true);
@@ -179,6 +442,42 @@ mod tests {
         assert_eq!(state, loaded_state);
     }
 
+    #[test]
+    fn test_save_state_privately() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("ha-agent-rs").join("state.json");
+        let state = State::new();
+
+        state.save_state(file_path.to_str().unwrap()).unwrap();
+        state.save_state(file_path.to_str().unwrap()).unwrap();
+
+        let mode = fs::metadata(&file_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(fs::read_dir(file_path.parent().unwrap()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_init_keeps_corrupt_state() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("state.json");
+        fs::write(&file_path, "{\"registered\": tr").unwrap();
+
+        assert!(State::init(file_path.to_str().unwrap()).is_err());
+        assert!(file_path.exists());
+    }
+
+    #[test]
+    fn test_state_lock() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("state.json");
+        let file_path = file_path.to_str().unwrap();
+
+        let lock = StateLock::acquire(file_path).unwrap();
+        assert_eq!(StateLock::acquire(file_path).err().unwrap().kind(), ErrorKind::WouldBlock);
+        drop(lock);
+        assert!(StateLock::acquire(file_path).is_ok());
+    }
+
     #[test]
     fn test_get_sensor_by_unique_id() {
         let state = State::new();
@@ -187,4 +486,128 @@ mod tests {
         assert!(state.get_sensor_by_unique_id("microphone").is_some());
         assert!(state.get_sensor_by_unique_id("nonexistent").is_none());
     }
+
+    #[test]
+    fn test_add_missing_sensors() {
+        let mut state = State::new();
+        let webcam = state.get_sensor_by_unique_id("webcam").unwrap();
+        let media_title = Sensor {
+            name: "Media Title".to_string(),
+            state: SensorState {
+                unique_id: "media_title".to_string(),
+                sensor_type: "sensor".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let missing = state.add_missing_sensors(vec![webcam, media_title]);
+
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].state.unique_id, "media_title");
+        assert_eq!(state.sensors.len(), 3);
+    }
+
+    #[test]
+    fn test_unregistered_sensors() {
+        let mut state = State::new();
+        let mut webcam = state.get_sensor_by_unique_id("webcam").unwrap();
+        webcam.name = "Camera".to_string();
+
+        // nothing changes until Home Assistant accepted the registration
+        assert_eq!(state.unregistered_sensors(vec![webcam.clone()]), vec![webcam.clone()]);
+        assert_eq!(state.get_sensor_by_unique_id("webcam").unwrap().name, "Webcam");
+
+        state.update_sensors(&[webcam.clone()]);
+        assert!(state.unregistered_sensors(vec![webcam]).is_empty());
+    }
+
+    #[test]
+    fn test_sensors_changing() {
+        let mut state = State::new();
+        let mut webcam = state.get_sensor_by_unique_id("webcam").unwrap();
+        let microphone = state.get_sensor_by_unique_id("microphone").unwrap();
+        webcam.name = "Camera".to_string();
+
+        let changed = state.add_missing_sensors(vec![webcam.clone(), microphone.clone()]);
+        assert_eq!(changed, vec![webcam.clone()]);
+
+        let disabled = state.removed_sensors(&[webcam.clone()]);
+        assert_eq!(disabled.len(), 1);
+        assert!(disabled[0].is_disabled());
+        assert!(!state.get_sensor_by_unique_id("microphone").unwrap().is_disabled());
+        state.update_sensors(&disabled);
+        assert!(state.removed_sensors(&[webcam.clone()]).is_empty());
+        assert_eq!(state.offline_states().len(), 1);
+
+        let enabled = state.add_missing_sensors(vec![microphone]);
+        assert_eq!(enabled[0].disabled, Some(false));
+        assert!(!state.get_sensor_by_unique_id("microphone").unwrap().is_disabled());
+    }
+
+    #[test]
+    fn test_unsent_states() {
+        let mut state = State::new();
+        let webcam = state.get_sensor_by_unique_id("webcam").unwrap().state;
+        let microphone = state.get_sensor_by_unique_id("microphone").unwrap().state;
+        state.mark_sent(&[webcam.clone(), microphone.clone()], 1_700_000_000);
+
+        let in_use = SensorState {
+            value: json!(true),
+            ..webcam.clone()
+        };
+        assert_eq!(state.unsent_states(vec![in_use.clone(), microphone.clone()]), vec![in_use]);
+
+        // registering a sensor again resets its state in Home Assistant
+        let mut renamed = state.get_sensor_by_unique_id("microphone").unwrap();
+        renamed.name = "Mic".to_string();
+        state.add_missing_sensors(vec![renamed]);
+        assert_eq!(state.unsent_states(vec![microphone.clone()]), vec![microphone]);
+        assert_eq!(state.last_sent["webcam"].sent_at, 1_700_000_000);
+    }
+
+    #[test]
+    fn test_offline_states() {
+        let mut state = State::new();
+        state.sensors[0].state.value = json!(true);
+        state.add_missing_sensors(vec![Sensor {
+            name: "Media Title".to_string(),
+            state: SensorState {
+                value: json!("Song 2"),
+                attributes: Map::from_iter([("artist".to_string(), json!("Blur"))]),
+                unique_id: "media_title".to_string(),
+                sensor_type: "sensor".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }]);
+
+        let states = state.offline_states();
+
+        assert_eq!(states[0].value, json!(false));
+        assert_eq!(states[2].value, Value::Null);
+        assert!(states[2].attributes.is_empty());
+        assert_eq!(states[2].unique_id, "media_title");
+    }
+
+    #[test]
+    fn test_zone_names() {
+        let zones: Vec<Zone> = serde_json::from_value(json!([
+            {
+                "entity_id": "zone.home",
+                "state": "1",
+                "attributes": {"latitude": 59.33, "longitude": 18.06, "radius": 100, "passive": false, "friendly_name": "Villa"}
+            },
+            {
+                "entity_id": "zone.office",
+                "state": "0",
+                "attributes": {"latitude": 59.34, "longitude": 18.07, "radius": 250.5, "friendly_name": "Office"}
+            }
+        ]))
+        .unwrap();
+
+        assert_eq!(zones[0].name(), "home");
+        assert_eq!(zones[1].name(), "Office");
+        assert_eq!(zones[1].attributes.radius, 250.5);
+    }
 }
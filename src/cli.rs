@@ -0,0 +1,296 @@
+// The subcommands besides `run`, for setting up an install and finding out what's wrong with one.
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use anyhow::{anyhow, Error};
+use chrono::DateTime;
+use serde_json::{json, Value};
+use zbus::Connection;
+
+use crate::agent_state::{State, StateLock};
+use crate::config::Config;
+use crate::connection::{self, Session};
+use crate::monitor::network::NetworkBackend;
+use crate::monitor::power::PowerBackend;
+use crate::monitor::{self, active_window, containers};
+
+/// Registers the device as a new one, whether or not the state file says it already is.
+pub async fn register(config: &Config) -> Result<(), Error> {
+    let _lock = StateLock::acquire(&config.state_file)?;
+    let mut session = Session::connect(config).await?;
+    let mut state = State::new();
+    println!("Registering device with {}", config.hass_url);
+    session.register(&mut state).await?;
+    let new_sensors = state.add_missing_sensors(monitor::enabled_sensors(&config.monitors).await);
+    session.register_sensors(&new_sensors).await?;
+    state.save_state(&config.state_file)?;
+    println!(
+        "Registered {} with {} sensors",
+        state.device.device_id,
+        state.sensors.len()
+    );
+    Ok(())
+}
+
+/// The config entry a mobile app device was registered with, from the device registry.
+fn find_config_entry(devices: &Value, device_id: &str) -> Option<String> {
+    let identifier = json!(["mobile_app", device_id]);
+    devices
+        .as_array()?
+        .iter()
+        .find(|device| {
+            device["identifiers"]
+                .as_array()
+                .is_some_and(|identifiers| identifiers.contains(&identifier))
+        })
+        .and_then(|device| device["config_entries"][0].as_str())
+        .map(str::to_string)
+}
+
+/// Removes the device from Home Assistant and the state file along with it.
+pub async fn unregister(config: &Config) -> Result<(), Error> {
+    let _lock = StateLock::acquire(&config.state_file)?;
+    let state = State::load_state(&config.state_file)
+        .map_err(|e| anyhow!("Nothing to unregister, can't read {}: {}", config.state_file, e))?;
+    let mut session = Session::connect(config).await?;
+    let devices = session.call(json!({ "type": "config/device_registry/list" })).await?;
+    match find_config_entry(&devices, &state.device.device_id) {
+        Some(entry_id) => {
+            session.remove_config_entry(&entry_id).await?;
+            println!("Removed {} from {}", state.device.device_id, config.hass_url);
+        }
+        None => println!(
+            "{} isn't registered with {}, clearing the state anyway",
+            state.device.device_id, config.hass_url
+        ),
+    }
+    fs::remove_file(&config.state_file)?;
+    println!("Removed {}", config.state_file);
+    Ok(())
+}
+
+/// Webhook ids work like passwords, so only their start is shown.
+fn redact(url: &str) -> String {
+    match url.rsplit_once('/') {
+        Some((base, id)) if !id.is_empty() => format!("{}/{}…", base, id.chars().take(4).collect::<String>()),
+        _ => url.to_string(),
+    }
+}
+
+fn sent_at(timestamp: u64) -> String {
+    DateTime::from_timestamp(timestamp as i64, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+pub fn status(config: &Config) -> Result<(), Error> {
+    let state = match State::load_state(&config.state_file) {
+        Ok(state) => state,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            println!("Not registered, there's no {}", config.state_file);
+            return Ok(());
+        }
+        Err(e) => return Err(anyhow!("Failed to read {}: {}", config.state_file, e)),
+    };
+    let webhook_url = connection::webhook_url(
+        config.hass_url.scheme(),
+        &connection::hass_address(&config.hass_url).unwrap_or_default(),
+        &state.webhook_info,
+    );
+    println!("State file:  {}", config.state_file);
+    println!("Registered:  {}", if state.registered { "yes" } else { "no" });
+    println!(
+        "Device:      {} ({} {})",
+        state.device.device_id, state.device.app_id, state.device.app_version
+    );
+    println!("Webhook:     {} {}", state.webhook_info.route(), redact(&webhook_url));
+    println!("Zones:       {}", state.zones.len());
+    println!("Sensors:     {}", state.sensors.len());
+    for sensor in &state.sensors {
+        let last_sent = match state.last_sent.get(&sensor.state.unique_id) {
+            Some(sent) => format!("{} at {}", sent.state.value, sent_at(sent.sent_at)),
+            None => "never sent".to_string(),
+        };
+        let disabled = if sensor.is_disabled() { " (disabled)" } else { "" };
+        println!("  {:<36} {}{}", sensor.state.unique_id, last_sent, disabled);
+    }
+    Ok(())
+}
+
+fn require(condition: bool, reason: &str) -> Result<(), Error> {
+    if condition {
+        Ok(())
+    } else {
+        Err(anyhow!("{}", reason))
+    }
+}
+
+/// Whether a D-Bus service is running, or can be started when it's first called.
+async fn has_service(bus: Option<&Connection>, name: &str) -> Result<(), Error> {
+    let bus = bus.ok_or(anyhow!("no system bus"))?;
+    let dbus = zbus::fdo::DBusProxy::new(bus).await?;
+    let names = [dbus.list_names().await?, dbus.list_activatable_names().await?].concat();
+    require(
+        names.iter().any(|service| service.as_str() == name),
+        &format!("{} isn't running", name),
+    )
+}
+
+/// Whether what a monitor reads from is there on this machine.
+async fn availability(
+    name: &str,
+    config: &Config,
+    system_bus: Option<&Connection>,
+    session_bus: Option<&Connection>,
+) -> Result<(), Error> {
+    let monitors = &config.monitors;
+    match name {
+        "webcam" => require(Path::new("/dev/video0").exists(), "no /dev/video0"),
+        "microphone" => require(Path::new("/proc/asound").exists(), "no ALSA"),
+        "mpris" => require(session_bus.is_some(), "no session bus"),
+        "session_state" | "sessions" => has_service(system_bus, "org.freedesktop.login1").await,
+        "active_window" => require(
+            active_window::detect_backend().is_some(),
+            "no X11, sway or Hyprland session",
+        ),
+        "power" => match monitors.power.backend {
+            PowerBackend::Sysfs => require(Path::new("/sys/class/power_supply").exists(), "no power supplies"),
+            PowerBackend::Upower => has_service(system_bus, "org.freedesktop.UPower").await,
+        },
+        "network" if monitors.network.backend == NetworkBackend::NetworkManager => {
+            has_service(system_bus, "org.freedesktop.NetworkManager").await
+        }
+        "bluetooth" => has_service(system_bus, "org.bluez").await,
+        "systemd" => has_service(system_bus, "org.freedesktop.systemd1").await,
+        "containers" => require(
+            monitors
+                .containers
+                .socket
+                .clone()
+                .or_else(containers::find_socket)
+                .is_some_and(|socket| socket.exists()),
+            "no Docker or Podman socket",
+        ),
+        "updates" => require(!monitors.updates.managers().is_empty(), "no supported package manager"),
+        "location" if monitors.location.geoclue => has_service(system_bus, "org.freedesktop.GeoClue2").await,
+        _ => Ok(()),
+    }
+}
+
+pub async fn list_sensors(config: &Config) -> Result<(), Error> {
+    let system_bus = Connection::system().await.ok();
+    let session_bus = Connection::session().await.ok();
+    for monitor in monitor::monitor_sensors(&config.monitors).await {
+        let available = match availability(monitor.name, config, system_bus.as_ref(), session_bus.as_ref()).await {
+            Ok(()) => "available".to_string(),
+            Err(e) => format!("unavailable: {}", e),
+        };
+        let enabled = if monitor.enabled { "enabled" } else { "disabled" };
+        println!("{} ({}, {})", monitor.name, enabled, available);
+        for sensor in monitor.sensors {
+            println!(
+                "  {:<36} {} [{}]",
+                sensor.state.unique_id, sensor.name, sensor.state.sensor_type
+            );
+        }
+    }
+    Ok(())
+}
+
+fn report(check: &str, result: &Result<String, Error>) -> bool {
+    match result {
+        Ok(detail) => println!("✓ {}: {}", check, detail),
+        Err(e) => println!("✗ {}: {}", check, e),
+    }
+    result.is_ok()
+}
+
+pub async fn test_connection(config: &Config) -> Result<(), Error> {
+    let mut ok = report("Access token", &connection::check_api(config).await);
+    let mut session = match Session::connect(config).await {
+        Ok(session) => session,
+        Err(e) => {
+            report("WebSocket", &Err(e));
+            return Err(anyhow!("Can't connect to {}", config.hass_url));
+        }
+    };
+    let websocket = session
+        .ws()
+        .get_config()
+        .await
+        .map(|hass_config| format!("Home Assistant {}", hass_config.version));
+    ok &= report("WebSocket", &websocket);
+    let webhook = match State::load_state(&config.state_file) {
+        Ok(state) if state.registered => {
+            session.update_webhook_url(&state.webhook_info);
+            session
+                .get_webhook_config()
+                .await
+                .map(|_| format!("reachable through the {} route", state.webhook_info.route()))
+        }
+        _ => Err(anyhow!("not registered, run `ha-agent-rs register` first")),
+    };
+    ok &= report("Webhook", &webhook);
+    require(ok, "Some checks failed")
+}
+
+fn parse_json(json: &str, what: &str) -> Result<Value, Error> {
+    serde_json::from_str(json).map_err(|e| anyhow!("The {} isn't valid JSON: {}", what, e))
+}
+
+pub async fn call_service(config: &Config, service: &str, data: &str, target: Option<&str>) -> Result<(), Error> {
+    let (domain, service) = service
+        .split_once('.')
+        .ok_or(anyhow!("Services are named like light.turn_on, not {}", service))?;
+    let data = parse_json(data, "service data")?;
+    let target = target.map(|target| parse_json(target, "target")).transpose()?;
+    let session = Session::connect(config).await?;
+    let response = session.ws().call_service(domain, service, data, target).await?;
+    println!("{}", serde_json::to_string_pretty(&response)?);
+    session.close().await
+}
+
+pub async fn render_template(config: &Config, template: &str) -> Result<(), Error> {
+    let session = Session::connect(config).await?;
+    match session.ws().render_template(template).await? {
+        Value::String(rendered) => println!("{}", rendered),
+        rendered => println!("{}", rendered),
+    }
+    session.close().await
+}
+
+/// Prints events until the connection closes, or the agent is stopped.
+pub async fn events(config: &Config, event_type: Option<&str>) -> Result<(), Error> {
+    let session = Session::connect(config).await?;
+    let mut events = session.ws().subscribe_events(event_type).await?;
+    while let Some(event) = events.next().await {
+        println!("{} {} {}", event.time_fired, event.event_type, event.data);
+    }
+    Err(anyhow!("Connection to {} closed", config.hass_url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_config_entry() {
+        let devices = json!([
+            {"identifiers": [["hue", "0017880100000000"]], "config_entries": ["a1"]},
+            {"identifiers": [["mobile_app", "alex@laptop"]], "config_entries": ["b2"]},
+        ]);
+
+        assert_eq!(find_config_entry(&devices, "alex@laptop").as_deref(), Some("b2"));
+        assert_eq!(find_config_entry(&devices, "sam@desktop"), None);
+    }
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact("https://ha.local/api/webhook/4e2b1cde9f"),
+            "https://ha.local/api/webhook/4e2b…"
+        );
+        assert_eq!(redact(""), "");
+    }
+}
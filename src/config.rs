@@ -1,6 +1,24 @@
-use std::env;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
 use structopt::StructOpt;
 
+use crate::monitor::active_window::TitlePrivacy;
+use crate::monitor::bluetooth::BluetoothConfig;
+use crate::monitor::commands::CommandSensorConfig;
+use crate::monitor::containers::ContainersConfig;
+use crate::monitor::files::FileSensorConfig;
+use crate::monitor::location::LocationConfig;
+use crate::monitor::network::NetworkConfig;
+use crate::monitor::power::PowerConfig;
+use crate::monitor::system::SystemConfig;
+use crate::monitor::sessions::SessionsConfig;
+use crate::monitor::systemd::SystemdConfig;
+use crate::monitor::updates::UpdatesConfig;
+use crate::mirror::MirrorConfig;
+use crate::scheduler::SchedulerConfig;
+use crate::transport::TransportConfig;
+
 #[derive(Debug, StructOpt)]
 /// A BLAZINGLY fast agent for Home Assistant
 struct Arguments {
@@ -11,14 +29,131 @@ struct Arguments {
     /// The long-lived access token for your Home Assistant user. Read more here: https://www.home-assistant.io/docs/authentication/#your-account-profile
     pub hass_token: Option<String>,
     #[structopt(long="state-file", short="f")]
-    /// The file to store the state of the agent in (default: haars.json)
+    /// The file to store the state of the agent in (default: $XDG_STATE_HOME/ha-agent-rs/haars.json)
     pub state_file: Option<String>,
+    #[structopt(long="window-title")]
+    /// How to report the title of the focused window: show, hash or omit (default: show)
+    pub window_title: Option<String>,
+    #[structopt(long="config", short="c")]
+    /// The TOML file configuring the monitors (default: haars.toml)
+    pub config_file: Option<String>,
+    #[structopt(long="ca-file")]
+    /// A PEM file with certificates to trust, e.g. the one of a self-signed Home Assistant
+    pub ca_file: Option<String>,
+    #[structopt(long="client-cert")]
+    /// A PEM client certificate, for a Home Assistant behind mutual TLS
+    pub client_cert: Option<String>,
+    #[structopt(long="client-key")]
+    /// The PKCS#8 PEM key of the client certificate
+    pub client_key: Option<String>,
+    #[structopt(long="insecure")]
+    /// Don't verify Home Assistant's certificate at all
+    pub insecure: bool,
+    #[structopt(subcommand)]
+    pub command: Option<Subcommand>,
+}
+
+#[derive(Clone, Debug, PartialEq, StructOpt)]
+pub enum Subcommand {
+    /// Run the agent (the default)
+    Run,
+    /// Register the device with Home Assistant again, even if it already is
+    Register,
+    /// Remove the device from Home Assistant and clear the state file
+    Unregister,
+    /// Show the registration, the webhook route and the last known sensor values
+    Status,
+    /// List every monitor, whether it can run on this machine and its sensors
+    ListSensors,
+    /// Check the access token, the WebSocket and the webhook
+    TestConnection,
+    /// Call a service, e.g. `call-service light.turn_on '{"brightness_pct": 50}' --target '{"entity_id": "light.desk"}'`
+    CallService {
+        /// The service, as domain.service
+        service: String,
+        #[structopt(default_value = "{}")]
+        /// The service data, as JSON
+        data: String,
+        #[structopt(long)]
+        /// Entities, devices or areas to call it for, as JSON
+        target: Option<String>,
+    },
+    /// Render a template, e.g. `render-template '{{ states("sun.sun") }}'`
+    RenderTemplate { template: String },
+    /// Print Home Assistant's events as they happen, all of them or of one type
+    Events { event_type: Option<String> },
+    /// Install a systemd user service that runs the agent with the current settings
+    InstallService {
+        #[structopt(long, default_value = "60")]
+        /// Seconds without a sign of life from the agent before systemd restarts it
+        watchdog_sec: u64,
+    },
+}
+
+/// The monitor settings from the config file. Every section is optional.
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Monitors {
+    pub system: SystemConfig,
+    pub power: PowerConfig,
+    pub network: NetworkConfig,
+    pub location: LocationConfig,
+    pub bluetooth: BluetoothConfig,
+    pub sessions: SessionsConfig,
+    pub commands: Vec<CommandSensorConfig>,
+    pub files: Vec<FileSensorConfig>,
+    pub systemd: SystemdConfig,
+    pub containers: ContainersConfig,
+    pub updates: UpdatesConfig,
+    pub scheduler: SchedulerConfig,
+    pub mirrors: Vec<MirrorConfig>,
 }
 
 pub struct Config {
     pub hass_url: url::Url,
     pub hass_token: String,
     pub state_file: String,
+    pub window_title: TitlePrivacy,
+    pub config_file: String,
+    pub transport: TransportConfig,
+    pub monitors: Monitors,
+    pub command: Subcommand,
+}
+
+pub fn load_monitors(path: &str) -> Result<Monitors, anyhow::Error> {
+    match fs::read_to_string(path) {
+        Ok(toml) => {
+            let monitors: Monitors = toml::from_str(&toml)?;
+            for command in &monitors.commands {
+                command.validate()?;
+            }
+            for zone in &monitors.location.zones {
+                zone.validate()?;
+            }
+            for mirror in &monitors.mirrors {
+                mirror.validate()?;
+            }
+            Ok(monitors)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Monitors::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// `$XDG_STATE_HOME/ha-agent-rs/haars.json`, unless there's a `haars.json` in the working
+/// directory where older versions kept it, which is still used so the device stays registered.
+fn default_state_file() -> String {
+    let legacy = "haars.json";
+    let state_home = env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")));
+    match state_home {
+        Some(state_home) if !Path::new(legacy).exists() => {
+            state_home.join("ha-agent-rs").join(legacy).to_string_lossy().into_owned()
+        }
+        _ => legacy.to_string(),
+    }
 }
 
 pub fn load_config() -> Config {
@@ -43,12 +178,60 @@ pub fn load_config() -> Config {
         .state_file
         .or_else(|| env::var("HAARS_FILE").ok())
         .or_else(|| dotenv::var("HAARS_FILE").ok())
-        .unwrap_or_else(|| "haars.json".to_string());
+        .unwrap_or_else(default_state_file);
+
+    let window_title = args
+        .window_title
+        .or_else(|| env::var("HAARS_WINDOW_TITLE").ok())
+        .or_else(|| dotenv::var("HAARS_WINDOW_TITLE").ok())
+        .map(|privacy| privacy.parse().expect("Failed to parse HAARS_WINDOW_TITLE"))
+        .unwrap_or(TitlePrivacy::Show);
+
+    let config_file = args
+        .config_file
+        .or_else(|| env::var("HAARS_CONFIG").ok())
+        .or_else(|| dotenv::var("HAARS_CONFIG").ok())
+        .unwrap_or_else(|| "haars.toml".to_string());
+
+    let ca_file = args
+        .ca_file
+        .or_else(|| env::var("HAARS_CA_FILE").ok())
+        .or_else(|| dotenv::var("HAARS_CA_FILE").ok());
+
+    let client_cert = args
+        .client_cert
+        .or_else(|| env::var("HAARS_CLIENT_CERT").ok())
+        .or_else(|| dotenv::var("HAARS_CLIENT_CERT").ok());
+
+    let client_key = args
+        .client_key
+        .or_else(|| env::var("HAARS_CLIENT_KEY").ok())
+        .or_else(|| dotenv::var("HAARS_CLIENT_KEY").ok());
+
+    let insecure = args.insecure
+        || env::var("HAARS_INSECURE")
+            .ok()
+            .or_else(|| dotenv::var("HAARS_INSECURE").ok())
+            .is_some_and(|insecure| matches!(insecure.as_str(), "1" | "true" | "yes"));
+
+    let transport = TransportConfig {
+        ca_file: ca_file.map(PathBuf::from),
+        client_cert: client_cert.map(PathBuf::from),
+        client_key: client_key.map(PathBuf::from),
+        insecure,
+    };
+
+    let monitors = load_monitors(&config_file).expect("Failed to load HAARS_CONFIG");
 
     Config {
         hass_url,
         hass_token,
         state_file,
+        window_title,
+        config_file,
+        transport,
+        monitors,
+        command: args.command.unwrap_or(Subcommand::Run),
     }
 }
 
@@ -64,11 +247,70 @@ mod tests {
         env::set_var("HASS_URL", TEST_URL_STRING);
         env::set_var("HASS_TOKEN", "token");
         env::set_var("HAARS_FILE", "file.json");
+        env::set_var("HAARS_WINDOW_TITLE", "hash");
 
         let config = load_config();
 
         assert_eq!(config.hass_url, url::Url::parse(TEST_URL_STRING).expect("Failed to parse url"));
         assert_eq!(config.hass_token, "token");
         assert_eq!(config.state_file, "file.json");
+        assert_eq!(config.window_title, TitlePrivacy::Hash);
+        assert_eq!(config.command, Subcommand::Run);
+    }
+
+    #[test]
+    fn test_parse_subcommands() {
+        let args = Arguments::from_iter(["ha-agent-rs", "--state-file", "file.json", "list-sensors"]);
+
+        assert_eq!(args.state_file.as_deref(), Some("file.json"));
+        assert_eq!(args.command, Some(Subcommand::ListSensors));
+        assert_eq!(Arguments::from_iter(["ha-agent-rs"]).command, None);
+        assert!(Arguments::from_iter_safe(["ha-agent-rs", "unregistre"]).is_err());
+        assert_eq!(
+            Arguments::from_iter(["ha-agent-rs", "install-service", "--watchdog-sec", "30"]).command,
+            Some(Subcommand::InstallService { watchdog_sec: 30 })
+        );
+        assert_eq!(
+            Arguments::from_iter(["ha-agent-rs", "call-service", "light.toggle"]).command,
+            Some(Subcommand::CallService {
+                service: "light.toggle".to_string(),
+                data: "{}".to_string(),
+                target: None
+            })
+        );
+    }
+
+    #[test]
+    fn test_load_monitors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("haars.toml");
+        fs::write(
+            &path,
+            "[system]\ninterval = 10\n[scheduler.sensors.cpu_usage]\nmin_interval = 60\n",
+        )
+        .unwrap();
+
+        let monitors = load_monitors(path.to_str().unwrap()).unwrap();
+
+        assert_eq!(monitors.system.interval, 10);
+        assert_eq!(monitors.system.percent_threshold, SystemConfig::default().percent_threshold);
+        assert_eq!(monitors.scheduler.sensors["cpu_usage"].min_interval, Some(60));
+        assert_eq!(monitors.scheduler.sensors["cpu_usage"].deadband, None);
+    }
+
+    #[test]
+    fn test_load_monitors_without_file() {
+        let monitors = load_monitors("does-not-exist.toml").unwrap();
+
+        assert_eq!(monitors, Monitors::default());
+    }
+
+    #[test]
+    fn test_load_monitors_rejects_unknown_sections() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("haars.toml");
+        fs::write(&path, "[sytem]\ninterval = 10\n").unwrap();
+
+        assert!(load_monitors(path.to_str().unwrap()).is_err());
     }
 }
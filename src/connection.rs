@@ -3,31 +3,55 @@
 // 3. check webcam status & update sensor
 // 4. profit, goto 3
 
+use std::collections::HashMap;
+use std::fmt;
+use std::future::pending;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
 use anyhow::{anyhow, Error};
-use async_tungstenite::tokio::{connect_async, TokioAdapter};
 use async_tungstenite::tungstenite::protocol::Message;
 use async_tungstenite::WebSocketStream;
+use chrono::DateTime;
 
-use futures::{SinkExt, StreamExt};
+use futures::{AsyncRead, AsyncWrite, SinkExt, StreamExt};
 use url::Url;
 
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
+use serde_json::{json, Map, Value};
+use tokio::select;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
 
-use crate::agent_state::{self, SensorState, WebhookInfo};
+use crate::agent_state::{self, Sensor, SensorState, WebhookInfo, Zone};
 use crate::config::Config;
+use crate::monitor::location::Location;
+use crate::transport;
 
 pub struct Session {
-    pub ws_stream: WebSocketStream<
-        async_tungstenite::stream::Stream<
-            TokioAdapter<tokio::net::TcpStream>,
-            TokioAdapter<tokio_native_tls::TlsStream<tokio::net::TcpStream>>,
-        >,
-    >,
+    ws: WsClient,
+    /// why the WebSocket was closed, once it is
+    closed: watch::Receiver<Option<String>>,
+    closed_reported: bool,
+    connection: JoinHandle<()>,
+    client: reqwest::Client,
     hass_protocol: String,
     hass_address: String,
     hass_token: String,
     webhook_url: String,
+    commands: broadcast::Sender<Command>,
+}
+
+/// A notification pushed by Home Assistant over the websocket push channel. Monitors that can be
+/// controlled from HA pick out the messages meant for them, e.g. `command_media`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Command {
+    pub message: String,
+    #[serde(default)]
+    pub data: Value,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -45,37 +69,492 @@ struct SensorMessage<T> {
     data: T,
 }
 
-impl Session {
-    fn calculate_webhook_url(&mut self, webhook_info: &WebhookInfo) -> String {
-        if let Some(cloudhook_url) = &webhook_info.cloudhook_url {
-            cloudhook_url.to_string()
-        } else if let Some(webhook_id) = &webhook_info.webhook_id {
-            if let Some(remote_ui_url) = &webhook_info.remote_ui_url {
-                format!("{}://{}/api/webhook/{}", self.hass_protocol, remote_ui_url, webhook_id)
-            } else {
-                format!(
-                    "{}://{}/api/webhook/{}",
-                    self.hass_protocol, self.hass_address, webhook_id
-                )
+/// Why a WebSocket command didn't get a result.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommandError {
+    /// Home Assistant answered with an error, e.g. `unknown_command` or `unauthorized`
+    Failed { code: String, message: String },
+    /// no answer within `transport::REQUEST_TIMEOUT`
+    Timeout,
+    /// the connection was closed before the answer came
+    Closed,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Failed { code, message } => write!(f, "{} ({})", message, code),
+            CommandError::Timeout => write!(f, "no answer from Home Assistant"),
+            CommandError::Closed => write!(f, "the connection to Home Assistant is closed"),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// The parts of Home Assistant's `get_config` the agent cares about.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct HassConfig {
+    pub version: String,
+    #[serde(default)]
+    pub location_name: String,
+    #[serde(default)]
+    pub time_zone: String,
+    #[serde(default)]
+    pub latitude: f64,
+    #[serde(default)]
+    pub longitude: f64,
+    #[serde(default)]
+    pub components: Vec<String>,
+}
+
+/// An event from `subscribe_events`, e.g. a `state_changed` with the old and new state in `data`.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct Event {
+    pub event_type: String,
+    #[serde(default)]
+    pub data: Value,
+    #[serde(default)]
+    pub time_fired: String,
+}
+
+/// An entity's state, as `subscribe_entities` events build it up.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct EntityState {
+    pub entity_id: String,
+    pub state: String,
+    #[serde(default)]
+    pub attributes: Map<String, Value>,
+    #[serde(default)]
+    pub last_changed: String,
+    #[serde(default)]
+    pub last_updated: String,
+}
+
+/// What `subscribe_entities` sends: the full states of the entities first, then only what changed,
+/// all with their keys shortened to a letter or two.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct EntitiesEvent {
+    #[serde(rename = "a", default)]
+    added: HashMap<String, CompressedState>,
+    #[serde(rename = "c", default)]
+    changed: HashMap<String, StateDiff>,
+    #[serde(rename = "r", default)]
+    removed: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+struct CompressedState {
+    #[serde(rename = "s")]
+    state: String,
+    #[serde(rename = "a", default)]
+    attributes: Map<String, Value>,
+    /// seconds since the epoch
+    #[serde(rename = "lc")]
+    last_changed: f64,
+    /// left out when it's the same as `last_changed`
+    #[serde(rename = "lu")]
+    last_updated: Option<f64>,
+}
+
+/// What was set, and which attributes were removed.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+struct StateDiff {
+    #[serde(rename = "+")]
+    set: Option<ChangedState>,
+    #[serde(rename = "-")]
+    removed: Option<RemovedAttributes>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+struct ChangedState {
+    #[serde(rename = "s")]
+    state: Option<String>,
+    /// only the attributes that changed
+    #[serde(rename = "a")]
+    attributes: Option<Map<String, Value>>,
+    #[serde(rename = "lc")]
+    last_changed: Option<f64>,
+    #[serde(rename = "lu")]
+    last_updated: Option<f64>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+struct RemovedAttributes {
+    #[serde(rename = "a", default)]
+    attributes: Vec<String>,
+}
+
+/// A time like the uncompressed states have, e.g. `2026-10-19T08:00:00.5+00:00`.
+fn timestamp(seconds: f64) -> String {
+    DateTime::from_timestamp_micros((seconds * 1_000_000.0).round() as i64)
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_default()
+}
+
+impl EntitiesEvent {
+    /// Applies the event to the states, by entity id, returning the entities it changed.
+    pub fn apply(self, states: &mut HashMap<String, EntityState>) -> Vec<String> {
+        let mut changed = vec![];
+        for (entity_id, added) in self.added {
+            let last_changed = timestamp(added.last_changed);
+            let state = EntityState {
+                entity_id: entity_id.clone(),
+                state: added.state,
+                attributes: added.attributes,
+                last_updated: added.last_updated.map_or_else(|| last_changed.clone(), timestamp),
+                last_changed,
+            };
+            states.insert(entity_id.clone(), state);
+            changed.push(entity_id);
+        }
+        for entity_id in self.removed {
+            if states.remove(&entity_id).is_some() {
+                changed.push(entity_id);
             }
-        } else {
-            "".to_string()
         }
+        for (entity_id, diff) in self.changed {
+            // changes only come for entities that were added first
+            let Some(state) = states.get_mut(&entity_id) else {
+                continue;
+            };
+            if let Some(set) = diff.set {
+                if let Some(value) = set.state {
+                    state.state = value;
+                }
+                if let Some(last_changed) = set.last_changed {
+                    state.last_changed = timestamp(last_changed);
+                    state.last_updated = state.last_changed.clone();
+                } else if let Some(last_updated) = set.last_updated {
+                    state.last_updated = timestamp(last_updated);
+                }
+                state.attributes.extend(set.attributes.unwrap_or_default());
+            }
+            for name in diff.removed.map(|removed| removed.attributes).unwrap_or_default() {
+                state.attributes.remove(&name);
+            }
+            changed.push(entity_id);
+        }
+        changed
     }
+}
 
-    pub fn update_webhook_url(&mut self, webhook_info: &WebhookInfo) {
-        self.webhook_url = self.calculate_webhook_url(webhook_info);
+/// What `render_template` subscriptions send: the result, or why it couldn't be rendered.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+struct TemplateUpdate {
+    #[serde(default)]
+    result: Value,
+    error: Option<String>,
+}
+
+/// The result of a command message, or its error.
+fn command_result(message: &Value) -> Result<Value, CommandError> {
+    if message["success"] == true {
+        Ok(message["result"].clone())
+    } else {
+        Err(CommandError::Failed {
+            code: message["error"]["code"].as_str().unwrap_or("unknown_error").to_string(),
+            message: message["error"]["message"].as_str().unwrap_or_default().to_string(),
+        })
     }
+}
 
-    pub async fn connect(config: &Config) -> Result<Self, Error> {
-        let hass_address = config.hass_url.host_str().ok_or(anyhow!("No host in URL"))?;
-        let hass_protocol = config.hass_url.scheme();
-        let hass_token = config.hass_token.clone();
-        println!("Home-assistant URL: {}", hass_address);
-        let url = Url::parse(format!("wss://{}/api/websocket", hass_address).as_str())?;
+/// What the connection task and the clients share: the commands waiting for their result and
+/// the subscriptions waiting for events, both by id.
+#[derive(Default)]
+struct Requests {
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<u64, oneshot::Sender<Result<Value, CommandError>>>>,
+    subscriptions: Mutex<HashMap<u64, mpsc::UnboundedSender<Value>>>,
+}
+
+impl Requests {
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Hands a message from Home Assistant to whoever is waiting for it.
+    fn dispatch(&self, text: &str) {
+        let message: Value = match serde_json::from_str(text) {
+            Ok(message) => message,
+            Err(e) => {
+                println!("Received invalid message {:?}: {}", text, e);
+                return;
+            }
+        };
+        let Some(id) = message["id"].as_u64() else {
+            println!("Received message: {:?}", text);
+            return;
+        };
+        match message["type"].as_str() {
+            Some("result") => {
+                // nobody waits for the result of an unsubscribe, or of a command that timed out
+                if let Some(result) = self.pending.lock().unwrap().remove(&id) {
+                    _ = result.send(command_result(&message));
+                }
+            }
+            Some("event") => {
+                let subscriptions = self.subscriptions.lock().unwrap();
+                if let Some(events) = subscriptions.get(&id) {
+                    _ = events.send(message["event"].clone());
+                }
+            }
+            _ => println!("Received message: {:?}", text),
+        }
+    }
+
+    /// Fails everything that's waiting, the connection is gone.
+    fn close(&self) {
+        self.pending.lock().unwrap().clear();
+        self.subscriptions.lock().unwrap().clear();
+    }
+}
+
+/// Sends commands over the WebSocket and waits for their results, from any task. The connection
+/// itself is handled by a task of its own, see `WsClient::start`.
+#[derive(Clone)]
+pub struct WsClient {
+    outgoing: mpsc::UnboundedSender<Message>,
+    requests: Arc<Requests>,
+}
+
+/// Events of a subscription, until it's dropped or the connection closes.
+pub struct Subscription<T> {
+    id: u64,
+    events: mpsc::UnboundedReceiver<Value>,
+    client: WsClient,
+    _events: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> Subscription<T> {
+    /// The next event, skipping ones that don't parse. `None` once the connection is closed.
+    pub async fn next(&mut self) -> Option<T> {
+        loop {
+            let event = self.events.recv().await?;
+            match serde_json::from_value(event.clone()) {
+                Ok(event) => return Some(event),
+                Err(e) => println!("Received unexpected event {}: {}", event, e),
+            }
+        }
+    }
+}
+
+impl<T> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        self.client.requests.subscriptions.lock().unwrap().remove(&self.id);
+        let unsubscribe = json!({ "type": "unsubscribe_events", "subscription": self.id });
+        self.client.send(unsubscribe, self.client.requests.next_id());
+    }
+}
+
+/// Reads and writes the WebSocket until it's closed, then tells why through `closed`.
+async fn run_connection<S>(
+    mut ws_stream: WebSocketStream<S>,
+    mut outgoing: mpsc::UnboundedReceiver<Message>,
+    requests: Arc<Requests>,
+    closed: watch::Sender<Option<String>>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let reason = loop {
+        select! {
+            message = outgoing.recv() => match message {
+                Some(message) => {
+                    if let Err(e) = ws_stream.send(message).await {
+                        break e.to_string();
+                    }
+                }
+                None => break "Session ended".to_string(),
+            },
+            message = ws_stream.next() => match message {
+                Some(Ok(Message::Text(text))) => requests.dispatch(&text),
+                Some(Ok(Message::Ping(_))) => {
+                    if let Err(e) = ws_stream.send(Message::Pong(vec![])).await {
+                        break e.to_string();
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => break "Connection closed".to_string(),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => break e.to_string(),
+            },
+        }
+    };
+    requests.close();
+    closed.send_replace(Some(reason));
+}
+
+impl WsClient {
+    /// Hands an authenticated WebSocket to a task of its own, returning the client for it, the
+    /// reason it closed once it does, and the task.
+    pub fn start<S>(ws_stream: WebSocketStream<S>) -> (Self, watch::Receiver<Option<String>>, JoinHandle<()>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel();
+        let (closed_tx, closed_rx) = watch::channel(None);
+        let requests = Arc::new(Requests::default());
+        let connection = tokio::spawn(run_connection(ws_stream, outgoing_rx, requests.clone(), closed_tx));
+        let client = Self {
+            outgoing: outgoing_tx,
+            requests,
+        };
+        (client, closed_rx, connection)
+    }
+
+    /// Queues a message, without waiting for its result. Returns whether the connection is open.
+    fn send(&self, mut message: Value, id: u64) -> bool {
+        message["id"] = json!(id);
+        self.outgoing.send(Message::text(message.to_string())).is_ok()
+    }
+
+    async fn command_with_id(&self, message: Value, id: u64) -> Result<Value, CommandError> {
+        let (result_tx, result_rx) = oneshot::channel();
+        self.requests.pending.lock().unwrap().insert(id, result_tx);
+        if !self.send(message, id) {
+            self.requests.pending.lock().unwrap().remove(&id);
+            return Err(CommandError::Closed);
+        }
+        match timeout(transport::REQUEST_TIMEOUT, result_rx).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(CommandError::Closed),
+            Err(_) => {
+                self.requests.pending.lock().unwrap().remove(&id);
+                Err(CommandError::Timeout)
+            }
+        }
+    }
+
+    /// Sends a command, e.g. `{"type": "config/device_registry/list"}`, and waits for its result.
+    pub async fn command(&self, message: Value) -> Result<Value, CommandError> {
+        self.command_with_id(message, self.requests.next_id()).await
+    }
+
+    /// Sends a command that starts a subscription, returning its events once it's confirmed.
+    pub async fn subscribe<T>(&self, message: Value) -> Result<Subscription<T>, CommandError> {
+        let id = self.requests.next_id();
+        // events can follow the result right away, so they need somewhere to go before it's sent
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        self.requests.subscriptions.lock().unwrap().insert(id, events_tx);
+        let subscription = Subscription {
+            id,
+            events: events_rx,
+            client: self.clone(),
+            _events: PhantomData,
+        };
+        // on failure, dropping the subscription cleans up after it
+        self.command_with_id(message, id).await?;
+        Ok(subscription)
+    }
+
+    pub async fn get_config(&self) -> Result<HassConfig, Error> {
+        let config = self.command(json!({ "type": "get_config" })).await?;
+        Ok(serde_json::from_value(config)?)
+    }
+
+    /// The states of the entities and their changes, which Home Assistant picks out itself.
+    pub async fn subscribe_entities(&self, entity_ids: &[String]) -> Result<Subscription<EntitiesEvent>, CommandError> {
+        self.subscribe(json!({ "type": "subscribe_entities", "entity_ids": entity_ids }))
+            .await
+    }
+
+    /// Events of one type, e.g. `state_changed`, or all of them.
+    pub async fn subscribe_events(&self, event_type: Option<&str>) -> Result<Subscription<Event>, CommandError> {
+        let mut message = json!({ "type": "subscribe_events" });
+        if let Some(event_type) = event_type {
+            message["event_type"] = json!(event_type);
+        }
+        self.subscribe(message).await
+    }
+
+    /// Calls a service, e.g. `light.turn_on`, returning its response if it has one.
+    pub async fn call_service(
+        &self,
+        domain: &str,
+        service: &str,
+        service_data: Value,
+        target: Option<Value>,
+    ) -> Result<Value, CommandError> {
+        let mut message = json!({
+            "type": "call_service",
+            "domain": domain,
+            "service": service,
+            "service_data": service_data,
+        });
+        if let Some(target) = target {
+            message["target"] = target;
+        }
+        self.command(message).await
+    }
+
+    /// Renders a template once. Home Assistant parses results that look like numbers, lists and
+    /// such, so it's not necessarily a string.
+    pub async fn render_template(&self, template: &str) -> Result<Value, CommandError> {
+        let mut updates: Subscription<TemplateUpdate> = self
+            .subscribe(json!({ "type": "render_template", "template": template, "strict": true }))
+            .await?;
+        let update = timeout(transport::REQUEST_TIMEOUT, updates.next())
+            .await
+            .map_err(|_| CommandError::Timeout)?
+            .ok_or(CommandError::Closed)?;
+        match update.error {
+            Some(message) => Err(CommandError::Failed {
+                code: "template_error".to_string(),
+                message,
+            }),
+            None => Ok(update.result),
+        }
+    }
+}
+
+/// Where sensor updates are posted: the cloudhook if there is one, otherwise the webhook on the
+/// remote UI or on the instance itself.
+pub fn webhook_url(hass_protocol: &str, hass_address: &str, webhook_info: &WebhookInfo) -> String {
+    if let Some(cloudhook_url) = &webhook_info.cloudhook_url {
+        cloudhook_url.to_string()
+    } else if let Some(webhook_id) = &webhook_info.webhook_id {
+        if let Some(remote_ui_url) = &webhook_info.remote_ui_url {
+            format!("{}://{}/api/webhook/{}", hass_protocol, remote_ui_url, webhook_id)
+        } else {
+            format!("{}://{}/api/webhook/{}", hass_protocol, hass_address, webhook_id)
+        }
+    } else {
+        "".to_string()
+    }
+}
+
+/// The host of a URL, with the port if it's not the scheme's default one, e.g. `ha.local:8123`.
+pub fn hass_address(url: &Url) -> Result<String, Error> {
+    let host = url.host_str().ok_or(anyhow!("No host in URL"))?;
+    Ok(match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    })
+}
 
-        // Then, use the `tungstenite` library to connect to the WebSocket URL
-        let (mut ws_stream, _) = connect_async(url).await.expect("Failed to connect websocket");
+/// Checks the access token against the REST API, returning its greeting.
+pub async fn check_api(config: &Config) -> Result<String, Error> {
+    let hass_address = hass_address(&config.hass_url)?;
+    let response: Value = transport::http_client(&config.transport)?
+        .get(format!("{}://{}/api/", config.hass_url.scheme(), hass_address))
+        .header("Authorization", format!("Bearer {}", config.hass_token))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(response["message"].as_str().unwrap_or_default().to_string())
+}
+
+/// Connects the WebSocket and authenticates with the access token, handing the connection to a task
+/// of its own, see `WsClient::start`.
+async fn authenticate(config: &Config) -> Result<(WsClient, watch::Receiver<Option<String>>, JoinHandle<()>), Error> {
+    let hass_address = hass_address(&config.hass_url)?;
+    let ws_protocol = if config.hass_url.scheme() == "http" { "ws" } else { "wss" };
+    let url = Url::parse(format!("{}://{}/api/websocket", ws_protocol, hass_address).as_str())?;
+
+    // Then, use the `tungstenite` library to connect to the WebSocket URL
+    let mut ws_stream = transport::connect_websocket(&config.transport, &url).await?;
+    let authenticate = async {
         let _auth_req = ws_stream.next().await.ok_or("Connection closed");
 
         // Send a message to register the new device
@@ -92,39 +571,144 @@ impl Session {
         let response_json = ws_stream
             .next()
             .await
-            .ok_or("didn't receive anything")
-            .expect("Authentication")?;
-        let response: Value = serde_json::from_str(response_json.to_string().as_str())?;
-
-        if response["type"] == "auth_ok" {
-            println!("Authenticated with {}", hass_address);
-
-            Ok(Self {
-                ws_stream,
-                hass_protocol: hass_protocol.to_string(),
-                hass_address: hass_address.to_string(),
-                hass_token: hass_token.to_string(),
-                webhook_url: "".to_string(),
-            })
-        } else {
-            Err(anyhow!("Authentication failed"))
-        }
+            .ok_or(anyhow!("Connection closed before authenticating"))??;
+        Ok::<Value, Error>(serde_json::from_str(response_json.to_string().as_str())?)
+    };
+    // a Home Assistant that's still starting up accepts connections long before it answers them
+    let response = timeout(transport::REQUEST_TIMEOUT, authenticate)
+        .await
+        .map_err(|_| anyhow!("No answer from {} while authenticating", hass_address))??;
+
+    if response["type"] == "auth_ok" {
+        println!("Authenticated with {}", hass_address);
+        Ok(WsClient::start(ws_stream))
+    } else {
+        Err(anyhow!("Authentication failed"))
     }
+}
 
-    pub async fn read_incoming(&mut self) -> Result<(), Error> {
-        while let Some(msg) = self.ws_stream.next().await {
-            let msg = msg?;
-            match msg {
-                Message::Ping(_) => {
-                    //respond to ping with pong
-                    self.ws_stream.send(Message::Pong(vec![])).await?;
-                }
-                Message::Text(s) => {
-                    println!("Received message: {:?}", s);
-                    // Continue processing text message... in the future
-                }
-                _ => {}
+impl Session {
+    pub fn update_webhook_url(&mut self, webhook_info: &WebhookInfo) {
+        self.webhook_url = webhook_url(&self.hass_protocol, &self.hass_address, webhook_info);
+    }
+
+    pub async fn connect(config: &Config) -> Result<Self, Error> {
+        let hass_address = hass_address(&config.hass_url)?;
+        let hass_protocol = config.hass_url.scheme();
+        println!("Home-assistant URL: {}", hass_address);
+        let client = transport::http_client(&config.transport)?;
+        let (ws, closed, connection) = authenticate(config).await?;
+        Ok(Self {
+            ws,
+            closed,
+            closed_reported: false,
+            connection,
+            client,
+            hass_protocol: hass_protocol.to_string(),
+            hass_address: hass_address.to_string(),
+            hass_token: config.hass_token.clone(),
+            webhook_url: "".to_string(),
+            commands: broadcast::channel(16).0,
+        })
+    }
+
+    /// Opens the WebSocket again after it was closed. Monitors keep getting commands, but only once
+    /// push notifications are subscribed again, and whatever used the old client has to start over.
+    pub async fn reconnect(&mut self, config: &Config) -> Result<(), Error> {
+        let (ws, closed, connection) = authenticate(config).await?;
+        self.ws = ws;
+        self.closed = closed;
+        self.closed_reported = false;
+        self.connection = connection;
+        Ok(())
+    }
+
+    /// Sends WebSocket commands, e.g. for a monitor's own task.
+    pub fn ws(&self) -> &WsClient {
+        &self.ws
+    }
+
+    pub fn subscribe_commands(&self) -> broadcast::Receiver<Command> {
+        self.commands.subscribe()
+    }
+
+    // https://developers.home-assistant.io/docs/api/native-app-integration/notifications#enabling-websocket-push-notifications
+    pub async fn subscribe_push_notifications(&mut self, webhook_info: &WebhookInfo) -> Result<(), Error> {
+        let webhook_id = webhook_info
+            .webhook_id
+            .as_ref()
+            .ok_or(anyhow!("No webhook id to subscribe with"))?;
+        let mut notifications: Subscription<Command> = self
+            .ws
+            .subscribe(json!({
+                "type": "mobile_app/push_notification_channel",
+                "webhook_id": webhook_id,
+                "support_confirm": false
+            }))
+            .await?;
+        let commands = self.commands.clone();
+        tokio::spawn(async move {
+            while let Some(command) = notifications.next().await {
+                // sending only fails if no monitor is listening for commands
+                _ = commands.send(command);
             }
+        });
+        Ok(())
+    }
+
+    /// Sends a command over the WebSocket and waits for its result.
+    pub async fn call(&mut self, message: Value) -> Result<Value, Error> {
+        let command = message["type"].clone();
+        self.ws
+            .command(message)
+            .await
+            .map_err(|e| anyhow!("{} failed: {}", command, e))
+    }
+
+    /// Removes a config entry, which for a mobile app is the same as deleting the device in the UI.
+    pub async fn remove_config_entry(&mut self, entry_id: &str) -> Result<(), Error> {
+        self.client
+            .delete(format!(
+                "{}://{}/api/config/config_entries/entry/{}",
+                self.hass_protocol, self.hass_address, entry_id
+            ))
+            .header("Authorization", format!("Bearer {}", self.hass_token))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// The webhook's `get_config`, which answers as long as the registration is known.
+    pub async fn get_webhook_config(&mut self) -> Result<Value, Error> {
+        let response = self
+            .client
+            .post(&self.webhook_url)
+            .body(json!({ "type": "get_config" }).to_string())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.json().await?)
+    }
+
+    /// Waits for the WebSocket to be closed and returns why, once. After that it never returns.
+    pub async fn closed(&mut self) -> String {
+        if self.closed_reported {
+            return pending().await;
+        }
+        let reason = match self.closed.wait_for(Option::is_some).await {
+            Ok(reason) => reason.clone().unwrap_or_default(),
+            Err(_) => "Connection closed".to_string(),
+        };
+        self.closed_reported = true;
+        reason
+    }
+
+    /// Closes the WebSocket, so Home Assistant doesn't wait for it to time out.
+    pub async fn close(self) -> Result<(), Error> {
+        if self.ws.outgoing.send(Message::Close(None)).is_ok() {
+            // the connection task ends once Home Assistant confirms
+            _ = timeout(transport::CONNECT_TIMEOUT, self.connection).await;
         }
         Ok(())
     }
@@ -138,7 +722,9 @@ impl Session {
     pub async fn register(&mut self, state: &mut agent_state::State) -> Result<(), Error> {
         self.register_device(state, true).await?;
         self.update_webhook_url(&state.webhook_info);
-        self.register_sensors(state).await?;
+        // a new device knows none of the sensors' states
+        state.last_sent.clear();
+        self.register_sensors(&state.sensors).await?;
         Ok(())
     }
 
@@ -154,8 +740,8 @@ impl Session {
 
         println!("json: {}", registration_json);
         //use reqwest to register device with message
-        let client = reqwest::Client::new();
-        let response = client
+        let response = self
+            .client
             .post(format!(
                 "{}://{}/api/mobile_app/registrations",
                 self.hass_protocol, self.hass_address
@@ -176,15 +762,15 @@ impl Session {
         }
     }
 
-    async fn register_sensors(&mut self, state: &mut agent_state::State) -> Result<(), Error> {
-        let client = reqwest::Client::new();
-        for sensor in &state.sensors {
+    pub async fn register_sensors(&mut self, sensors: &[Sensor]) -> Result<(), Error> {
+        for sensor in sensors {
             let registration_json = json!(SensorMessage {
                 message_type: "register_sensor".to_string(),
                 data: sensor.clone()
             });
             println!("json: {}", registration_json);
-            let response = client
+            let response = self
+                .client
                 .post(&self.webhook_url)
                 .body(registration_json.to_string())
                 .send()
@@ -192,25 +778,26 @@ impl Session {
 
             if response.status().is_success() {
                 println!("Registered sensor {}", sensor.state.unique_id);
-                println!(
-                    "Registered sensor {:?}",
-                    response.text().await.expect("Sensor registration response")
-                );
+                println!("Registered sensor {:?}", response.text().await?);
             } else {
-                println!("Failed to register device with Home Assistant");
+                return Err(anyhow!(
+                    "Failed to register sensor {}: {}",
+                    sensor.state.unique_id,
+                    response.status()
+                ));
             }
         }
         Ok(())
     }
 
     pub async fn update_sensor(&mut self, sensors: Vec<SensorState>) -> Result<(), Error> {
-        let client = reqwest::Client::new();
         let registration_json = json!(SensorMessage {
             message_type: "update_sensor_states".to_string(),
             data: sensors
         });
         println!("json: {}", registration_json);
-        let response = client
+        let response = self
+            .client
             .post(&self.webhook_url)
             .body(registration_json.to_string())
             .send()
@@ -221,9 +808,290 @@ impl Session {
                 "Updated sensors {}",
                 response.text().await.expect("Sensor update response")
             );
+            Ok(())
         } else {
-            println!("Failed to update sensors {}", response.status());
+            Err(anyhow!("Failed to update sensors {}", response.status()))
         }
-        Ok(())
+    }
+
+    pub async fn update_location(&mut self, location: &Location) -> Result<(), Error> {
+        let location_json = json!(SensorMessage {
+            message_type: "update_location".to_string(),
+            data: location
+        });
+        // the coordinates stay out of the log, like they stay out of Home Assistant when asked to
+        match (&location.location_name, location.gps_accuracy) {
+            (Some(zone), _) => println!("Updating location to zone {}", zone),
+            (None, Some(accuracy)) => println!("Updating location to coordinates accurate to {} m", accuracy),
+            (None, None) => println!("Updating location"),
+        }
+        let response = self
+            .client
+            .post(&self.webhook_url)
+            .body(location_json.to_string())
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(anyhow!("Home Assistant answered {}", response.status()))
+        }
+    }
+
+    pub async fn get_zones(&mut self) -> Result<Vec<Zone>, Error> {
+        let response = self
+            .client
+            .post(&self.webhook_url)
+            .body(json!({ "type": "get_zones" }).to_string())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.json().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_tungstenite::tokio::{accept_async, client_async, TokioAdapter};
+    use tokio::io::DuplexStream;
+
+    type Server = WebSocketStream<TokioAdapter<DuplexStream>>;
+
+    /// A client and the Home Assistant end of its WebSocket, past authentication.
+    async fn connect() -> (WsClient, watch::Receiver<Option<String>>, Server) {
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let (client, server) = tokio::join!(
+            client_async("ws://localhost/api/websocket", client_io),
+            accept_async(server_io)
+        );
+        let (ws, closed, _) = WsClient::start(client.unwrap().0);
+        (ws, closed, server.unwrap())
+    }
+
+    async fn receive<S: AsyncRead + AsyncWrite + Unpin>(server: &mut WebSocketStream<S>) -> Value {
+        loop {
+            if let Message::Text(text) = server.next().await.unwrap().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    async fn reply<S: AsyncRead + AsyncWrite + Unpin>(server: &mut WebSocketStream<S>, message: Value) {
+        server.send(Message::text(message.to_string())).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_commands() {
+        let (ws, _closed, mut server) = connect().await;
+        let config = tokio::spawn({
+            let ws = ws.clone();
+            async move { ws.get_config().await }
+        });
+        let request = receive(&mut server).await;
+        let call = tokio::spawn({
+            let ws = ws.clone();
+            async move { ws.call_service("light", "turn_on", json!({}), None).await }
+        });
+        let call_request = receive(&mut server).await;
+        assert_eq!(request["type"], "get_config");
+        assert_eq!(call_request["type"], "call_service");
+        assert_ne!(request["id"], call_request["id"]);
+
+        // answered out of order
+        let error = json!({"code": "service_not_found", "message": "Service light.turn_on not found."});
+        reply(
+            &mut server,
+            json!({"id": call_request["id"], "type": "result", "success": false, "error": error}),
+        )
+        .await;
+        let result = json!({"version": "2026.10.1", "location_name": "Home"});
+        reply(
+            &mut server,
+            json!({"id": request["id"], "type": "result", "success": true, "result": result}),
+        )
+        .await;
+
+        assert_eq!(
+            call.await.unwrap(),
+            Err(CommandError::Failed {
+                code: "service_not_found".to_string(),
+                message: "Service light.turn_on not found.".to_string()
+            })
+        );
+        let config = config.await.unwrap().unwrap();
+        assert_eq!(config.version, "2026.10.1");
+        assert_eq!(config.location_name, "Home");
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_events() {
+        let (ws, _closed, mut server) = connect().await;
+        let subscription = tokio::spawn({
+            let ws = ws.clone();
+            async move { ws.subscribe_events(Some("state_changed")).await }
+        });
+        let request = receive(&mut server).await;
+        assert_eq!(request["event_type"], "state_changed");
+        let id = request["id"].clone();
+        reply(
+            &mut server,
+            json!({"id": id, "type": "result", "success": true, "result": null}),
+        )
+        .await;
+        let event = json!({"event_type": "state_changed", "data": {"entity_id": "sun.sun"}});
+        reply(&mut server, json!({"id": id, "type": "event", "event": event})).await;
+
+        let mut events = subscription.await.unwrap().unwrap();
+        let event = events.next().await.unwrap();
+        assert_eq!(event.event_type, "state_changed");
+        assert_eq!(event.data["entity_id"], "sun.sun");
+
+        drop(events);
+        let unsubscribe = receive(&mut server).await;
+        assert_eq!(unsubscribe["type"], "unsubscribe_events");
+        assert_eq!(unsubscribe["subscription"], id);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_entities() {
+        let (ws, _closed, mut server) = connect().await;
+        let entity_ids = vec!["alarm_control_panel.home".to_string(), "sensor.washer_power".to_string()];
+        let subscription = tokio::spawn({
+            let ws = ws.clone();
+            async move { ws.subscribe_entities(&entity_ids).await }
+        });
+        let request = receive(&mut server).await;
+        assert_eq!(request["type"], "subscribe_entities");
+        assert_eq!(
+            request["entity_ids"],
+            json!(["alarm_control_panel.home", "sensor.washer_power"])
+        );
+        let id = request["id"].clone();
+        reply(
+            &mut server,
+            json!({"id": id, "type": "result", "success": true, "result": null}),
+        )
+        .await;
+        let added = json!({"a": {
+            "alarm_control_panel.home": {"s": "armed_away", "a": {"friendly_name": "Alarm"}, "c": "01J", "lc": 1792396800.5},
+            "sensor.washer_power": {"s": "412", "a": {"unit_of_measurement": "W", "power_factor": 0.9}, "c": "01K", "lc": 1792396800.0, "lu": 1792400400.0},
+        }});
+        let changed = json!({"c": {
+            "alarm_control_panel.home": {"+": {"s": "disarmed", "c": "01L", "lc": 1792404000.0}},
+            "sensor.washer_power": {"+": {"a": {"unit_of_measurement": "kW"}, "lu": 1792404000.0}, "-": {"a": ["power_factor"]}},
+        }});
+        let removed = json!({"r": ["sensor.washer_power"]});
+        for event in [added, changed, removed] {
+            reply(&mut server, json!({"id": id, "type": "event", "event": event})).await;
+        }
+
+        let mut events = subscription.await.unwrap().unwrap();
+        let mut states = HashMap::new();
+        let mut added = events.next().await.unwrap().apply(&mut states);
+        added.sort();
+        assert_eq!(added, vec!["alarm_control_panel.home", "sensor.washer_power"]);
+        let alarm = &states["alarm_control_panel.home"];
+        assert_eq!(alarm.state, "armed_away");
+        assert_eq!(alarm.last_changed, "2026-10-19T08:00:00.500+00:00");
+        assert_eq!(alarm.last_updated, alarm.last_changed);
+        assert_eq!(states["sensor.washer_power"].last_updated, "2026-10-19T09:00:00+00:00");
+
+        events.next().await.unwrap().apply(&mut states);
+        assert_eq!(states["alarm_control_panel.home"].state, "disarmed");
+        assert_eq!(states["alarm_control_panel.home"].attributes["friendly_name"], "Alarm");
+        let washer = &states["sensor.washer_power"];
+        assert_eq!(washer.state, "412");
+        assert_eq!(washer.attributes["unit_of_measurement"], "kW");
+        assert!(!washer.attributes.contains_key("power_factor"));
+        assert_eq!(washer.last_changed, "2026-10-19T08:00:00+00:00");
+        assert_eq!(washer.last_updated, "2026-10-19T10:00:00+00:00");
+
+        assert_eq!(
+            events.next().await.unwrap().apply(&mut states),
+            vec!["sensor.washer_power"]
+        );
+        assert!(!states.contains_key("sensor.washer_power"));
+    }
+
+    #[tokio::test]
+    async fn test_render_template() {
+        let (ws, _closed, mut server) = connect().await;
+        let rendered = tokio::spawn({
+            let ws = ws.clone();
+            async move { ws.render_template("{{ states('sun.sun') }}").await }
+        });
+        let request = receive(&mut server).await;
+        assert_eq!(request["template"], "{{ states('sun.sun') }}");
+        let id = request["id"].clone();
+        reply(
+            &mut server,
+            json!({"id": id, "type": "result", "success": true, "result": null}),
+        )
+        .await;
+        let event = json!({"result": "above_horizon", "listeners": {"entities": ["sun.sun"]}});
+        reply(&mut server, json!({"id": id, "type": "event", "event": event})).await;
+
+        assert_eq!(rendered.await.unwrap(), Ok(json!("above_horizon")));
+        assert_eq!(receive(&mut server).await["type"], "unsubscribe_events");
+    }
+
+    #[tokio::test]
+    async fn test_closed() {
+        let (ws, mut closed, mut server) = connect().await;
+        let command = tokio::spawn({
+            let ws = ws.clone();
+            async move { ws.command(json!({"type": "ping"})).await }
+        });
+        receive(&mut server).await;
+        server.close(None).await.unwrap();
+
+        assert_eq!(command.await.unwrap(), Err(CommandError::Closed));
+        closed.wait_for(Option::is_some).await.unwrap();
+        assert_eq!(ws.command(json!({"type": "ping"})).await, Err(CommandError::Closed));
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = Config {
+            hass_url: Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap(),
+            hass_token: "token".to_string(),
+            state_file: String::new(),
+            window_title: crate::monitor::active_window::TitlePrivacy::Omit,
+            config_file: String::new(),
+            transport: transport::TransportConfig::default(),
+            monitors: Default::default(),
+            command: crate::config::Subcommand::Run,
+        };
+        // Home Assistant restarting: the first connection is closed, the second one answers
+        let server = tokio::spawn(async move {
+            for restarted in [false, true] {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut server = async_tungstenite::tokio::accept_async(stream).await.unwrap();
+                reply(&mut server, json!({"type": "auth_required"})).await;
+                assert_eq!(receive(&mut server).await["access_token"], "token");
+                reply(&mut server, json!({"type": "auth_ok"})).await;
+                if !restarted {
+                    server.close(None).await.unwrap();
+                    continue;
+                }
+                let request = receive(&mut server).await;
+                reply(
+                    &mut server,
+                    json!({"id": request["id"], "type": "result", "success": true, "result": "pong"}),
+                )
+                .await;
+            }
+        });
+
+        let mut session = Session::connect(&config).await.unwrap();
+        session.closed().await;
+        assert_eq!(session.ws().command(json!({"type": "ping"})).await, Err(CommandError::Closed));
+
+        session.reconnect(&config).await.unwrap();
+        assert_eq!(session.ws().command(json!({"type": "ping"})).await, Ok(json!("pong")));
+        server.await.unwrap();
     }
 }
@@ -3,26 +3,364 @@
 // 3. check webcam status & update sensor
 // 4. profit, goto 3
 mod agent_state;
+mod cli;
 mod connection;
 mod monitor;
 mod config;
+mod mirror;
+mod scheduler;
+mod service;
+mod transport;
 
-use tokio::sync::watch;
+use std::future::pending;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
 use tokio::select;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, interval_at, sleep, sleep_until, Duration, Instant};
 
-use agent_state::State;
+use agent_state::{Sensor, SensorState, State, StateLock, Zone};
+use config::{Config, Subcommand};
 use connection::Session;
+use monitor::active_window;
+use monitor::bluetooth;
+use monitor::commands;
+use monitor::containers;
+use monitor::files;
+use monitor::inotify::PathWatcher;
+use monitor::location::{self, LocationSender};
 use monitor::microphone;
+use monitor::mpris;
+use monitor::network;
+use monitor::power;
+use monitor::session_state;
+use monitor::sessions;
+use monitor::system;
+use monitor::systemd;
+use monitor::updates;
 use monitor::webcam;
+use monitor::{RegistrationSender, SensorSender};
+use scheduler::Scheduler;
+
+// zones rarely change, but a new one shouldn't wait for a restart
+const ZONE_REFRESH: Duration = Duration::from_secs(60 * 60);
+const CONFIG_SETTLE: Duration = Duration::from_millis(200);
+// the last sent states are saved this often, and at shutdown
+const STATE_SAVE: Duration = Duration::from_secs(5 * 60);
+// the first attempt to reconnect, doubling up to the longest wait between attempts
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
+// sensors Home Assistant didn't accept, e.g. while it restarts, are registered again this often
+const REGISTRATION_RETRY: Duration = Duration::from_secs(30);
+
+/// Registers the sensors with Home Assistant, and adds them to the state once it accepted them.
+async fn register_sensors(
+    session: &mut Session,
+    state: &mut State,
+    config: &Config,
+    sensors: Vec<Sensor>,
+) -> Result<(), anyhow::Error> {
+    if !sensors.is_empty() {
+        session.register_sensors(&sensors).await?;
+        state.update_sensors(&sensors);
+        state.save_state(&config.state_file)?;
+    }
+    Ok(())
+}
+
+/// Registers the sensors a monitor found while running. The ones Home Assistant didn't accept are
+/// kept in `unregistered`, to try again later.
+async fn register_new_sensors(
+    session: &mut Session,
+    state: &mut State,
+    config: &Config,
+    sensors: Vec<Sensor>,
+    unregistered: &mut Vec<Sensor>,
+) {
+    let new_sensors = state.unregistered_sensors(sensors);
+    if let Err(e) = register_sensors(session, state, config, new_sensors.clone()).await {
+        println!("Failed to register sensors, trying again later: {}", e);
+        unregistered.retain(|sensor| {
+            !new_sensors
+                .iter()
+                .any(|new_sensor| new_sensor.state.unique_id == sensor.state.unique_id)
+        });
+        unregistered.extend(new_sensors);
+    }
+}
+
+/// Registers the sensors of the enabled monitors, and disables the ones of monitors that are gone.
+async fn register_monitor_sensors(session: &mut Session, state: &mut State, config: &Config) -> Result<(), anyhow::Error> {
+    let sensors = monitor::enabled_sensors(&config.monitors).await;
+    let mut changed = state.removed_sensors(&sensors);
+    changed.extend(state.unregistered_sensors(sensors));
+    register_sensors(session, state, config, changed).await
+}
+
+/// Sends the states that differ from what Home Assistant was last sent, remembering them. Returns
+/// whether there was anything to send, and so the state has to be saved.
+async fn send_states(
+    session: &mut Session,
+    state: &mut State,
+    states: Vec<SensorState>,
+) -> Result<bool, anyhow::Error> {
+    let states = state.unsent_states(states);
+    if states.is_empty() {
+        return Ok(false);
+    }
+    session.update_sensor(states.clone()).await?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    state.mark_sent(&states, now.as_secs());
+    Ok(true)
+}
+
+/// Sends the next batch of the scheduler, returning whether Home Assistant was sent anything, and
+/// so the state has to be saved. A batch that failed is kept to try again later.
+async fn send_batch(
+    session: &mut Session,
+    state: &mut State,
+    scheduler: &mut Scheduler,
+) -> Result<bool, anyhow::Error> {
+    let now = Instant::now();
+    let states = scheduler.take(now);
+    if states.is_empty() {
+        return Ok(false);
+    }
+    match send_states(session, state, states.clone()).await {
+        Ok(sent) => {
+            scheduler.sent(&states, now);
+            Ok(sent)
+        }
+        Err(e) => {
+            scheduler.failed(states, now);
+            Err(e)
+        }
+    }
+}
+
+/// Tells systemd the agent is up, once Home Assistant has answered its webhook.
+fn notify_ready(config: &Config) {
+    service::notify(&format!("READY=1\nSTATUS=Connected to {}, monitoring", config.hass_url));
+    println!("All good! Monitoring...");
+}
+
+/// Fetches Home Assistant's zones into the state, returning whether they changed.
+async fn refresh_zones(session: &mut Session, state: &mut State, config: &Config) -> bool {
+    match session.get_zones().await {
+        Ok(zones) if zones != state.zones => {
+            state.zones = zones;
+            if let Err(e) = state.save_state(&config.state_file) {
+                println!("Failed to save zones: {}", e);
+            }
+            true
+        }
+        Ok(_) => false,
+        Err(e) => {
+            println!("Failed to get zones, using the last known ones: {}", e);
+            false
+        }
+    }
+}
+
+/// Where the monitors send what they find, kept around to start them again on a reload.
+struct MonitorChannels {
+    sensor_tx: SensorSender,
+    register_tx: RegistrationSender,
+    location_tx: LocationSender,
+    zones_tx: watch::Sender<Vec<Zone>>,
+}
+
+impl MonitorChannels {
+    /// Starts the monitors, except the webcam and microphone ones which have nothing to configure,
+    /// and the mirrors of Home Assistant entities.
+    fn spawn(&self, config: &Config, session: &Session) -> Monitors {
+        Monitors {
+            local: self.spawn_local(config),
+            connected: self.spawn_connected(config, session),
+        }
+    }
+
+    fn spawn_local(&self, config: &Config) -> Vec<JoinHandle<()>> {
+        let sensor_tx = &self.sensor_tx;
+        let monitors = &config.monitors;
+        vec![
+            tokio::spawn(session_state::start(sensor_tx.clone())),
+            tokio::spawn(active_window::start(sensor_tx.clone(), config.window_title)),
+            tokio::spawn(system::start(sensor_tx.clone(), monitors.system.clone())),
+            tokio::spawn(power::start(sensor_tx.clone(), self.register_tx.clone(), monitors.power.clone())),
+            tokio::spawn(network::start(sensor_tx.clone(), monitors.network.clone())),
+            tokio::spawn(bluetooth::start(sensor_tx.clone(), monitors.bluetooth.clone())),
+            tokio::spawn(sessions::start(sensor_tx.clone(), monitors.sessions.clone())),
+            tokio::spawn(commands::start(sensor_tx.clone(), monitors.commands.clone())),
+            tokio::spawn(files::start(sensor_tx.clone(), monitors.files.clone())),
+            tokio::spawn(location::start(self.location_tx.clone(), monitors.location.clone(), self.zones_tx.subscribe())),
+        ]
+    }
+
+    /// The monitors that take commands from Home Assistant, and the mirrors, all of which are
+    /// handed the current WebSocket.
+    fn spawn_connected(&self, config: &Config, session: &Session) -> Vec<JoinHandle<()>> {
+        let sensor_tx = &self.sensor_tx;
+        let monitors = &config.monitors;
+        vec![
+            tokio::spawn(mpris::start(sensor_tx.clone(), session.subscribe_commands())),
+            tokio::spawn(systemd::start(sensor_tx.clone(), session.subscribe_commands(), monitors.systemd.clone())),
+            tokio::spawn(containers::start(sensor_tx.clone(), session.subscribe_commands(), monitors.containers.clone())),
+            tokio::spawn(updates::start(sensor_tx.clone(), session.subscribe_commands(), monitors.updates.clone())),
+            tokio::spawn(mirror::start(session.ws().clone(), monitors.mirrors.clone())),
+        ]
+    }
+}
+
+/// The running monitors, split by whether they have to be restarted along with the WebSocket.
+struct Monitors {
+    local: Vec<JoinHandle<()>>,
+    connected: Vec<JoinHandle<()>>,
+}
+
+impl Monitors {
+    fn iter(&self) -> impl Iterator<Item = &JoinHandle<()>> {
+        self.local.iter().chain(&self.connected)
+    }
+
+    async fn stop(&mut self) {
+        stop_monitors(&mut self.local).await;
+        stop_monitors(&mut self.connected).await;
+    }
+}
+
+/// Aborts the monitors and waits until they're gone, so they don't overlap with the ones started
+/// after them, e.g. both holding the same D-Bus name.
+async fn stop_monitors(monitors: &mut Vec<JoinHandle<()>>) {
+    for monitor in monitors.iter() {
+        monitor.abort();
+    }
+    for monitor in monitors.drain(..) {
+        // a monitor that panicked is gone just as well
+        _ = monitor.await;
+    }
+}
+
+/// Reads the config file again and restarts the monitors with it, unless nothing changed and
+/// it's not `forced`. An invalid config file is reported and otherwise ignored, the monitors keep
+/// running as they were.
+async fn reload_monitors(
+    session: &mut Session,
+    state: &mut State,
+    config: &mut Config,
+    channels: &MonitorChannels,
+    monitors: &mut Monitors,
+    scheduler: &mut Scheduler,
+    forced: bool,
+) {
+    let new_monitors = match config::load_monitors(&config.config_file) {
+        Ok(new_monitors) => new_monitors,
+        Err(e) => {
+            println!("Keeping the current config, {} is invalid: {}", config.config_file, e);
+            return;
+        }
+    };
+    if new_monitors == config.monitors && !forced {
+        return;
+    }
+    println!("Reloading {}", config.config_file);
+    monitors.stop().await;
+    config.monitors = new_monitors;
+    scheduler.reconfigure(config.monitors.scheduler.clone());
+    if let Err(e) = register_monitor_sensors(session, state, config).await {
+        println!("Failed to register the sensors: {}", e);
+    }
+    *monitors = channels.spawn(config, session);
+}
+
+/// Opens the WebSocket again, and restarts what depends on it: the push notifications, and the
+/// monitors and mirrors that were handed the old connection. The others keep running, so e.g.
+/// the updates aren't checked all over again.
+async fn reconnect(
+    session: &mut Session,
+    state: &State,
+    config: &Config,
+    channels: &MonitorChannels,
+    monitors: &mut Monitors,
+) -> Result<(), anyhow::Error> {
+    session.reconnect(config).await?;
+    println!("Reconnected to {}", config.hass_url);
+    if let Err(e) = session.subscribe_push_notifications(&state.webhook_info).await {
+        println!("Not receiving commands from Home Assistant: {}", e);
+    }
+    stop_monitors(&mut monitors.connected).await;
+    monitors.connected = channels.spawn_connected(config, session);
+    service::notify(&format!("STATUS=Connected to {}, monitoring", config.hass_url));
+    Ok(())
+}
+
+/// Waits for the config file to change, forever if it can't be watched.
+async fn config_changed(watcher: &mut Option<PathWatcher>) {
+    let Some(changes) = watcher else {
+        return pending().await;
+    };
+    if let Err(e) = changes.changed().await {
+        println!("Stopped watching the config file: {}", e);
+        *watcher = None;
+        pending().await
+    }
+}
+
+fn watch_config(path: &str) -> Option<PathWatcher> {
+    let watch = || -> Result<PathWatcher, anyhow::Error> {
+        let mut watcher = PathWatcher::new()?;
+        watcher.watch(Path::new(path))?;
+        Ok(watcher)
+    };
+    watch()
+        .inspect_err(|e| println!("Not watching {} for changes: {}", path, e))
+        .ok()
+}
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     let config = config::load_config();
+    match config.command.clone() {
+        Subcommand::Run => run(config).await,
+        Subcommand::Register => cli::register(&config).await,
+        Subcommand::Unregister => cli::unregister(&config).await,
+        Subcommand::Status => cli::status(&config),
+        Subcommand::ListSensors => cli::list_sensors(&config).await,
+        Subcommand::TestConnection => cli::test_connection(&config).await,
+        Subcommand::CallService { service, data, target } => {
+            cli::call_service(&config, &service, &data, target.as_deref()).await
+        }
+        Subcommand::RenderTemplate { template } => cli::render_template(&config, &template).await,
+        Subcommand::Events { event_type } => cli::events(&config, event_type.as_deref()).await,
+        Subcommand::InstallService { watchdog_sec } => service::install(&config, watchdog_sec),
+    }
+}
+
+async fn run(mut config: Config) -> Result<(), anyhow::Error> {
     let (webcam_state_tx, mut webcam_state_rx) = watch::channel::<bool>(false);
     let (microphone_state_tx, mut microphone_state_rx) = watch::channel::<bool>(false);
+    let (sensor_tx, mut sensor_rx) = mpsc::unbounded_channel();
+    let (register_tx, mut register_rx) = mpsc::unbounded_channel();
+    let (location_tx, mut location_rx) = mpsc::unbounded_channel();
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut hangup = signal(SignalKind::hangup())?;
 
+    let _lock = StateLock::acquire(&config.state_file)?;
+    let mut state = State::init(&config.state_file)?;
+    if config.transport.insecure {
+        println!("Not verifying the certificate of {}, anyone in between can read the token", config.hass_url);
+    }
     let mut session = Session::connect(&config).await?;
-    let mut state = State::init(&config.state_file);
+    service::notify(&format!("STATUS=Connected to {}, registering", config.hass_url));
+    match session.ws().get_config().await {
+        Ok(hass_config) => println!("{} runs Home Assistant {}", hass_config.location_name, hass_config.version),
+        Err(e) => println!("Failed to get Home Assistant's config: {}", e),
+    }
     if !state.registered {
         println!("Registering device with {}", &config.hass_url);
         session.register(&mut state).await?;
@@ -38,49 +376,165 @@ async fn main() -> Result<(), anyhow::Error> {
         session.update_webhook_url(&state.webhook_info);
     }
 
-    tokio::spawn(webcam::start(webcam_state_tx));
-    tokio::spawn(microphone::start(microphone_state_tx));
-
-    //initial sensor update
-    let mut webcam_sensor = state.get_sensor_by_unique_id("webcam").unwrap();
-    if webcam::is_webcam_in_use() {
-        webcam_sensor.state.value = true;
+    register_monitor_sensors(&mut session, &mut state, &config).await?;
+    refresh_zones(&mut session, &mut state, &config).await;
+    let channels = MonitorChannels {
+        sensor_tx,
+        register_tx,
+        location_tx,
+        zones_tx: watch::channel(state.zones.clone()).0,
+    };
+    let mut zone_refresh = interval_at(Instant::now() + ZONE_REFRESH, ZONE_REFRESH);
+    if let Err(e) = session.subscribe_push_notifications(&state.webhook_info).await {
+        println!("Not receiving commands from Home Assistant: {}", e);
     }
-    session.update_sensor(vec![webcam_sensor.state.clone()]).await?;
 
+    let builtin_monitors = [
+        tokio::spawn(webcam::start(webcam_state_tx)),
+        tokio::spawn(microphone::start(microphone_state_tx)),
+    ];
+    let mut monitors = channels.spawn(&config, &session);
+    let mut config_watcher = watch_config(&config.config_file);
+
+    //initial sensor update, which only sends what changed while the agent wasn't running
+    let mut scheduler = Scheduler::new(config.monitors.scheduler.clone(), Instant::now());
+    let mut webcam_sensor = state.get_sensor_by_unique_id("webcam").unwrap();
+    webcam_sensor.state.value = json!(webcam::is_webcam_in_use());
     let mut microphone_sensor = state.get_sensor_by_unique_id("microphone").unwrap();
-    if microphone::is_microphone_in_use() {
-        microphone_sensor.state.value = true;
-    }
-    session.update_sensor(vec![microphone_sensor.state.clone()]).await?;
+    microphone_sensor.state.value = json!(microphone::is_microphone_in_use());
+    scheduler.push(
+        vec![webcam_sensor.state.clone(), microphone_sensor.state.clone()],
+        Instant::now(),
+    );
+    let mut unsaved = false;
+    let mut unregistered: Vec<Sensor> = vec![];
+    let mut registration_retry = interval_at(Instant::now() + REGISTRATION_RETRY, REGISTRATION_RETRY);
+    // set while the WebSocket is closed
+    let mut reconnect_at: Option<Instant> = None;
+    let mut reconnect_delay = RECONNECT_DELAY;
+    let mut state_save = interval_at(Instant::now() + STATE_SAVE, STATE_SAVE);
 
-    println!("All good! Monitoring...");
+    // without a watchdog the interval is never ticked, the period doesn't matter then
+    let watchdog = service::watchdog_interval();
+    let mut watchdog_ping = interval(watchdog.unwrap_or(Duration::from_secs(60)));
+    // the WebSocket works, but the sensor updates go to the webhook, which may not
+    let mut ready = match session.get_webhook_config().await {
+        Ok(_) => {
+            notify_ready(&config);
+            true
+        }
+        Err(e) => {
+            println!("Home Assistant doesn't answer the webhook yet: {}", e);
+            service::notify(&format!(
+                "STATUS=Connected to {}, waiting for the webhook",
+                config.hass_url
+            ));
+            false
+        }
+    };
     loop {
         select! {
             // The unwrap() here will only panic if all senders have been dropped. This will
             // not happen in normal operation.
             _ = webcam_state_rx.changed() => {
-                let state = *webcam_state_rx.borrow();
-                if state != webcam_sensor.state.value {
-                    webcam_sensor.state.value = state;
-                    session.update_sensor(vec![webcam_sensor.state.clone()]).await.unwrap();
-                }
+                webcam_sensor.state.value = json!(*webcam_state_rx.borrow());
+                scheduler.push(vec![webcam_sensor.state.clone()], Instant::now());
             },
             _ = microphone_state_rx.changed() => {
-                let state = *microphone_state_rx.borrow();
-                if state != microphone_sensor.state.value {
-                    microphone_sensor.state.value = state;
-                    session.update_sensor(vec![microphone_sensor.state.clone()]).await.unwrap();
+                microphone_sensor.state.value = json!(*microphone_state_rx.borrow());
+                scheduler.push(vec![microphone_sensor.state.clone()], Instant::now());
+            },
+            Some(sensors) = register_rx.recv() => {
+                register_new_sensors(&mut session, &mut state, &config, sensors, &mut unregistered).await;
+            },
+            Some(states) = sensor_rx.recv() => {
+                // sensors are registered before their first state is sent, make sure that's done
+                while let Ok(sensors) = register_rx.try_recv() {
+                    register_new_sensors(&mut session, &mut state, &config, sensors, &mut unregistered).await;
+                }
+                scheduler.push(states, Instant::now());
+            },
+            _ = registration_retry.tick(), if !unregistered.is_empty() => {
+                let sensors = std::mem::take(&mut unregistered);
+                register_new_sensors(&mut session, &mut state, &config, sensors, &mut unregistered).await;
+            },
+            _ = scheduler.wait() => {
+                match send_batch(&mut session, &mut state, &mut scheduler).await {
+                    Ok(sent) => {
+                        unsaved |= sent;
+                        if sent && !ready {
+                            notify_ready(&config);
+                            ready = true;
+                        }
+                    }
+                    Err(e) => println!("{}, trying again later", e),
+                }
+            },
+            _ = state_save.tick(), if unsaved => {
+                match state.save_state(&config.state_file) {
+                    Ok(()) => unsaved = false,
+                    Err(e) => println!("Failed to save the last sent states: {}", e),
                 }
             },
-            result = session.read_incoming() => {
-                // Handle WebSocket message here
-                if result.is_err() {
-                    println!("Error reading incoming message: {:?}", result.err());
-                    continue;
+            _ = zone_refresh.tick() => {
+                if refresh_zones(&mut session, &mut state, &config).await {
+                    channels.zones_tx.send_replace(state.zones.clone());
+                }
+            },
+            Some(location) = location_rx.recv() => {
+                // the next fix supersedes this one, it's not worth retrying
+                if let Err(e) = session.update_location(&location).await {
+                    println!("Failed to update location: {}", e);
+                }
+            },
+            _ = watchdog_ping.tick(), if watchdog.is_some() => {
+                service::notify("WATCHDOG=1");
+            },
+            _ = hangup.recv() => {
+                reload_monitors(&mut session, &mut state, &mut config, &channels, &mut monitors, &mut scheduler, true).await;
+            },
+            _ = config_changed(&mut config_watcher) => {
+                // editors tend to write a file in a few steps, let them finish
+                sleep(CONFIG_SETTLE).await;
+                reload_monitors(&mut session, &mut state, &mut config, &channels, &mut monitors, &mut scheduler, false).await;
+            },
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+            reason = session.closed() => {
+                println!("Lost the connection to {}: {}", config.hass_url, reason);
+                service::notify(&format!("STATUS=Connection to {} lost: {}, reconnecting", config.hass_url, reason));
+                reconnect_delay = RECONNECT_DELAY;
+                reconnect_at = Some(Instant::now() + reconnect_delay);
+            },
+            _ = sleep_until(reconnect_at.unwrap_or_else(Instant::now)), if reconnect_at.is_some() => {
+                match reconnect(&mut session, &state, &config, &channels, &mut monitors).await {
+                    Ok(()) => reconnect_at = None,
+                    Err(e) => {
+                        reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+                        println!(
+                            "Failed to reconnect to {}, trying again in {} seconds: {}",
+                            config.hass_url,
+                            reconnect_delay.as_secs(),
+                            e
+                        );
+                        reconnect_at = Some(Instant::now() + reconnect_delay);
+                    }
                 }
             },
             else => continue,
         }
     }
+
+    println!("Shutting down...");
+    service::notify("STOPPING=1\nSTATUS=Shutting down");
+    for monitor in builtin_monitors.iter().chain(monitors.iter()) {
+        monitor.abort();
+    }
+    // whatever the scheduler still holds is superseded by these
+    let offline_states = state.offline_states();
+    if let Err(e) = send_states(&mut session, &mut state, offline_states).await {
+        println!("Failed to send the final sensor states: {}", e);
+    }
+    state.save_state(&config.state_file)?;
+    session.close().await
 }
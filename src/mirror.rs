@@ -0,0 +1,516 @@
+// The other direction: Home Assistant entities mirrored to this machine, to a file, a named pipe,
+// a D-Bus property or a status bar, so scripts and bars can show them without polling the API.
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Error};
+use nix::sys::stat::Mode;
+use nix::unistd::mkfifo;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::select;
+use tokio::time::{interval, Duration};
+use zbus::Connection;
+
+use crate::connection::{EntityState, WsClient};
+
+pub const DBUS_NAME: &str = "io.github.alekzanther.HaAgentRs";
+pub const DBUS_PATH: &str = "/io/github/alekzanther/HaAgentRs/Mirror";
+// how often a pipe nobody reads is tried again, so a new reader gets the states right away
+const PIPE_RETRY: Duration = Duration::from_secs(2);
+const I3BAR_HEADER: &str = "{\"version\":1}\n[\n";
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+    #[default]
+    File,
+    Pipe,
+    Dbus,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// the state, attributes and last change of every entity, by entity id
+    #[default]
+    Json,
+    /// a custom module's JSON, one line per update
+    Waybar,
+    /// a block per entity, in the i3bar protocol
+    I3bar,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorConfig {
+    pub entities: Vec<String>,
+    #[serde(default)]
+    pub output: Output,
+    /// the file or pipe to write to
+    pub path: Option<PathBuf>,
+    /// how a file or pipe is written
+    #[serde(default)]
+    pub format: Format,
+}
+
+impl MirrorConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.entities.is_empty() {
+            return Err(anyhow!("A mirror needs at least one entity"));
+        }
+        match self.output {
+            Output::File | Output::Pipe if self.path.is_none() => Err(anyhow!(
+                "Mirroring {} to a file or pipe needs a path",
+                self.entities.join(", ")
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The mirrored entities by entity id.
+type States = HashMap<String, EntityState>;
+
+/// "Washing Machine: running", or "Power: 230 W".
+fn label(state: &EntityState) -> String {
+    let attribute = |name: &str| state.attributes.get(name).and_then(Value::as_str);
+    let name = attribute("friendly_name").unwrap_or(&state.entity_id);
+    match attribute("unit_of_measurement") {
+        Some(unit) => format!("{}: {} {}", name, state.state, unit),
+        None => format!("{}: {}", name, state.state),
+    }
+}
+
+/// The entities' states as one line, in the order they're configured. Entities Home Assistant
+/// doesn't know are left out.
+fn render(format: Format, entities: &[String], states: &States) -> String {
+    let known: Vec<&EntityState> = entities.iter().filter_map(|entity_id| states.get(entity_id)).collect();
+    let rendered = match format {
+        Format::Json => Value::Object(
+            known
+                .iter()
+                .map(|state| {
+                    let mirrored = json!({
+                        "state": state.state,
+                        "attributes": state.attributes,
+                        "last_changed": state.last_changed,
+                    });
+                    (state.entity_id.clone(), mirrored)
+                })
+                .collect(),
+        ),
+        Format::Waybar => json!({
+            "text": known.iter().map(|state| label(state)).collect::<Vec<_>>().join(" | "),
+            "tooltip": known.iter().map(|state| label(state)).collect::<Vec<_>>().join("\n"),
+            // for styling, e.g. #custom-ha.armed_away
+            "class": known.iter().map(|state| state.state.clone()).collect::<Vec<_>>(),
+        }),
+        Format::I3bar => Value::Array(
+            known
+                .iter()
+                .map(|state| {
+                    json!({
+                        "name": "ha-agent-rs",
+                        "instance": state.entity_id,
+                        "full_text": label(state),
+                        "short_text": state.state,
+                    })
+                })
+                .collect(),
+        ),
+    };
+    match format {
+        // i3bar's status lines are elements of an endless array
+        Format::I3bar => format!("{},", rendered),
+        _ => rendered.to_string(),
+    }
+}
+
+/// Replaces a file in one go, so a reader never sees half of it.
+fn write_file(path: &Path, content: &str) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, content)?;
+    fs::rename(&temporary, path)
+}
+
+/// Creates the named pipe, unless it's there already.
+fn create_pipe(path: &Path) -> Result<(), Error> {
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.file_type().is_fifo() => Ok(()),
+        Ok(_) => Err(anyhow!("{} isn't a named pipe", path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(mkfifo(path, Mode::S_IRUSR | Mode::S_IWUSR)?),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// A named pipe, open while someone reads it.
+struct Pipe {
+    path: PathBuf,
+    format: Format,
+    file: Option<File>,
+}
+
+impl Pipe {
+    /// Writes a line, if anyone is reading. Never blocks: without a reader, or with one that
+    /// doesn't keep up, the line is dropped.
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        if self.file.is_none() {
+            let opened = OpenOptions::new()
+                .write(true)
+                .custom_flags(nix::libc::O_NONBLOCK)
+                .open(&self.path);
+            let mut file = match opened {
+                Ok(file) => file,
+                // no reader
+                Err(e) if e.raw_os_error() == Some(nix::libc::ENXIO) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            if self.format == Format::I3bar {
+                file.write_all(I3BAR_HEADER.as_bytes())?;
+            }
+            self.file = Some(file);
+        }
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        match file.write_all(format!("{}\n", line).as_bytes()) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            // the reader is gone, the next one gets a fresh start
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => {
+                self.file = None;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Serves the mirrored states as the `States` property, entity id to state.
+#[derive(Default)]
+struct MirroredStates {
+    states: HashMap<String, String>,
+}
+
+#[zbus::interface(name = "io.github.alekzanther.HaAgentRs.Mirror")]
+impl MirroredStates {
+    #[zbus(property)]
+    fn states(&self) -> HashMap<String, String> {
+        self.states.clone()
+    }
+}
+
+/// Connects to the session bus, or `address`, and serves the `States` property there.
+async fn export_states(address: Option<&str>) -> zbus::Result<Connection> {
+    let builder = match address {
+        Some(address) => zbus::connection::Builder::address(address)?,
+        None => zbus::connection::Builder::session()?,
+    };
+    // the mirror restarts on a reload or reconnect, possibly before its old connection is closed
+    builder
+        .name(DBUS_NAME)?
+        .allow_name_replacements(true)
+        .replace_existing_names(true)
+        .serve_at(DBUS_PATH, MirroredStates::default())?
+        .build()
+        .await
+}
+
+/// Updates the entities' part of the `States` property.
+async fn publish_states(connection: &Connection, entities: &[String], states: &States) -> zbus::Result<()> {
+    let object = connection
+        .object_server()
+        .interface::<_, MirroredStates>(DBUS_PATH)
+        .await?;
+    let mut mirrored = object.get_mut().await;
+    for entity_id in entities {
+        match states.get(entity_id) {
+            Some(state) => mirrored.states.insert(entity_id.clone(), state.state.clone()),
+            None => mirrored.states.remove(entity_id),
+        };
+    }
+    mirrored.states_changed(object.signal_emitter()).await
+}
+
+enum Target {
+    File(PathBuf),
+    Pipe(Pipe),
+    Dbus(Connection),
+}
+
+struct Mirror {
+    entities: Vec<String>,
+    format: Format,
+    target: Target,
+}
+
+impl Mirror {
+    /// The D-Bus connection is shared by every mirror with a D-Bus output.
+    async fn open(config: MirrorConfig, dbus: &mut Option<Connection>) -> Result<Self, Error> {
+        let path = config.path.clone().unwrap_or_default();
+        let target = match config.output {
+            Output::File => Target::File(path),
+            Output::Pipe => {
+                create_pipe(&path)?;
+                Target::Pipe(Pipe {
+                    path,
+                    format: config.format,
+                    file: None,
+                })
+            }
+            Output::Dbus => match dbus {
+                Some(connection) => Target::Dbus(connection.clone()),
+                None => Target::Dbus(dbus.insert(export_states(None).await?).clone()),
+            },
+        };
+        Ok(Self {
+            entities: config.entities,
+            format: config.format,
+            target,
+        })
+    }
+
+    async fn write(&mut self, states: &States) {
+        let line = render(self.format, &self.entities, states);
+        let result = match &mut self.target {
+            Target::File(path) => write_file(path, &format!("{}\n", line)).map_err(Error::from),
+            Target::Pipe(pipe) => pipe.write(&line).map_err(Error::from),
+            Target::Dbus(connection) => publish_states(connection, &self.entities, states)
+                .await
+                .map_err(Error::from),
+        };
+        if let Err(e) = result {
+            println!("Failed to mirror {}: {}", self.entities.join(", "), e);
+        }
+    }
+
+    /// A pipe nobody reads yet.
+    fn waiting(&self) -> bool {
+        matches!(&self.target, Target::Pipe(pipe) if pipe.file.is_none())
+    }
+}
+
+async fn mirror(ws: WsClient, configs: Vec<MirrorConfig>) -> Result<(), Error> {
+    let entities: HashSet<String> = configs.iter().flat_map(|config| config.entities.clone()).collect();
+    let entities: Vec<String> = entities.into_iter().collect();
+    let mut events = ws.subscribe_entities(&entities).await?;
+    // the first event has the states of all the entities Home Assistant knows
+    let mut states = States::new();
+    events.next().await.ok_or(anyhow!("Connection closed"))?.apply(&mut states);
+
+    let mut dbus = None;
+    let mut mirrors = vec![];
+    for config in configs {
+        mirrors.push(Mirror::open(config, &mut dbus).await?);
+    }
+    for mirror in &mut mirrors {
+        mirror.write(&states).await;
+    }
+
+    let mut retry = interval(PIPE_RETRY);
+    loop {
+        select! {
+            event = events.next() => {
+                let Some(event) = event else {
+                    // stale until the agent reconnects and starts the mirrors over, so say so
+                    for state in states.values_mut() {
+                        state.state = "unavailable".to_string();
+                    }
+                    for mirror in &mut mirrors {
+                        mirror.write(&states).await;
+                    }
+                    return Err(anyhow!("Connection closed"));
+                };
+                let changed = event.apply(&mut states);
+                for mirror in mirrors
+                    .iter_mut()
+                    .filter(|mirror| mirror.entities.iter().any(|entity_id| changed.contains(entity_id)))
+                {
+                    mirror.write(&states).await;
+                }
+            },
+            _ = retry.tick() => {
+                for mirror in mirrors.iter_mut().filter(|mirror| mirror.waiting()) {
+                    mirror.write(&states).await;
+                }
+            },
+        }
+    }
+}
+
+pub async fn start(ws: WsClient, configs: Vec<MirrorConfig>) {
+    if configs.is_empty() {
+        return;
+    }
+    if let Err(e) = mirror(ws, configs).await {
+        println!("Stopped mirroring entities until the agent reconnects: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::testing::start_bus;
+    use std::io::Read;
+    use tempfile::tempdir;
+
+    fn state(entity_id: &str, state: &str, attributes: Value, last_updated: &str) -> EntityState {
+        EntityState {
+            entity_id: entity_id.to_string(),
+            state: state.to_string(),
+            attributes: serde_json::from_value(attributes).unwrap(),
+            last_updated: last_updated.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn states() -> States {
+        [
+            state(
+                "alarm_control_panel.home",
+                "armed_away",
+                json!({"friendly_name": "Alarm"}),
+                "2026-10-19T08:00:00+00:00",
+            ),
+            state(
+                "sensor.washer_power",
+                "412",
+                json!({"friendly_name": "Washer", "unit_of_measurement": "W"}),
+                "2026-10-19T08:00:00+00:00",
+            ),
+        ]
+        .into_iter()
+        .map(|state| (state.entity_id.clone(), state))
+        .collect()
+    }
+
+    fn entities() -> Vec<String> {
+        vec![
+            "sensor.washer_power".to_string(),
+            "alarm_control_panel.home".to_string(),
+            "light.gone".to_string(),
+        ]
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config = MirrorConfig {
+            entities: entities(),
+            output: Output::Pipe,
+            path: None,
+            format: Format::Waybar,
+        };
+        assert!(config.validate().is_err());
+
+        config.output = Output::Dbus;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_render() {
+        let states = states();
+
+        let rendered: Value = serde_json::from_str(&render(Format::Json, &entities(), &states)).unwrap();
+        assert_eq!(rendered["alarm_control_panel.home"]["state"], "armed_away");
+        assert_eq!(
+            rendered["sensor.washer_power"]["attributes"]["unit_of_measurement"],
+            "W"
+        );
+        assert!(rendered.get("light.gone").is_none());
+
+        assert_eq!(
+            render(Format::Waybar, &entities(), &states),
+            json!({
+                "text": "Washer: 412 W | Alarm: armed_away",
+                "tooltip": "Washer: 412 W\nAlarm: armed_away",
+                "class": ["412", "armed_away"],
+            })
+            .to_string()
+        );
+
+        let i3bar = render(Format::I3bar, &entities(), &states);
+        let blocks: Value = serde_json::from_str(i3bar.strip_suffix(',').unwrap()).unwrap();
+        assert_eq!(blocks[1]["instance"], "alarm_control_panel.home");
+        assert_eq!(blocks[1]["full_text"], "Alarm: armed_away");
+    }
+
+    #[test]
+    fn test_pipe() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("ha-states");
+        create_pipe(&path).unwrap();
+        create_pipe(&path).unwrap();
+        let mut pipe = Pipe {
+            path: path.clone(),
+            format: Format::I3bar,
+            file: None,
+        };
+
+        // nobody's reading yet
+        pipe.write("[],").unwrap();
+        assert!(pipe.file.is_none());
+
+        let mut reader = OpenOptions::new()
+            .read(true)
+            .custom_flags(nix::libc::O_NONBLOCK)
+            .open(&path)
+            .unwrap();
+        pipe.write("[],").unwrap();
+        let mut received = String::new();
+        reader.read_to_string(&mut received).unwrap_or_default();
+        assert_eq!(received, format!("{}[],\n", I3BAR_HEADER));
+
+        drop(reader);
+        pipe.write("[],").unwrap();
+        assert!(pipe.file.is_none());
+
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        assert!(create_pipe(&file).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dbus() {
+        let Some((_bus, address)) = start_bus() else {
+            return;
+        };
+        let connection = export_states(Some(&address)).await.unwrap();
+        publish_states(&connection, &entities(), &states()).await.unwrap();
+
+        let client = zbus::connection::Builder::address(address.as_str())
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let properties = zbus::fdo::PropertiesProxy::builder(&client)
+            .destination(DBUS_NAME)
+            .unwrap()
+            .path(DBUS_PATH)
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let interface = zbus::names::InterfaceName::try_from("io.github.alekzanther.HaAgentRs.Mirror").unwrap();
+        let mirrored: HashMap<String, String> = properties.get(interface, "States").await.unwrap().try_into().unwrap();
+
+        assert_eq!(mirrored["alarm_control_panel.home"], "armed_away");
+        assert_eq!(mirrored["sensor.washer_power"], "412");
+        assert_eq!(mirrored.len(), 2);
+
+        // a restarted mirror takes the name over while the old connection is still open
+        let restarted = export_states(Some(&address)).await.unwrap();
+        let owner = zbus::fdo::DBusProxy::new(&client)
+            .await
+            .unwrap()
+            .get_name_owner(DBUS_NAME.try_into().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(Some(owner.as_ref()), restarted.unique_name().map(|name| name.as_ref()));
+        drop(connection);
+    }
+}
@@ -0,0 +1,95 @@
+// Hyprland IPC -- https://wiki.hyprland.org/IPC/
+use std::env;
+use std::path::{Path, PathBuf};
+
+use anyhow::Error;
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+use super::{FocusedWindow, WindowSender};
+
+/// Newer Hyprland versions keep their sockets in `$XDG_RUNTIME_DIR/hypr`, older ones in `/tmp/hypr`.
+fn socket_dir() -> Result<PathBuf, Error> {
+    let signature = env::var("HYPRLAND_INSTANCE_SIGNATURE")?;
+    if let Ok(runtime_dir) = env::var("XDG_RUNTIME_DIR") {
+        let dir = PathBuf::from(runtime_dir).join("hypr").join(&signature);
+        if dir.exists() {
+            return Ok(dir);
+        }
+    }
+    Ok(PathBuf::from("/tmp/hypr").join(signature))
+}
+
+async fn active_window(dir: &Path) -> Result<Option<FocusedWindow>, Error> {
+    let mut stream = UnixStream::connect(dir.join(".socket.sock")).await?;
+    stream.write_all(b"j/activewindow").await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    let window: Value = serde_json::from_str(&response)?;
+    Ok(window["class"].as_str().map(|class| FocusedWindow {
+        class: class.to_string(),
+        title: window["title"].as_str().unwrap_or_default().to_string(),
+    }))
+}
+
+pub enum Event {
+    Focus(Option<FocusedWindow>),
+    TitleChanged,
+}
+
+/// Parses a line from the event socket, e.g. `activewindow>>firefox,Home Assistant - Mozilla Firefox`.
+pub fn parse_event(line: &str) -> Option<Event> {
+    let (name, data) = line.split_once(">>")?;
+    match name {
+        "activewindow" => {
+            // the class can't contain commas, the title can
+            let (class, title) = data.split_once(',').unwrap_or((data, ""));
+            if class.is_empty() && title.is_empty() {
+                Some(Event::Focus(None))
+            } else {
+                Some(Event::Focus(Some(FocusedWindow {
+                    class: class.to_string(),
+                    title: title.to_string(),
+                })))
+            }
+        }
+        "windowtitle" | "windowtitlev2" => Some(Event::TitleChanged),
+        _ => None,
+    }
+}
+
+pub async fn watch(window_tx: WindowSender) -> Result<(), Error> {
+    let dir = socket_dir()?;
+    let events = UnixStream::connect(dir.join(".socket2.sock")).await?;
+    window_tx.send(active_window(&dir).await?)?;
+
+    let mut lines = BufReader::new(events).lines();
+    while let Some(line) = lines.next_line().await? {
+        match parse_event(&line) {
+            Some(Event::Focus(window)) => window_tx.send(window)?,
+            // title events don't say whether the window is focused, so ask
+            Some(Event::TitleChanged) => window_tx.send(active_window(&dir).await?)?,
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_event() {
+        let Some(Event::Focus(Some(window))) = parse_event("activewindow>>firefox,Home Assistant, Overview") else {
+            panic!("expected a focused window");
+        };
+        assert_eq!(window.class, "firefox");
+        assert_eq!(window.title, "Home Assistant, Overview");
+
+        assert!(matches!(parse_event("activewindow>>,"), Some(Event::Focus(None))));
+        assert!(matches!(parse_event("windowtitle>>5566d1f0"), Some(Event::TitleChanged)));
+        assert!(parse_event("workspace>>2").is_none());
+    }
+}
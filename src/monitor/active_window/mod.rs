@@ -0,0 +1,216 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::task::JoinSet;
+
+use crate::agent_state::{Sensor, SensorState};
+use crate::monitor::{send_changes, SensorSender};
+
+#[cfg(feature = "hyprland")]
+pub mod hyprland;
+#[cfg(feature = "sway")]
+pub mod sway;
+#[cfg(feature = "x11")]
+pub mod x11;
+
+/// Backends send `None` when no window has focus, e.g. on an empty workspace.
+pub type WindowSender = UnboundedSender<Option<FocusedWindow>>;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FocusedWindow {
+    pub class: String,
+    pub title: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TitlePrivacy {
+    Show,
+    Hash,
+    Omit,
+}
+
+impl fmt::Display for TitlePrivacy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            TitlePrivacy::Show => "show",
+            TitlePrivacy::Hash => "hash",
+            TitlePrivacy::Omit => "omit",
+        })
+    }
+}
+
+impl FromStr for TitlePrivacy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "show" => Ok(TitlePrivacy::Show),
+            "hash" => Ok(TitlePrivacy::Hash),
+            "omit" => Ok(TitlePrivacy::Omit),
+            _ => Err(anyhow!("Unknown window title option {:?}, expected show, hash or omit", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    #[cfg(feature = "hyprland")]
+    Hyprland,
+    #[cfg(feature = "sway")]
+    Sway,
+    #[cfg(feature = "x11")]
+    X11,
+}
+
+impl Backend {
+    fn name(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "hyprland")]
+            Backend::Hyprland => "hyprland",
+            #[cfg(feature = "sway")]
+            Backend::Sway => "sway",
+            #[cfg(feature = "x11")]
+            Backend::X11 => "x11",
+        }
+    }
+}
+
+/// Picks the backend from the environment of the graphical session. Compositors are checked
+/// before X11, as Xwayland sets `DISPLAY` too.
+pub fn detect_backend() -> Option<Backend> {
+    #[cfg(feature = "hyprland")]
+    if std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+        return Some(Backend::Hyprland);
+    }
+    #[cfg(feature = "sway")]
+    if std::env::var_os("SWAYSOCK").is_some() {
+        return Some(Backend::Sway);
+    }
+    #[cfg(feature = "x11")]
+    if std::env::var_os("DISPLAY").is_some() {
+        return Some(Backend::X11);
+    }
+    None
+}
+
+pub fn sensors() -> Vec<Sensor> {
+    vec![Sensor {
+        name: "Active Application".to_string(),
+        state: SensorState {
+            unique_id: "active_application".to_string(),
+            sensor_type: "sensor".to_string(),
+            icon: "mdi:application".to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    }]
+}
+
+pub fn hash_title(title: &str) -> String {
+    Sha256::digest(title.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub fn sensor_states(window: Option<&FocusedWindow>, backend: &str, privacy: TitlePrivacy) -> Vec<SensorState> {
+    let mut states: Vec<SensorState> = sensors().into_iter().map(|sensor| sensor.state).collect();
+    let state = &mut states[0];
+    state.attributes.insert("backend".to_string(), json!(backend));
+    match window {
+        Some(window) => {
+            state.value = json!(window.class);
+            let title = match privacy {
+                TitlePrivacy::Show => Some(("title", window.title.clone())),
+                TitlePrivacy::Hash => Some(("title_hash", hash_title(&window.title))),
+                TitlePrivacy::Omit => None,
+            };
+            if let Some((key, title)) = title {
+                state.attributes.insert(key.to_string(), json!(title));
+            }
+        }
+        None => state.value = Value::Null,
+    }
+    states
+}
+
+// without any backend feature enabled there is nothing to watch with
+#[allow(unused_variables)]
+async fn watch(backend: Backend, window_tx: WindowSender) -> Result<(), Error> {
+    match backend {
+        #[cfg(feature = "hyprland")]
+        Backend::Hyprland => hyprland::watch(window_tx).await,
+        #[cfg(feature = "sway")]
+        Backend::Sway => sway::watch(window_tx).await,
+        #[cfg(feature = "x11")]
+        Backend::X11 => tokio::task::spawn_blocking(move || x11::watch(window_tx)).await?,
+    }
+}
+
+pub async fn start(sensor_tx: SensorSender, privacy: TitlePrivacy) {
+    let Some(backend) = detect_backend() else {
+        println!("No supported window system found, not monitoring the active window");
+        return;
+    };
+    let (window_tx, mut window_rx) = mpsc::unbounded_channel();
+    // dropped along with start, which stops the watcher; the X11 thread follows once window_rx is gone
+    let mut watcher = JoinSet::new();
+    watcher.spawn(async move {
+        if let Err(e) = watch(backend, window_tx).await {
+            println!("Stopped monitoring the active window: {}", e);
+        }
+    });
+
+    let mut last_states = vec![];
+    while let Some(window) = window_rx.recv().await {
+        send_changes(
+            &sensor_tx,
+            &mut last_states,
+            sensor_states(window.as_ref(), backend.name(), privacy),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor() -> FocusedWindow {
+        FocusedWindow {
+            class: "code".to_string(),
+            title: "main.rs - ha-agent-rs".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_title_privacy() {
+        let shown = sensor_states(Some(&editor()), "sway", TitlePrivacy::Show);
+        let hashed = sensor_states(Some(&editor()), "sway", TitlePrivacy::Hash);
+        let omitted = sensor_states(Some(&editor()), "sway", TitlePrivacy::Omit);
+
+        assert_eq!(shown[0].value, json!("code"));
+        assert_eq!(shown[0].attributes["title"], json!("main.rs - ha-agent-rs"));
+        assert_eq!(hashed[0].attributes["title_hash"], json!(hash_title("main.rs - ha-agent-rs")));
+        assert!(!hashed[0].attributes.contains_key("title"));
+        assert!(!omitted[0].attributes.contains_key("title"));
+        assert!(!omitted[0].attributes.contains_key("title_hash"));
+    }
+
+    #[test]
+    fn test_no_focused_window() {
+        let states = sensor_states(None, "x11", TitlePrivacy::Show);
+
+        assert_eq!(states[0].value, Value::Null);
+    }
+
+    #[test]
+    fn test_parse_title_privacy() {
+        assert_eq!("omit".parse::<TitlePrivacy>().unwrap(), TitlePrivacy::Omit);
+        assert!("everything".parse::<TitlePrivacy>().is_err());
+        assert_eq!(TitlePrivacy::Hash.to_string().parse::<TitlePrivacy>().unwrap(), TitlePrivacy::Hash);
+    }
+}
@@ -0,0 +1,133 @@
+// sway IPC -- https://man.archlinux.org/man/sway-ipc.7
+use std::env;
+
+use anyhow::{anyhow, Error};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+use super::{FocusedWindow, WindowSender};
+
+const MAGIC: &[u8] = b"i3-ipc";
+const SUBSCRIBE: u32 = 2;
+const GET_TREE: u32 = 4;
+
+async fn send(stream: &mut UnixStream, message_type: u32, payload: &str) -> Result<(), Error> {
+    let mut message = MAGIC.to_vec();
+    message.extend((payload.len() as u32).to_ne_bytes());
+    message.extend(message_type.to_ne_bytes());
+    message.extend(payload.as_bytes());
+    stream.write_all(&message).await?;
+    Ok(())
+}
+
+async fn receive(stream: &mut UnixStream) -> Result<(u32, Value), Error> {
+    let mut header = [0; 14];
+    stream.read_exact(&mut header).await?;
+    if &header[..6] != MAGIC {
+        return Err(anyhow!("Invalid sway IPC message"));
+    }
+    let length = u32::from_ne_bytes(header[6..10].try_into()?);
+    let message_type = u32::from_ne_bytes(header[10..14].try_into()?);
+    let mut payload = vec![0; length as usize];
+    stream.read_exact(&mut payload).await?;
+    Ok((message_type, serde_json::from_slice(&payload)?))
+}
+
+/// Native Wayland windows have an `app_id`, Xwayland ones an X11 class.
+pub fn window_from_node(node: &Value) -> FocusedWindow {
+    let class = node["app_id"]
+        .as_str()
+        .or_else(|| node["window_properties"]["class"].as_str())
+        .unwrap_or_default();
+    FocusedWindow {
+        class: class.to_string(),
+        title: node["name"].as_str().unwrap_or_default().to_string(),
+    }
+}
+
+pub fn focused_in_tree(node: &Value) -> Option<FocusedWindow> {
+    if node["focused"] == true && (node["type"] == "con" || node["type"] == "floating_con") {
+        return Some(window_from_node(node));
+    }
+    ["nodes", "floating_nodes"]
+        .iter()
+        .filter_map(|children| node[children].as_array())
+        .flatten()
+        .find_map(focused_in_tree)
+}
+
+/// Returns the new focus for window events that change it, and None for events that don't.
+pub fn parse_window_event(event: &Value) -> Option<Option<FocusedWindow>> {
+    let container = &event["container"];
+    match event["change"].as_str()? {
+        "focus" => Some(Some(window_from_node(container))),
+        "title" if container["focused"] == true => Some(Some(window_from_node(container))),
+        "close" if container["focused"] == true => Some(None),
+        _ => None,
+    }
+}
+
+pub async fn watch(window_tx: WindowSender) -> Result<(), Error> {
+    let path = env::var("SWAYSOCK")?;
+    let mut stream = UnixStream::connect(path).await?;
+
+    send(&mut stream, GET_TREE, "").await?;
+    let (_, tree) = receive(&mut stream).await?;
+    window_tx.send(focused_in_tree(&tree))?;
+
+    send(&mut stream, SUBSCRIBE, &json!(["window"]).to_string()).await?;
+    let (_, subscribed) = receive(&mut stream).await?;
+    if subscribed["success"] != true {
+        return Err(anyhow!("Failed to subscribe to sway window events"));
+    }
+    loop {
+        let (_, event) = receive(&mut stream).await?;
+        if let Some(window) = parse_window_event(&event) {
+            window_tx.send(window)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_focused_in_tree() {
+        let tree = json!({
+            "type": "root",
+            "nodes": [{
+                "type": "workspace",
+                "nodes": [
+                    { "type": "con", "focused": false, "app_id": "foot", "name": "shell" },
+                ],
+                "floating_nodes": [
+                    {
+                        "type": "floating_con",
+                        "focused": true,
+                        "app_id": null,
+                        "window_properties": { "class": "zoom" },
+                        "name": "Zoom Meeting"
+                    },
+                ]
+            }]
+        });
+
+        let window = focused_in_tree(&tree).unwrap();
+
+        assert_eq!(window.class, "zoom");
+        assert_eq!(window.title, "Zoom Meeting");
+    }
+
+    #[test]
+    fn test_parse_window_event() {
+        let focus = json!({ "change": "focus", "container": { "app_id": "foot", "name": "shell" } });
+        let unfocused_title = json!({ "change": "title", "container": { "focused": false, "name": "x" } });
+        let close = json!({ "change": "close", "container": { "focused": true, "app_id": "foot" } });
+
+        assert_eq!(parse_window_event(&focus).unwrap().unwrap().class, "foot");
+        assert_eq!(parse_window_event(&unfocused_title), None);
+        assert_eq!(parse_window_event(&close), Some(None));
+    }
+}
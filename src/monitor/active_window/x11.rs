@@ -0,0 +1,138 @@
+// EWMH -- https://specifications.freedesktop.org/wm-spec/latest/
+use std::os::fd::AsFd;
+
+use anyhow::Error;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{Atom, AtomEnum, ChangeWindowAttributesAux, ConnectionExt, EventMask, Window};
+use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+
+use super::{FocusedWindow, WindowSender};
+
+// how often a thread waiting for events checks whether the monitor is still there
+const CLOSED_CHECK_MS: u16 = 1000;
+
+struct Atoms {
+    net_active_window: Atom,
+    net_wm_name: Atom,
+    utf8_string: Atom,
+}
+
+impl Atoms {
+    fn intern(connection: &RustConnection) -> Result<Self, Error> {
+        let intern = |name: &[u8]| -> Result<Atom, Error> { Ok(connection.intern_atom(false, name)?.reply()?.atom) };
+        Ok(Self {
+            net_active_window: intern(b"_NET_ACTIVE_WINDOW")?,
+            net_wm_name: intern(b"_NET_WM_NAME")?,
+            utf8_string: intern(b"UTF8_STRING")?,
+        })
+    }
+}
+
+/// `WM_CLASS` holds two null-terminated strings, the instance and the class name.
+pub fn parse_wm_class(value: &[u8]) -> String {
+    let mut parts = value.split(|byte| *byte == 0).map(String::from_utf8_lossy);
+    let instance = parts.next().unwrap_or_default();
+    match parts.next() {
+        Some(class) if !class.is_empty() => class.to_string(),
+        _ => instance.to_string(),
+    }
+}
+
+fn property(connection: &RustConnection, window: Window, property: Atom, kind: Atom) -> Result<Vec<u8>, Error> {
+    Ok(connection
+        .get_property(false, window, property, kind, 0, u32::MAX)?
+        .reply()?
+        .value)
+}
+
+fn active_window(connection: &RustConnection, root: Window, atoms: &Atoms) -> Result<Option<Window>, Error> {
+    let reply = connection
+        .get_property(false, root, atoms.net_active_window, AtomEnum::WINDOW, 0, 1)?
+        .reply()?;
+    Ok(reply.value32().and_then(|mut windows| windows.next()).filter(|window| *window != 0))
+}
+
+fn read_window(connection: &RustConnection, window: Window, atoms: &Atoms) -> Result<FocusedWindow, Error> {
+    let class = property(connection, window, AtomEnum::WM_CLASS.into(), AtomEnum::STRING.into())?;
+    let mut title = property(connection, window, atoms.net_wm_name, atoms.utf8_string)?;
+    if title.is_empty() {
+        title = property(connection, window, AtomEnum::WM_NAME.into(), AtomEnum::STRING.into())?;
+    }
+    Ok(FocusedWindow {
+        class: parse_wm_class(&class),
+        title: String::from_utf8_lossy(&title).to_string(),
+    })
+}
+
+// x11rb blocks while waiting for events, so this runs on a blocking thread
+pub fn watch(window_tx: WindowSender) -> Result<(), Error> {
+    let (connection, screen) = x11rb::connect(None)?;
+    let root = connection.setup().roots[screen].root;
+    let atoms = Atoms::intern(&connection)?;
+    let property_changes = ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE);
+    connection.change_window_attributes(root, &property_changes)?;
+
+    let mut active = None;
+    loop {
+        let focused = active_window(&connection, root, &atoms)?;
+        if focused != active {
+            // follow title changes of the focused window
+            if let Some(window) = focused {
+                connection.change_window_attributes(window, &property_changes)?;
+            }
+            active = focused;
+        }
+        connection.flush()?;
+        // the window can be gone already, which is the same as nothing having focus
+        window_tx.send(active.and_then(|window| read_window(&connection, window, &atoms).ok()))?;
+
+        loop {
+            match next_event(&connection, &window_tx)? {
+                None => return Ok(()),
+                Some(Event::PropertyNotify(event)) if event.window == root && event.atom == atoms.net_active_window => {
+                    break
+                }
+                Some(Event::PropertyNotify(event))
+                    if Some(event.window) == active
+                        && (event.atom == atoms.net_wm_name || event.atom == Atom::from(AtomEnum::WM_NAME)) =>
+                {
+                    break
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Waits for the next event, or None once nobody listens any more. A blocking thread can't be
+/// aborted, so it has to notice by itself that the monitor is gone.
+fn next_event(connection: &RustConnection, window_tx: &WindowSender) -> Result<Option<Event>, Error> {
+    loop {
+        if let Some(event) = connection.poll_for_event()? {
+            return Ok(Some(event));
+        }
+        if window_tx.is_closed() {
+            return Ok(None);
+        }
+        let mut fds = [PollFd::new(connection.stream().as_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, PollTimeout::from(CLOSED_CHECK_MS)) {
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_wm_class() {
+        assert_eq!(parse_wm_class(b"navigator\0firefox\0"), "firefox");
+        assert_eq!(parse_wm_class(b"xterm\0"), "xterm");
+        assert_eq!(parse_wm_class(b""), "");
+    }
+}
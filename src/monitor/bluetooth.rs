@@ -0,0 +1,406 @@
+// BlueZ -- https://git.kernel.org/pub/scm/bluetooth/bluez.git/tree/doc
+use std::collections::HashMap;
+
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::select;
+use zbus::fdo::{ManagedObjects, ObjectManagerProxy};
+use zbus::message::Type;
+use zbus::zvariant::{self, OwnedValue};
+use zbus::{Connection, MatchRule, MessageStream};
+
+use crate::agent_state::{Sensor, SensorState};
+use crate::monitor::{send_changes, SensorSender};
+
+const BLUEZ: &str = "org.bluez";
+
+// Bluetooth assigned numbers, the Headset and Handsfree profiles
+const HEADSET_UUIDS: [&str; 2] = [
+    "00001108-0000-1000-8000-00805f9b34fb",
+    "0000111e-0000-1000-8000-00805f9b34fb",
+];
+// audio/video major device class, with its wearable headset, hands-free and headphones minor classes
+const AUDIO_VIDEO: u32 = 0x04;
+const HEADSET_MINOR_CLASSES: [u32; 3] = [0x01, 0x02, 0x06];
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BluetoothConfig {
+    pub enabled: bool,
+}
+
+impl Default for BluetoothConfig {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Adapter {
+    pub name: String,
+    pub address: String,
+    pub powered: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BluetoothDevice {
+    pub name: String,
+    pub address: String,
+    /// the freedesktop icon name BlueZ derives from the device class, e.g. audio-headset
+    pub icon: Option<String>,
+    pub class: Option<u32>,
+    pub uuids: Vec<String>,
+}
+
+impl BluetoothDevice {
+    pub fn is_headset(&self) -> bool {
+        let by_icon = matches!(self.icon.as_deref(), Some("audio-headset" | "audio-headphones"));
+        let by_class = self.class.is_some_and(|class| {
+            (class >> 8) & 0x1f == AUDIO_VIDEO && HEADSET_MINOR_CLASSES.contains(&((class >> 2) & 0x3f))
+        });
+        let by_profile = self
+            .uuids
+            .iter()
+            .any(|uuid| HEADSET_UUIDS.contains(&uuid.to_lowercase().as_str()));
+        by_icon || by_class || by_profile
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BluetoothStatus {
+    pub adapters: Vec<Adapter>,
+    /// only the connected ones
+    pub devices: Vec<BluetoothDevice>,
+}
+
+fn property_string(properties: &HashMap<String, OwnedValue>, key: &str) -> Option<String> {
+    match properties.get(key).map(|value| &**value) {
+        Some(zvariant::Value::Str(s)) => Some(s.to_string()),
+        _ => None,
+    }
+}
+
+fn property_bool(properties: &HashMap<String, OwnedValue>, key: &str) -> bool {
+    matches!(
+        properties.get(key).map(|value| &**value),
+        Some(zvariant::Value::Bool(true))
+    )
+}
+
+fn property_strings(properties: &HashMap<String, OwnedValue>, key: &str) -> Vec<String> {
+    match properties.get(key).map(|value| &**value) {
+        Some(zvariant::Value::Array(array)) => array
+            .iter()
+            .filter_map(|value| match value {
+                zvariant::Value::Str(s) => Some(s.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+pub fn parse_objects(objects: &ManagedObjects) -> BluetoothStatus {
+    let mut status = BluetoothStatus::default();
+    let mut paths: Vec<_> = objects.keys().collect();
+    paths.sort_by_key(|path| path.as_str());
+    for path in paths {
+        let interfaces = &objects[path];
+        if let Some(adapter) = interfaces.get("org.bluez.Adapter1") {
+            status.adapters.push(Adapter {
+                name: property_string(adapter, "Alias")
+                    .or_else(|| property_string(adapter, "Name"))
+                    .unwrap_or_default(),
+                address: property_string(adapter, "Address").unwrap_or_default(),
+                powered: property_bool(adapter, "Powered"),
+            });
+        }
+        if let Some(device) = interfaces.get("org.bluez.Device1") {
+            if !property_bool(device, "Connected") {
+                continue;
+            }
+            let address = property_string(device, "Address").unwrap_or_default();
+            status.devices.push(BluetoothDevice {
+                name: property_string(device, "Alias")
+                    .or_else(|| property_string(device, "Name"))
+                    .unwrap_or_else(|| address.clone()),
+                address,
+                icon: property_string(device, "Icon"),
+                class: match device.get("Class").map(|value| &**value) {
+                    Some(zvariant::Value::U32(class)) => Some(*class),
+                    _ => None,
+                },
+                uuids: property_strings(device, "UUIDs"),
+            });
+        }
+    }
+    status
+}
+
+pub async fn read_status(connection: &Connection) -> zbus::Result<BluetoothStatus> {
+    let manager = ObjectManagerProxy::builder(connection)
+        .destination(BLUEZ)?
+        .path("/")?
+        .build()
+        .await?;
+    Ok(parse_objects(&manager.get_managed_objects().await?))
+}
+
+pub fn sensors() -> Vec<Sensor> {
+    vec![
+        Sensor {
+            name: "Bluetooth".to_string(),
+            state: SensorState {
+                unique_id: "bluetooth_power".to_string(),
+                sensor_type: "binary_sensor".to_string(),
+                icon: "mdi:bluetooth".to_string(),
+                ..Default::default()
+            },
+            device_class: Some("power".to_string()),
+            ..Default::default()
+        },
+        Sensor {
+            name: "Bluetooth Connected Devices".to_string(),
+            state: SensorState {
+                unique_id: "bluetooth_connected_devices".to_string(),
+                sensor_type: "sensor".to_string(),
+                icon: "mdi:bluetooth-connect".to_string(),
+                ..Default::default()
+            },
+            state_class: Some("measurement".to_string()),
+            ..Default::default()
+        },
+        Sensor {
+            name: "Headset Connected".to_string(),
+            state: SensorState {
+                unique_id: "bluetooth_headset".to_string(),
+                sensor_type: "binary_sensor".to_string(),
+                icon: "mdi:headset".to_string(),
+                ..Default::default()
+            },
+            device_class: Some("connectivity".to_string()),
+            ..Default::default()
+        },
+    ]
+}
+
+pub fn sensor_states(status: &BluetoothStatus) -> Vec<SensorState> {
+    let device_json = |device: &BluetoothDevice| json!({ "name": device.name, "address": device.address });
+    let devices: Vec<Value> = status.devices.iter().map(device_json).collect();
+    let headsets: Vec<Value> = status
+        .devices
+        .iter()
+        .filter(|device| device.is_headset())
+        .map(device_json)
+        .collect();
+    let adapters: Vec<Value> = status
+        .adapters
+        .iter()
+        .map(|adapter| json!({ "name": adapter.name, "address": adapter.address, "powered": adapter.powered }))
+        .collect();
+
+    let mut states: Vec<SensorState> = sensors().into_iter().map(|sensor| sensor.state).collect();
+    states[0].value = json!(status.adapters.iter().any(|adapter| adapter.powered));
+    states[0].attributes.insert("adapters".to_string(), json!(adapters));
+    states[1].value = json!(devices.len());
+    states[1].attributes.insert("devices".to_string(), json!(devices));
+    states[2].value = json!(!headsets.is_empty());
+    states[2].attributes.insert("headsets".to_string(), json!(headsets));
+    states
+}
+
+async fn signal_stream(connection: &Connection) -> zbus::Result<futures::stream::SelectAll<MessageStream>> {
+    let properties_changed = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .sender(BLUEZ)?
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .build();
+    // adapters and devices coming and going
+    let objects_changed = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .sender(BLUEZ)?
+        .interface("org.freedesktop.DBus.ObjectManager")?
+        .build();
+    Ok(futures::stream::select_all([
+        MessageStream::for_match_rule(properties_changed, connection, None).await?,
+        MessageStream::for_match_rule(objects_changed, connection, None).await?,
+    ]))
+}
+
+async fn refresh(connection: &Connection, sensor_tx: &SensorSender, last_states: &mut Vec<SensorState>) {
+    match read_status(connection).await {
+        Ok(status) => send_changes(sensor_tx, last_states, sensor_states(&status)),
+        Err(e) => println!("Failed to read Bluetooth devices: {}", e),
+    }
+}
+
+pub async fn start(sensor_tx: SensorSender, config: BluetoothConfig) {
+    if !config.enabled {
+        return;
+    }
+    let connection = match Connection::system().await {
+        Ok(connection) => connection,
+        Err(e) => {
+            println!("No system bus, not monitoring Bluetooth: {}", e);
+            return;
+        }
+    };
+    let mut signals = match signal_stream(&connection).await {
+        Ok(signals) => signals,
+        Err(e) => {
+            println!("Failed to subscribe to BlueZ signals: {}", e);
+            return;
+        }
+    };
+    let mut last_states = vec![];
+
+    refresh(&connection, &sensor_tx, &mut last_states).await;
+    loop {
+        select! {
+            Some(_) = signals.next() => {
+                refresh(&connection, &sensor_tx, &mut last_states).await;
+            },
+            else => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::testing::start_bus;
+    use std::time::Duration;
+    use tokio::time::timeout;
+    use zbus::object_server::SignalEmitter;
+
+    struct FakeAdapter;
+
+    #[zbus::interface(name = "org.bluez.Adapter1")]
+    impl FakeAdapter {
+        #[zbus(property)]
+        fn alias(&self) -> String {
+            "laptop".to_string()
+        }
+
+        #[zbus(property)]
+        fn address(&self) -> String {
+            "00:1A:7D:DA:71:13".to_string()
+        }
+
+        #[zbus(property)]
+        fn powered(&self) -> bool {
+            true
+        }
+    }
+
+    struct FakeDevice {
+        alias: String,
+        icon: String,
+        connected: bool,
+    }
+
+    #[zbus::interface(name = "org.bluez.Device1")]
+    impl FakeDevice {
+        #[zbus(property)]
+        fn alias(&self) -> String {
+            self.alias.clone()
+        }
+
+        #[zbus(property)]
+        fn address(&self) -> String {
+            "AC:80:0A:11:22:33".to_string()
+        }
+
+        #[zbus(property)]
+        fn icon(&self) -> String {
+            self.icon.clone()
+        }
+
+        #[zbus(property)]
+        fn connected(&self) -> bool {
+            self.connected
+        }
+
+        #[zbus(property)]
+        fn set_connected(&mut self, connected: bool) {
+            self.connected = connected;
+        }
+    }
+
+    #[test]
+    fn test_is_headset() {
+        let by_class = BluetoothDevice {
+            class: Some(0x240404),
+            ..Default::default()
+        };
+        let by_profile = BluetoothDevice {
+            uuids: vec!["0000111E-0000-1000-8000-00805F9B34FB".to_string()],
+            ..Default::default()
+        };
+        let keyboard = BluetoothDevice {
+            icon: Some("input-keyboard".to_string()),
+            class: Some(0x002540),
+            uuids: vec!["00001124-0000-1000-8000-00805f9b34fb".to_string()],
+            ..Default::default()
+        };
+
+        assert!(by_class.is_headset());
+        assert!(by_profile.is_headset());
+        assert!(!keyboard.is_headset());
+    }
+
+    #[tokio::test]
+    async fn test_read_status_and_signals() {
+        let Some((_bus, address)) = start_bus() else {
+            return;
+        };
+        let bluez = zbus::connection::Builder::address(address.as_str())
+            .unwrap()
+            .name(BLUEZ)
+            .unwrap()
+            .serve_at("/", zbus::fdo::ObjectManager)
+            .unwrap()
+            .serve_at("/org/bluez/hci0", FakeAdapter)
+            .unwrap()
+            .serve_at(
+                "/org/bluez/hci0/dev_AC_80_0A_11_22_33",
+                FakeDevice {
+                    alias: "WH-1000XM4".to_string(),
+                    icon: "audio-headset".to_string(),
+                    connected: true,
+                },
+            )
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let connection = zbus::connection::Builder::address(address.as_str())
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let mut signals = signal_stream(&connection).await.unwrap();
+
+        let states = sensor_states(&read_status(&connection).await.unwrap());
+        assert_eq!(states[0].value, json!(true));
+        assert_eq!(states[1].value, json!(1));
+        assert_eq!(states[1].attributes["devices"][0]["name"], json!("WH-1000XM4"));
+        assert_eq!(states[2].value, json!(true));
+
+        let device = bluez
+            .object_server()
+            .interface::<_, FakeDevice>("/org/bluez/hci0/dev_AC_80_0A_11_22_33")
+            .await
+            .unwrap();
+        device.get_mut().await.connected = false;
+        let emitter = SignalEmitter::new(&bluez, "/org/bluez/hci0/dev_AC_80_0A_11_22_33").unwrap();
+        device.get().await.connected_changed(&emitter).await.unwrap();
+
+        timeout(Duration::from_secs(5), signals.next()).await.unwrap();
+        let states = sensor_states(&read_status(&connection).await.unwrap());
+        assert_eq!(states[1].value, json!(0));
+        assert_eq!(states[2].value, json!(false));
+    }
+}
@@ -0,0 +1,348 @@
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::process::Command;
+use tokio::select;
+use tokio::task::JoinSet;
+use tokio::time::{interval, timeout};
+
+use crate::agent_state::{Sensor, SensorState};
+use crate::monitor::inotify::PathWatcher;
+use crate::monitor::system::slug;
+use crate::monitor::{send_changes, SensorSender};
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Parser {
+    /// the trimmed output
+    #[default]
+    Raw,
+    Number,
+    /// the value at `json_path`, e.g. `battery.level` or `devices.0.name`
+    Json,
+    /// the first capture group of `regex`, or the whole match without one
+    Regex,
+}
+
+/// A sensor backed by a shell command, from a `[[commands]]` section of the config file.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CommandSensorConfig {
+    pub name: String,
+    /// run with `sh -c`
+    pub command: String,
+    /// defaults to `command_` followed by the name in lower case
+    pub unique_id: Option<String>,
+    /// sensor or binary_sensor
+    #[serde(rename = "type", default = "default_sensor_type")]
+    pub sensor_type: String,
+    /// seconds between runs; without it and without `watch`, every minute
+    pub interval: Option<u64>,
+    /// runs the command whenever this file or directory changes
+    pub watch: Option<PathBuf>,
+    #[serde(default)]
+    pub parser: Parser,
+    pub json_path: Option<String>,
+    pub regex: Option<String>,
+    pub unit: Option<String>,
+    pub device_class: Option<String>,
+    pub state_class: Option<String>,
+    pub icon: Option<String>,
+    /// seconds before the command is killed
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_sensor_type() -> String {
+    "sensor".to_string()
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+impl CommandSensorConfig {
+    pub fn unique_id(&self) -> String {
+        self.unique_id
+            .clone()
+            .unwrap_or_else(|| format!("command_{}", slug(&self.name)))
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.sensor_type != "sensor" && self.sensor_type != "binary_sensor" {
+            return Err(anyhow!("{}: type must be sensor or binary_sensor", self.name));
+        }
+        match self.parser {
+            Parser::Json if self.json_path.is_none() => {
+                Err(anyhow!("{}: the json parser needs a json_path", self.name))
+            }
+            Parser::Regex => match &self.regex {
+                Some(regex) => Regex::new(regex)
+                    .map(|_| ())
+                    .map_err(|e| anyhow!("{}: {}", self.name, e)),
+                None => Err(anyhow!("{}: the regex parser needs a regex", self.name)),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    pub fn sensor(&self) -> Sensor {
+        Sensor {
+            name: self.name.clone(),
+            state: SensorState {
+                unique_id: self.unique_id(),
+                sensor_type: self.sensor_type.clone(),
+                icon: self.icon.clone().unwrap_or_else(|| "mdi:console".to_string()),
+                ..Default::default()
+            },
+            device_class: self.device_class.clone(),
+            unit_of_measurement: self.unit.clone(),
+            state_class: self.state_class.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+pub fn sensors(configs: &[CommandSensorConfig]) -> Vec<Sensor> {
+    configs.iter().map(CommandSensorConfig::sensor).collect()
+}
+
+fn json_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    let path = path.trim_start_matches('$').trim_start_matches('.');
+    path.split('.')
+        .filter(|key| !key.is_empty())
+        .try_fold(value, |value, key| match value {
+            Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+            _ => value.get(key),
+        })
+}
+
+pub fn parse_output(config: &CommandSensorConfig, output: &str) -> Result<Value, Error> {
+    let output = output.trim();
+    let value = match config.parser {
+        Parser::Raw => json!(output),
+        Parser::Number => match serde_json::from_str::<Value>(output) {
+            Ok(number @ Value::Number(_)) => number,
+            _ => return Err(anyhow!("{:?} is not a number", output)),
+        },
+        Parser::Json => {
+            let document: Value = serde_json::from_str(output)?;
+            let path = config.json_path.as_deref().unwrap_or_default();
+            json_path(&document, path)
+                .cloned()
+                .ok_or_else(|| anyhow!("nothing at {}", path))?
+        }
+        Parser::Regex => {
+            let regex = Regex::new(config.regex.as_deref().unwrap_or_default())?;
+            let captures = regex
+                .captures(output)
+                .ok_or_else(|| anyhow!("{} doesn't match", regex))?;
+            let matched = captures.get(1).or_else(|| captures.get(0)).map_or("", |m| m.as_str());
+            json!(matched)
+        }
+    };
+    Ok(if config.sensor_type == "binary_sensor" {
+        json!(is_on(&value))
+    } else {
+        value
+    })
+}
+
+/// How binary sensors read the parsed output: true, non-zero numbers and the usual words for on.
+pub fn is_on(value: &Value) -> bool {
+    match value {
+        Value::Bool(on) => *on,
+        Value::Number(number) => number.as_f64().is_some_and(|number| number != 0.0),
+        Value::String(text) => matches!(text.to_lowercase().as_str(), "on" | "true" | "yes" | "1"),
+        _ => false,
+    }
+}
+
+/// Runs the command, leaving the state unknown with an `error` attribute when that fails.
+pub async fn run(config: &CommandSensorConfig) -> SensorState {
+    let mut state = config.sensor().state;
+    let spawned = Command::new("sh")
+        .args(["-c", &config.command])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // a group of its own, so a timeout kills what the shell started along with it
+        .process_group(0)
+        .kill_on_drop(true)
+        .spawn();
+    let child = match spawned {
+        Ok(child) => child,
+        Err(e) => {
+            state.attributes.insert("error".to_string(), json!(e.to_string()));
+            return state;
+        }
+    };
+    let group = child.id().map(|pid| Pid::from_raw(pid as i32));
+    let output = match timeout(Duration::from_secs(config.timeout), child.wait_with_output()).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => {
+            state.attributes.insert("error".to_string(), json!(e.to_string()));
+            return state;
+        }
+        Err(_) => {
+            if let Some(group) = group {
+                _ = killpg(group, Signal::SIGKILL);
+            }
+            let error = format!("timed out after {} seconds", config.timeout);
+            state.attributes.insert("error".to_string(), json!(error));
+            return state;
+        }
+    };
+    if let Some(code) = output.status.code() {
+        state.attributes.insert("exit_code".to_string(), json!(code));
+    }
+    match parse_output(config, &String::from_utf8_lossy(&output.stdout)) {
+        Ok(value) => state.value = value,
+        Err(e) => {
+            state.attributes.insert("error".to_string(), json!(e.to_string()));
+        }
+    }
+    state
+}
+
+async fn watch(sensor_tx: SensorSender, config: CommandSensorConfig) -> Result<(), Error> {
+    let mut watcher = match &config.watch {
+        Some(path) => {
+            let mut watcher = PathWatcher::new()?;
+            watcher.watch(path)?;
+            Some(watcher)
+        }
+        None => None,
+    };
+    let every = match (config.interval, &watcher) {
+        (Some(seconds), _) => Some(Duration::from_secs(seconds.max(1))),
+        (None, None) => Some(Duration::from_secs(60)),
+        (None, Some(_)) => None,
+    };
+    let mut ticks = every.map(interval);
+    let mut last_states = vec![];
+
+    send_changes(&sensor_tx, &mut last_states, vec![run(&config).await]);
+    if let Some(ticks) = &mut ticks {
+        ticks.tick().await;
+    }
+    loop {
+        select! {
+            Some(_) = async { Some(ticks.as_mut()?.tick().await) } => {},
+            Some(changed) = async { Some(watcher.as_mut()?.changed().await) } => {
+                changed?;
+            },
+        }
+        send_changes(&sensor_tx, &mut last_states, vec![run(&config).await]);
+    }
+}
+
+/// Runs every command on its own schedule. The commands stop when this is aborted.
+pub async fn start(sensor_tx: SensorSender, configs: Vec<CommandSensorConfig>) {
+    let mut commands = JoinSet::new();
+    for config in configs {
+        let sensor_tx = sensor_tx.clone();
+        commands.spawn(async move {
+            let name = config.name.clone();
+            if let Err(e) = watch(sensor_tx, config).await {
+                println!("Stopped running the command for {}: {}", name, e);
+            }
+        });
+    }
+    while commands.join_next().await.is_some() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Monitors;
+    use tokio::time::sleep;
+
+    fn config(toml: &str) -> CommandSensorConfig {
+        let monitors: Monitors = toml::from_str(&format!("[[commands]]\nname = \"Test\"\n{}", toml)).unwrap();
+        monitors.commands[0].clone()
+    }
+
+    #[test]
+    fn test_parse_output() {
+        let raw = config("command = \"\"");
+        let number = config("command = \"\"\nparser = \"number\"");
+        let json_config = config("command = \"\"\nparser = \"json\"\njson_path = \"$.devices.1.level\"");
+        let regex = config("command = \"\"\nparser = \"regex\"\nregex = 'temp=(\\d+)'");
+        let binary = config("command = \"\"\ntype = \"binary_sensor\"");
+
+        assert_eq!(parse_output(&raw, " backing up\n").unwrap(), json!("backing up"));
+        assert_eq!(parse_output(&number, "42.5\n").unwrap(), json!(42.5));
+        assert!(parse_output(&number, "lots").is_err());
+        assert_eq!(
+            parse_output(&json_config, r#"{"devices": [{"level": 10}, {"level": 80}]}"#).unwrap(),
+            json!(80)
+        );
+        assert!(parse_output(&json_config, r#"{"devices": []}"#).is_err());
+        assert_eq!(parse_output(&regex, "cpu temp=61 fan=1200").unwrap(), json!("61"));
+        assert_eq!(parse_output(&binary, "ON\n").unwrap(), json!(true));
+        assert_eq!(parse_output(&binary, "0").unwrap(), json!(false));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(config("command = \"\"").validate().is_ok());
+        assert!(config("command = \"\"\nparser = \"json\"").validate().is_err());
+        assert!(config("command = \"\"\nparser = \"regex\"\nregex = \"(\"")
+            .validate()
+            .is_err());
+        assert!(config("command = \"\"\ntype = \"switch\"").validate().is_err());
+        assert_eq!(config("command = \"\"").unique_id(), "command_test");
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let dirty = config("command = \"echo 3; exit 1\"\nparser = \"number\"\nunit = \"files\"");
+        let slow = config("command = \"sleep 5\"\ntimeout = 1");
+
+        let state = run(&dirty).await;
+        assert_eq!(state.value, json!(3));
+        assert_eq!(state.attributes["exit_code"], json!(1));
+
+        let state = run(&slow).await;
+        assert_eq!(state.value, Value::Null);
+        assert_eq!(state.attributes["error"], json!("timed out after 1 seconds"));
+    }
+
+    #[tokio::test]
+    async fn test_run_kills_what_the_command_started() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let slow = config(&format!(
+            "command = \"sleep 30 & echo $! > {}; wait\"\ntimeout = 1",
+            pid_file.display()
+        ));
+
+        run(&slow).await;
+        let pid: i32 = std::fs::read_to_string(&pid_file).unwrap().trim().parse().unwrap();
+        sleep(Duration::from_millis(200)).await;
+        // gone, or a zombie waiting to be reaped by whoever inherited it from the shell
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+        assert!(stat.is_empty() || stat.contains(") Z "));
+    }
+
+    #[tokio::test]
+    async fn test_start_stops_its_commands() {
+        let (sensor_tx, mut sensor_rx) = tokio::sync::mpsc::unbounded_channel();
+        let counter = config("command = \"date +%s%N\"\ninterval = 1");
+        let commands = tokio::spawn(start(sensor_tx, vec![counter]));
+        assert!(timeout(Duration::from_secs(5), sensor_rx.recv()).await.unwrap().is_some());
+
+        commands.abort();
+        _ = commands.await;
+        // the sender is only dropped once the command's own task is gone too
+        assert_eq!(timeout(Duration::from_secs(3), sensor_rx.recv()).await, Ok(None));
+    }
+}
@@ -0,0 +1,373 @@
+// Docker Engine API -- https://docs.docker.com/reference/api/engine/
+// Podman serves the same API on its own socket
+use std::env;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::select;
+use tokio::sync::broadcast::Receiver;
+use tokio::time::interval;
+
+use crate::agent_state::{Sensor, SensorState};
+use crate::connection::Command;
+use crate::monitor::system::slug;
+use crate::monitor::{send_changes, SensorSender};
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Start,
+    Stop,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContainersConfig {
+    pub enabled: bool,
+    /// the Docker or Podman API socket, found by itself when left out
+    pub socket: Option<PathBuf>,
+    /// seconds between readings
+    pub interval: u64,
+    /// container names to report the status of, besides the running count
+    pub watch: Vec<String>,
+    /// what Home Assistant may do with the watched containers, nothing unless listed
+    pub actions: Vec<Action>,
+}
+
+impl Default for ContainersConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            socket: None,
+            interval: 30,
+            watch: vec![],
+            actions: vec![],
+        }
+    }
+}
+
+/// `$DOCKER_HOST`, then the Docker socket, then Podman's rootless and rootful sockets.
+pub fn find_socket() -> Option<PathBuf> {
+    if let Some(path) = env::var("DOCKER_HOST")
+        .ok()
+        .and_then(|host| host.strip_prefix("unix://").map(PathBuf::from))
+    {
+        return Some(path);
+    }
+    let rootless = env::var("XDG_RUNTIME_DIR").map(|dir| Path::new(&dir).join("podman/podman.sock"));
+    [PathBuf::from("/var/run/docker.sock")]
+        .into_iter()
+        .chain(rootless)
+        .chain([PathBuf::from("/run/podman/podman.sock")])
+        .find(|path| path.exists())
+}
+
+/// Undoes `Transfer-Encoding: chunked`.
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut data = vec![];
+    // responses without a body, like 204s, have no chunks at all
+    while !body.is_empty() {
+        let line_end = body
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or_else(|| anyhow!("truncated chunk"))?;
+        let size = std::str::from_utf8(&body[..line_end])?;
+        let size = usize::from_str_radix(size.split(';').next().unwrap_or_default().trim(), 16)?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(data);
+        }
+        data.extend_from_slice(body.get(..size).ok_or_else(|| anyhow!("truncated chunk"))?);
+        body = body.get(size + 2..).unwrap_or_default();
+    }
+    Ok(data)
+}
+
+/// A single HTTP/1.0 request over the socket, which the server answers and then hangs up on.
+pub async fn request(socket: &Path, method: &str, path: &str) -> Result<(u16, Value), Error> {
+    let mut stream = UnixStream::connect(socket).await?;
+    let request = format!(
+        "{} {} HTTP/1.0\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n",
+        method, path
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await?;
+
+    let header_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| anyhow!("incomplete response"))?;
+    let head = String::from_utf8_lossy(&response[..header_end]).to_lowercase();
+    let status: u16 = head
+        .split_whitespace()
+        .nth(1)
+        .ok_or_else(|| anyhow!("no status in the response"))?
+        .parse()?;
+    let mut body = response[header_end + 4..].to_vec();
+    if head
+        .lines()
+        .any(|line| line.starts_with("transfer-encoding:") && line.contains("chunked"))
+    {
+        body = dechunk(&body)?;
+    }
+    let body = if body.iter().all(u8::is_ascii_whitespace) {
+        Value::Null
+    } else {
+        serde_json::from_slice(&body)?
+    };
+    Ok((status, body))
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContainerStatus {
+    /// created, running, paused, restarting, removing, exited or dead
+    pub state: String,
+    /// starting, healthy or unhealthy, for containers with a health check
+    pub health: Option<String>,
+    pub image: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContainersStatus {
+    pub running: usize,
+    /// per watched container, None when there's no such container
+    pub containers: Vec<Option<ContainerStatus>>,
+}
+
+pub async fn read_status(socket: &Path, config: &ContainersConfig) -> Result<ContainersStatus, Error> {
+    let (_, running) = request(socket, "GET", "/containers/json").await?;
+    let mut containers = vec![];
+    for name in &config.watch {
+        let container = match request(socket, "GET", &format!("/containers/{}/json", name)).await? {
+            (200, inspect) => Some(ContainerStatus {
+                state: inspect["State"]["Status"].as_str().unwrap_or_default().to_string(),
+                health: inspect["State"]["Health"]["Status"].as_str().map(str::to_string),
+                image: inspect["Config"]["Image"].as_str().map(str::to_string),
+            }),
+            (404, _) => None,
+            (status, body) => return Err(anyhow!("inspecting {} failed with {}: {}", name, status, body)),
+        };
+        containers.push(container);
+    }
+    Ok(ContainersStatus {
+        running: running.as_array().map_or(0, Vec::len),
+        containers,
+    })
+}
+
+pub fn sensors(config: &ContainersConfig) -> Vec<Sensor> {
+    let running = Sensor {
+        name: "Running Containers".to_string(),
+        state: SensorState {
+            unique_id: "containers_running".to_string(),
+            sensor_type: "sensor".to_string(),
+            icon: "mdi:docker".to_string(),
+            ..Default::default()
+        },
+        state_class: Some("measurement".to_string()),
+        ..Default::default()
+    };
+    let containers = config.watch.iter().map(|name| Sensor {
+        name: format!("Container {}", name),
+        state: SensorState {
+            unique_id: format!("container_{}", slug(name)),
+            sensor_type: "sensor".to_string(),
+            icon: "mdi:package-variant".to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    [running].into_iter().chain(containers).collect()
+}
+
+pub fn sensor_states(config: &ContainersConfig, status: &ContainersStatus) -> Vec<SensorState> {
+    let mut states: Vec<SensorState> = sensors(config).into_iter().map(|sensor| sensor.state).collect();
+    states[0].value = json!(status.running);
+    for ((name, container), state) in config.watch.iter().zip(&status.containers).zip(&mut states[1..]) {
+        state.attributes.insert("container".to_string(), json!(name));
+        // a container that doesn't exist (anymore) is unknown rather than stopped
+        if let Some(container) = container {
+            state.value = json!(container.state);
+            state.attributes.insert("health".to_string(), json!(container.health));
+            state.attributes.insert("image".to_string(), json!(container.image));
+        }
+    }
+    states
+}
+
+/// Handles a `command_container` notification, e.g.
+/// `{"message": "command_container", "data": {"container": "jellyfin", "action": "stop"}}`.
+/// Only watched containers can be controlled, and only with the allowed actions.
+pub async fn handle_command(socket: &Path, config: &ContainersConfig, command: &Command) -> Result<(), Error> {
+    if command.message != "command_container" {
+        return Ok(());
+    }
+    let name = command.data["container"].as_str().unwrap_or_default();
+    let action: Action = serde_json::from_value(command.data["action"].clone())?;
+    if !config.watch.iter().any(|watched| watched == name) {
+        return Err(anyhow!("{} is not a watched container", name));
+    }
+    if !config.actions.contains(&action) {
+        return Err(anyhow!("{:?} is not allowed for containers", action));
+    }
+    let path = match action {
+        Action::Start => format!("/containers/{}/start", name),
+        Action::Stop => format!("/containers/{}/stop", name),
+    };
+    match request(socket, "POST", &path).await? {
+        // 304 means it already was started or stopped
+        (204 | 304, _) => Ok(()),
+        (status, body) => Err(anyhow!("{:?} {} failed with {}: {}", action, name, status, body)),
+    }
+}
+
+async fn refresh(
+    socket: &Path,
+    config: &ContainersConfig,
+    sensor_tx: &SensorSender,
+    last_states: &mut Vec<SensorState>,
+) {
+    match read_status(socket, config).await {
+        Ok(status) => send_changes(sensor_tx, last_states, sensor_states(config, &status)),
+        Err(e) => println!("Failed to read containers: {}", e),
+    }
+}
+
+pub async fn start(sensor_tx: SensorSender, mut commands: Receiver<Command>, config: ContainersConfig) {
+    if !config.enabled {
+        return;
+    }
+    let Some(socket) = config.socket.clone().or_else(find_socket) else {
+        println!("No Docker or Podman socket, not monitoring containers");
+        return;
+    };
+    let mut ticks = interval(Duration::from_secs(config.interval.max(1)));
+    let mut last_states = vec![];
+    loop {
+        select! {
+            _ = ticks.tick() => {},
+            Ok(command) = commands.recv() => {
+                // notifications for the other monitors
+                if command.message != "command_container" {
+                    continue;
+                }
+                if let Err(e) = handle_command(&socket, &config, &command).await {
+                    println!("Failed to handle container command: {}", e);
+                    continue;
+                }
+            },
+        }
+        refresh(&socket, &config, &sensor_tx, &mut last_states).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tempfile::tempdir;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::UnixListener;
+
+    // Answers like the Docker API does, with jellyfin running and postgres unhealthy
+    async fn serve(listener: UnixListener, requests: Arc<Mutex<Vec<String>>>) {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut request_line = String::new();
+            stream.read_line(&mut request_line).await.unwrap();
+            let request_line = request_line.trim().trim_end_matches(" HTTP/1.0").to_string();
+            requests.lock().unwrap().push(request_line.clone());
+            let (status, body) = match request_line.as_str() {
+                "GET /containers/json" => ("200 OK", r#"[{"Names": ["/jellyfin"]}]"#),
+                "GET /containers/jellyfin/json" => (
+                    "200 OK",
+                    r#"{"State": {"Status": "running"}, "Config": {"Image": "jellyfin/jellyfin"}}"#,
+                ),
+                "GET /containers/postgres/json" => (
+                    "200 OK",
+                    r#"{"State": {"Status": "exited", "Health": {"Status": "unhealthy"}}, "Config": {"Image": "postgres:16"}}"#,
+                ),
+                "POST /containers/jellyfin/stop" => ("204 No Content", ""),
+                _ => ("404 Not Found", r#"{"message": "No such container"}"#),
+            };
+            // chunked like Docker's own responses, to exercise the decoding
+            let chunked = if body.is_empty() {
+                String::new()
+            } else {
+                format!("{:x}\r\n{}\r\n0\r\n\r\n", body.len(), body)
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\n\r\n{}",
+                status, chunked
+            );
+            stream.get_mut().write_all(response.as_bytes()).await.unwrap();
+        }
+    }
+
+    fn config(socket: &Path) -> ContainersConfig {
+        ContainersConfig {
+            socket: Some(socket.to_path_buf()),
+            watch: vec!["jellyfin".to_string(), "postgres".to_string(), "gone".to_string()],
+            actions: vec![Action::Stop],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_dechunk() {
+        assert_eq!(
+            dechunk(b"4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\n\r\n").unwrap(),
+            b"Wikipedia"
+        );
+        assert!(dechunk(b"a\r\nshort").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_status() {
+        let dir = tempdir().unwrap();
+        let socket = dir.path().join("docker.sock");
+        tokio::spawn(serve(UnixListener::bind(&socket).unwrap(), Arc::default()));
+        let config = config(&socket);
+
+        let states = sensor_states(&config, &read_status(&socket, &config).await.unwrap());
+
+        assert_eq!(states[0].value, json!(1));
+        assert_eq!(states[1].unique_id, "container_jellyfin");
+        assert_eq!(states[1].value, json!("running"));
+        assert_eq!(states[2].value, json!("exited"));
+        assert_eq!(states[2].attributes["health"], json!("unhealthy"));
+        assert_eq!(states[3].value, Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_handle_command() {
+        let dir = tempdir().unwrap();
+        let socket = dir.path().join("docker.sock");
+        let requests = Arc::new(Mutex::new(vec![]));
+        tokio::spawn(serve(UnixListener::bind(&socket).unwrap(), requests.clone()));
+        let config = config(&socket);
+        let command = |container: &str, action: &str| Command {
+            message: "command_container".to_string(),
+            data: json!({ "container": container, "action": action }),
+        };
+
+        handle_command(&socket, &config, &command("jellyfin", "stop"))
+            .await
+            .unwrap();
+        assert!(handle_command(&socket, &config, &command("jellyfin", "start"))
+            .await
+            .is_err());
+        assert!(handle_command(&socket, &config, &command("traefik", "stop"))
+            .await
+            .is_err());
+
+        assert_eq!(*requests.lock().unwrap(), vec!["POST /containers/jellyfin/stop"]);
+    }
+}
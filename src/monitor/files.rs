@@ -0,0 +1,230 @@
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Error;
+use chrono::DateTime;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::agent_state::{Sensor, SensorState};
+use crate::monitor::inotify::PathWatcher;
+use crate::monitor::system::slug;
+use crate::monitor::{send_changes, SensorSender};
+
+// Home Assistant cuts states off at 255 characters, and only small files are meant to be read
+const MAX_CONTENT: usize = 255;
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Report {
+    /// the trimmed content of a small file, e.g. an LED's brightness in sysfs
+    #[default]
+    Content,
+    Exists,
+    /// in bytes, for a directory the total of the files directly in it
+    Size,
+    /// when the file was last modified
+    Modified,
+    Lines,
+    /// the number of entries in a directory
+    Entries,
+}
+
+/// A sensor following a file or directory, from a `[[files]]` section of the config file.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FileSensorConfig {
+    pub name: String,
+    pub path: PathBuf,
+    #[serde(default)]
+    pub report: Report,
+    /// defaults to `file_` followed by the name in lower case
+    pub unique_id: Option<String>,
+    pub unit: Option<String>,
+    pub device_class: Option<String>,
+    pub icon: Option<String>,
+}
+
+impl FileSensorConfig {
+    pub fn unique_id(&self) -> String {
+        self.unique_id
+            .clone()
+            .unwrap_or_else(|| format!("file_{}", slug(&self.name)))
+    }
+
+    pub fn sensor(&self) -> Sensor {
+        let (sensor_type, unit, device_class, state_class) = match self.report {
+            Report::Exists => ("binary_sensor", None, None, None),
+            Report::Size => ("sensor", Some("B"), Some("data_size"), Some("measurement")),
+            Report::Modified => ("sensor", None, Some("timestamp"), None),
+            Report::Lines | Report::Entries => ("sensor", None, None, Some("measurement")),
+            Report::Content => ("sensor", None, None, None),
+        };
+        Sensor {
+            name: self.name.clone(),
+            state: SensorState {
+                unique_id: self.unique_id(),
+                sensor_type: sensor_type.to_string(),
+                icon: self.icon.clone().unwrap_or_else(|| "mdi:file-eye".to_string()),
+                ..Default::default()
+            },
+            unit_of_measurement: self.unit.clone().or(unit.map(str::to_string)),
+            device_class: self.device_class.clone().or(device_class.map(str::to_string)),
+            state_class: state_class.map(str::to_string),
+            ..Default::default()
+        }
+    }
+}
+
+pub fn sensors(configs: &[FileSensorConfig]) -> Vec<Sensor> {
+    configs.iter().map(FileSensorConfig::sensor).collect()
+}
+
+fn read_value(config: &FileSensorConfig) -> Result<Value, Error> {
+    let path = &config.path;
+    Ok(match config.report {
+        Report::Exists => json!(path.exists()),
+        Report::Content => {
+            let mut content = String::new();
+            File::open(path)?.take(4096).read_to_string(&mut content)?;
+            json!(content.trim().chars().take(MAX_CONTENT).collect::<String>())
+        }
+        Report::Size if path.is_dir() => {
+            let mut total = 0;
+            for entry in fs::read_dir(path)? {
+                let metadata = entry?.metadata()?;
+                if metadata.is_file() {
+                    total += metadata.len();
+                }
+            }
+            json!(total)
+        }
+        Report::Size => json!(fs::metadata(path)?.len()),
+        Report::Modified => {
+            let modified = fs::metadata(path)?.modified()?.duration_since(UNIX_EPOCH)?;
+            let modified = DateTime::from_timestamp(modified.as_secs() as i64, modified.subsec_nanos());
+            json!(modified.map(|modified| modified.to_rfc3339()))
+        }
+        Report::Lines => json!(BufReader::new(File::open(path)?).lines().count()),
+        Report::Entries => json!(fs::read_dir(path)?.count()),
+    })
+}
+
+/// A file that can't be read, e.g. because it doesn't exist (yet), leaves the sensor unknown.
+pub fn read_state(config: &FileSensorConfig) -> SensorState {
+    let mut state = config.sensor().state;
+    state.attributes.insert("path".to_string(), json!(config.path));
+    match read_value(config) {
+        Ok(value) => state.value = value,
+        Err(e) => {
+            state.attributes.insert("error".to_string(), json!(e.to_string()));
+        }
+    }
+    state
+}
+
+async fn watch(sensor_tx: SensorSender, configs: Vec<FileSensorConfig>) -> Result<(), Error> {
+    let mut watcher = PathWatcher::new()?;
+    // the configs by their index in the watcher, leaving out the ones that can't be watched
+    let mut watched = vec![];
+    for (index, config) in configs.iter().enumerate() {
+        match watcher.watch(&config.path) {
+            Ok(_) => watched.push(index),
+            Err(e) => println!("Not watching {}: {}", config.path.display(), e),
+        }
+    }
+    let mut last_states: Vec<Vec<SensorState>> = vec![vec![]; configs.len()];
+    for (config, last) in configs.iter().zip(&mut last_states) {
+        send_changes(&sensor_tx, last, vec![read_state(config)]);
+    }
+    loop {
+        for index in watcher.changed().await? {
+            let index = watched[index];
+            send_changes(&sensor_tx, &mut last_states[index], vec![read_state(&configs[index])]);
+        }
+    }
+}
+
+pub async fn start(sensor_tx: SensorSender, configs: Vec<FileSensorConfig>) {
+    if configs.is_empty() {
+        return;
+    }
+    if let Err(e) = watch(sensor_tx, configs).await {
+        println!("Stopped watching files: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::tempdir;
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+
+    fn config(name: &str, path: PathBuf, report: Report) -> FileSensorConfig {
+        FileSensorConfig {
+            name: name.to_string(),
+            path,
+            report,
+            unique_id: None,
+            unit: None,
+            device_class: None,
+            icon: None,
+        }
+    }
+
+    #[test]
+    fn test_read_state() {
+        let dir = tempdir().unwrap();
+        let log = dir.path().join("build.log");
+        fs::write(&log, "one\ntwo\nthree\n").unwrap();
+        fs::write(dir.path().join("brightness"), "255\n").unwrap();
+
+        let state = |report, path: PathBuf| read_state(&config("Test", path, report)).value;
+
+        assert_eq!(state(Report::Content, dir.path().join("brightness")), json!("255"));
+        assert_eq!(state(Report::Lines, log.clone()), json!(3));
+        assert_eq!(state(Report::Size, log.clone()), json!(14));
+        assert_eq!(state(Report::Size, dir.path().to_path_buf()), json!(18));
+        assert_eq!(state(Report::Entries, dir.path().to_path_buf()), json!(2));
+        assert_eq!(state(Report::Exists, dir.path().join("missing")), json!(false));
+        assert!(state(Report::Modified, log).as_str().unwrap().contains('T'));
+
+        let missing = read_state(&config("Test", dir.path().join("missing"), Report::Lines));
+        assert_eq!(missing.value, Value::Null);
+        assert!(missing.attributes.contains_key("error"));
+    }
+
+    #[test]
+    fn test_sensor() {
+        let sensor = config("Backup Size", PathBuf::from("/backups"), Report::Size).sensor();
+
+        assert_eq!(sensor.state.unique_id, "file_backup_size");
+        assert_eq!(sensor.unit_of_measurement.as_deref(), Some("B"));
+        assert_eq!(sensor.device_class.as_deref(), Some("data_size"));
+    }
+
+    #[tokio::test]
+    async fn test_start() {
+        let dir = tempdir().unwrap();
+        let flag = dir.path().join("ready");
+        let (sensor_tx, mut sensor_rx) = mpsc::unbounded_channel();
+        tokio::spawn(start(sensor_tx, vec![config("Ready", flag.clone(), Report::Exists)]));
+
+        let states = timeout(Duration::from_secs(5), sensor_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(states[0].value, json!(false));
+
+        fs::write(&flag, "").unwrap();
+        let states = timeout(Duration::from_secs(5), sensor_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(states[0].value, json!(true));
+    }
+}
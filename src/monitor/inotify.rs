@@ -0,0 +1,110 @@
+// inotify -- https://man7.org/linux/man-pages/man7/inotify.7.html
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+
+use anyhow::{anyhow, Error};
+use futures::StreamExt;
+use inotify::{EventStream, Inotify, WatchDescriptor, WatchMask};
+
+/// Watches files and directories for changes. Files are watched through their directory, so
+/// they're still followed after being replaced or deleted and created again.
+pub struct PathWatcher {
+    inotify: Inotify,
+    events: EventStream<[u8; 1024]>,
+    /// per watched path, its watch and for files the name to look for in the directory's events
+    watches: Vec<(WatchDescriptor, Option<OsString>)>,
+}
+
+impl PathWatcher {
+    pub fn new() -> Result<Self, Error> {
+        let mut inotify = Inotify::init()?;
+        let events = inotify.event_stream([0; 1024])?;
+        Ok(Self {
+            inotify,
+            events,
+            watches: vec![],
+        })
+    }
+
+    /// Starts watching `path`, returning the index [`PathWatcher::changed`] reports it by.
+    pub fn watch(&mut self, path: &Path) -> Result<usize, Error> {
+        let mask = WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MODIFY
+            | WatchMask::CLOSE_WRITE
+            | WatchMask::ATTRIB
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO;
+        let (dir, name) = if path.is_dir() {
+            (path, None)
+        } else {
+            let name = path
+                .file_name()
+                .ok_or_else(|| anyhow!("{} is not a file", path.display()))?;
+            let dir = path
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            (dir, Some(name.to_os_string()))
+        };
+        let watch = self.inotify.add_watch(dir, mask)?;
+        self.watches.push((watch, name));
+        Ok(self.watches.len() - 1)
+    }
+
+    /// Waits for changes, returning the indices of the paths that changed.
+    pub async fn changed(&mut self) -> io::Result<Vec<usize>> {
+        while let Some(event) = self.events.next().await {
+            let event = event?;
+            let changed: Vec<usize> = self
+                .watches
+                .iter()
+                .enumerate()
+                .filter(|(_, (watch, name))| event.wd == *watch && (name.is_none() || event.name == *name))
+                .map(|(index, _)| index)
+                .collect();
+            if !changed.is_empty() {
+                return Ok(changed);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::UnexpectedEof, "inotify stopped"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+    use tempfile::tempdir;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_changed() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("status");
+        let other = dir.path().join("other");
+        let mut watcher = PathWatcher::new().unwrap();
+        let file_index = watcher.watch(&file).unwrap();
+        let dir_index = watcher.watch(dir.path()).unwrap();
+
+        fs::write(&other, "unrelated").unwrap();
+        let changed = timeout(Duration::from_secs(5), watcher.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(changed, vec![dir_index]);
+
+        // replaced rather than written in place, like most editors do
+        fs::write(dir.path().join("status.tmp"), "done").unwrap();
+        fs::rename(dir.path().join("status.tmp"), &file).unwrap();
+        let mut changed = vec![];
+        while !changed.contains(&file_index) {
+            changed = timeout(Duration::from_secs(5), watcher.changed())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+    }
+}
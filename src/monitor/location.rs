@@ -0,0 +1,481 @@
+// update_location -- https://developers.home-assistant.io/docs/api/native-app-integration/sending-data#update-device-location
+// GeoClue2 -- https://www.freedesktop.org/software/geoclue/docs/
+use std::net::IpAddr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::select;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::{interval, sleep};
+use zbus::proxy::CacheProperties;
+use zbus::zvariant::OwnedObjectPath;
+use zbus::Connection;
+
+use crate::agent_state::Zone;
+use crate::monitor::network::{self, NetworkReader};
+
+// the same settling time as the network monitor, addresses arrive a moment after the link
+const SETTLE: Duration = Duration::from_secs(1);
+
+/// Where the device is, see the `update_location` webhook. Either a zone name or coordinates.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct Location {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gps: Option<[f64; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gps_accuracy: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub altitude: Option<f64>,
+}
+
+pub type LocationSender = UnboundedSender<Location>;
+
+/// A Home Assistant zone, recognised by the Wi-Fi network or the subnet the device is on.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ZoneRule {
+    /// the zone name as Home Assistant knows it, `home` for the home zone
+    pub name: String,
+    pub ssids: Vec<String>,
+    /// e.g. `192.168.1.0/24` or `2001:db8::/64`
+    pub subnets: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocationConfig {
+    /// off unless asked for, not everybody wants their laptop tracked
+    pub enabled: bool,
+    /// ask GeoClue for coordinates when no zone matches
+    pub geoclue: bool,
+    /// send coordinates outside of Home Assistant's zones, otherwise only zone names leave the machine
+    pub send_coordinates: bool,
+    /// seconds between checks, network changes are picked up right away
+    pub interval: u64,
+    pub zones: Vec<ZoneRule>,
+}
+
+impl Default for LocationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            geoclue: false,
+            send_coordinates: true,
+            interval: 60,
+            zones: vec![],
+        }
+    }
+}
+
+impl ZoneRule {
+    pub fn validate(&self) -> Result<(), Error> {
+        for subnet in &self.subnets {
+            parse_subnet(subnet).map_err(|e| anyhow!("Invalid subnet of zone {}: {}", self.name, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// A subnet written as `address/prefix length`, split into the two.
+fn parse_subnet(subnet: &str) -> Result<(IpAddr, u32), Error> {
+    let (network, prefix) = subnet
+        .split_once('/')
+        .ok_or_else(|| anyhow!("{} is missing a prefix length", subnet))?;
+    let network: IpAddr = network.parse()?;
+    let prefix: u32 = prefix.parse()?;
+    let bits = if network.is_ipv4() { 32 } else { 128 };
+    if prefix > bits {
+        return Err(anyhow!("{} has a prefix longer than its address", subnet));
+    }
+    Ok((network, prefix))
+}
+
+/// Whether `address` is within `subnet`, written as `address/prefix length`.
+pub fn in_subnet(address: &IpAddr, subnet: &str) -> Result<bool, Error> {
+    let (network, prefix) = parse_subnet(subnet)?;
+    let (address, network, bits) = match (address, network) {
+        (IpAddr::V4(address), IpAddr::V4(network)) => (u32::from(*address) as u128, u32::from(network) as u128, 32),
+        (IpAddr::V6(address), IpAddr::V6(network)) => (u128::from(*address), u128::from(network), 128),
+        _ => return Ok(false),
+    };
+    let mask = if prefix == 0 {
+        0
+    } else {
+        u128::MAX << (bits - prefix) & (u128::MAX >> (128 - bits))
+    };
+    Ok(address & mask == network & mask)
+}
+
+/// The first zone matching the SSID or one of the addresses. The subnets are checked when the
+/// config is loaded, see `ZoneRule::validate`.
+pub fn match_zone<'a>(zones: &'a [ZoneRule], ssid: Option<&str>, addresses: &[IpAddr]) -> Option<&'a ZoneRule> {
+    zones.iter().find(|zone| {
+        let ssid_matches = ssid.is_some_and(|ssid| zone.ssids.iter().any(|known| known == ssid));
+        let subnet_matches = zone.subnets.iter().any(|subnet| {
+            addresses
+                .iter()
+                .any(|address| in_subnet(address, subnet).unwrap_or(false))
+        });
+        ssid_matches || subnet_matches
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Coordinates {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// meters
+    pub accuracy: f64,
+    pub altitude: Option<f64>,
+}
+
+const EARTH_RADIUS: f64 = 6_371_000.0;
+
+/// The great-circle distance in meters.
+pub fn distance(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (latitude_from, latitude_to) = (from.0.to_radians(), to.0.to_radians());
+    let latitude_delta = latitude_to - latitude_from;
+    let longitude_delta = (to.1 - from.1).to_radians();
+    let a = (latitude_delta / 2.0).sin().powi(2)
+        + latitude_from.cos() * latitude_to.cos() * (longitude_delta / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+/// The Home Assistant zone the coordinates are in, the way HA picks it: the accuracy counts in the
+/// device's favour, the closest zone wins and passive zones never do.
+pub fn zone_at<'a>(zones: &'a [Zone], coordinates: &Coordinates) -> Option<&'a Zone> {
+    let mut closest: Option<(&Zone, f64)> = None;
+    for zone in zones.iter().filter(|zone| !zone.attributes.passive) {
+        let zone_distance = distance(
+            (coordinates.latitude, coordinates.longitude),
+            (zone.attributes.latitude, zone.attributes.longitude),
+        );
+        if zone_distance - coordinates.accuracy >= zone.attributes.radius {
+            continue;
+        }
+        let closer = match closest {
+            None => true,
+            Some((current, current_distance)) => {
+                zone_distance < current_distance
+                    || (zone_distance == current_distance && zone.attributes.radius < current.attributes.radius)
+            }
+        };
+        if closer {
+            closest = Some((zone, zone_distance));
+        }
+    }
+    closest.map(|(zone, _)| zone)
+}
+
+fn named(name: String) -> Location {
+    Location {
+        location_name: Some(name),
+        ..Default::default()
+    }
+}
+
+/// A matching configured zone wins, then coordinates, and without either the device isn't home.
+/// Coordinates within one of Home Assistant's `zones` are sent as that zone's name.
+pub fn locate(
+    rule: Option<&ZoneRule>,
+    coordinates: Option<Coordinates>,
+    zones: &[Zone],
+    send_coordinates: bool,
+) -> Location {
+    if let Some(rule) = rule {
+        return named(rule.name.clone());
+    }
+    let Some(coordinates) = coordinates else {
+        return named("not_home".to_string());
+    };
+    if let Some(zone) = zone_at(zones, &coordinates) {
+        return named(zone.name());
+    }
+    if !send_coordinates {
+        return named("not_home".to_string());
+    }
+    Location {
+        gps: Some([coordinates.latitude, coordinates.longitude]),
+        gps_accuracy: Some(coordinates.accuracy.round() as u32),
+        altitude: coordinates.altitude,
+        ..Default::default()
+    }
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.GeoClue2.Manager",
+    default_service = "org.freedesktop.GeoClue2",
+    default_path = "/org/freedesktop/GeoClue2/Manager"
+)]
+trait GeoClueManager {
+    fn get_client(&self) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.GeoClue2.Client",
+    default_service = "org.freedesktop.GeoClue2"
+)]
+trait GeoClueClient {
+    fn start(&self) -> zbus::Result<()>;
+    #[zbus(property)]
+    fn set_desktop_id(&self, id: &str) -> zbus::Result<()>;
+    #[zbus(property)]
+    fn set_requested_accuracy_level(&self, level: u32) -> zbus::Result<()>;
+    #[zbus(signal)]
+    fn location_updated(&self, old: OwnedObjectPath, new: OwnedObjectPath) -> zbus::Result<()>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.GeoClue2.Location",
+    default_service = "org.freedesktop.GeoClue2"
+)]
+trait GeoClueLocation {
+    #[zbus(property)]
+    fn latitude(&self) -> zbus::Result<f64>;
+    #[zbus(property)]
+    fn longitude(&self) -> zbus::Result<f64>;
+    #[zbus(property)]
+    fn accuracy(&self) -> zbus::Result<f64>;
+    #[zbus(property)]
+    fn altitude(&self) -> zbus::Result<f64>;
+}
+
+// GClueAccuracyLevel
+const ACCURACY_EXACT: u32 = 8;
+
+async fn read_coordinates(connection: &Connection, path: OwnedObjectPath) -> zbus::Result<Coordinates> {
+    let location = GeoClueLocationProxy::builder(connection)
+        .path(path)?
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?;
+    let altitude = location.altitude().await?;
+    Ok(Coordinates {
+        latitude: location.latitude().await?,
+        longitude: location.longitude().await?,
+        accuracy: location.accuracy().await?,
+        // GeoClue uses -G_MAXDOUBLE for an unknown altitude
+        altitude: (altitude > f64::MIN).then_some(altitude),
+    })
+}
+
+async fn watch_geoclue(coordinates_tx: watch::Sender<Option<Coordinates>>) -> Result<(), Error> {
+    let connection = Connection::system().await?;
+    let client_path = GeoClueManagerProxy::new(&connection).await?.get_client().await?;
+    let client = GeoClueClientProxy::builder(&connection)
+        .path(client_path)?
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?;
+    client.set_desktop_id(env!("CARGO_PKG_NAME")).await?;
+    client.set_requested_accuracy_level(ACCURACY_EXACT).await?;
+    let mut updates = client.receive_location_updated().await?;
+    client.start().await?;
+
+    while let Some(update) = updates.next().await {
+        let coordinates = read_coordinates(&connection, update.args()?.new).await?;
+        coordinates_tx.send(Some(coordinates))?;
+    }
+    Ok(())
+}
+
+pub async fn start(location_tx: LocationSender, config: LocationConfig, mut zones_rx: watch::Receiver<Vec<Zone>>) {
+    if !config.enabled {
+        return;
+    }
+    let (coordinates_tx, mut coordinates_rx) = watch::channel(None);
+    // the watchers stop when start is dropped
+    let mut watchers = JoinSet::new();
+    if config.geoclue {
+        watchers.spawn(async move {
+            if let Err(e) = watch_geoclue(coordinates_tx).await {
+                println!("Stopped watching GeoClue: {}", e);
+            }
+        });
+    }
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+    watchers.spawn(async move {
+        if let Err(e) = network::watch_netlink(event_tx).await {
+            println!("Stopped watching netlink for location changes: {}", e);
+        }
+    });
+
+    let reader = NetworkReader::new("/");
+    let mut ticks = interval(Duration::from_secs(config.interval.max(1)));
+    let mut last_location = None;
+    loop {
+        select! {
+            _ = ticks.tick() => {},
+            Some(_) = event_rx.recv() => {
+                sleep(SETTLE).await;
+                while event_rx.try_recv().is_ok() {}
+            },
+            Ok(_) = coordinates_rx.changed() => {},
+            Ok(_) = zones_rx.changed() => {},
+        }
+        let wifi = reader.wifi().await;
+        let addresses: Vec<IpAddr> = network::addresses().into_values().flatten().collect();
+        let zone = match_zone(
+            &config.zones,
+            wifi.as_ref().and_then(|wifi| wifi.ssid.as_deref()),
+            &addresses,
+        );
+        let location = locate(
+            zone,
+            *coordinates_rx.borrow(),
+            &zones_rx.borrow(),
+            config.send_coordinates,
+        );
+        if last_location.as_ref() != Some(&location) {
+            location_tx.send(location.clone()).expect("Unable to send");
+            last_location = Some(location);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn zones() -> Vec<ZoneRule> {
+        vec![
+            ZoneRule {
+                name: "home".to_string(),
+                ssids: vec!["Home Network".to_string()],
+                subnets: vec!["192.168.1.0/24".to_string()],
+            },
+            ZoneRule {
+                name: "Office".to_string(),
+                ssids: vec![],
+                subnets: vec!["10.20.0.0/16".to_string(), "2001:db8:20::/48".to_string()],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_in_subnet() {
+        let address: IpAddr = "192.168.1.42".parse().unwrap();
+
+        assert!(in_subnet(&address, "192.168.1.0/24").unwrap());
+        assert!(!in_subnet(&address, "192.168.2.0/24").unwrap());
+        assert!(in_subnet(&address, "0.0.0.0/0").unwrap());
+        assert!(!in_subnet(&address, "2001:db8::/32").unwrap());
+        assert!(in_subnet(&"2001:db8:20:1::5".parse().unwrap(), "2001:db8:20::/48").unwrap());
+        assert!(in_subnet(&address, "192.168.1.0").is_err());
+        assert!(in_subnet(&address, "192.168.1.0/33").is_err());
+    }
+
+    #[test]
+    fn test_validate_zone() {
+        let zone = |subnet: &str| ZoneRule {
+            name: "home".to_string(),
+            subnets: vec![subnet.to_string()],
+            ..Default::default()
+        };
+
+        assert!(zone("192.168.1.0/24").validate().is_ok());
+        assert!(zone("2001:db8::/64").validate().is_ok());
+        assert!(zone("192.168.1.0/64").validate().is_err());
+        assert!(zone("home.lan/24").validate().is_err());
+    }
+
+    #[test]
+    fn test_match_zone() {
+        let zones = zones();
+
+        let by_ssid = match_zone(&zones, Some("Home Network"), &[]);
+        let by_subnet = match_zone(&zones, Some("Guest"), &["10.20.3.4".parse().unwrap()]);
+        let by_ipv6 = match_zone(&zones, None, &["2001:db8:20::7".parse().unwrap()]);
+
+        assert_eq!(by_ssid.map(|zone| zone.name.as_str()), Some("home"));
+        assert_eq!(by_subnet.map(|zone| zone.name.as_str()), Some("Office"));
+        assert_eq!(by_ipv6.map(|zone| zone.name.as_str()), Some("Office"));
+        assert_eq!(match_zone(&zones, Some("Cafe"), &["172.16.0.9".parse().unwrap()]), None);
+    }
+
+    fn ha_zones() -> Vec<Zone> {
+        serde_json::from_value(json!([
+            {"entity_id": "zone.home", "attributes": {"latitude": 59.3300, "longitude": 18.0600, "radius": 100}},
+            {"entity_id": "zone.city", "attributes": {"latitude": 59.3300, "longitude": 18.0600, "radius": 5000, "friendly_name": "City"}},
+            {"entity_id": "zone.gym", "attributes": {"latitude": 59.3400, "longitude": 18.0600, "radius": 50, "friendly_name": "Gym", "passive": true}}
+        ]))
+        .unwrap()
+    }
+
+    fn coordinates(latitude: f64, accuracy: f64) -> Coordinates {
+        Coordinates {
+            latitude,
+            longitude: 18.06,
+            accuracy,
+            altitude: None,
+        }
+    }
+
+    #[test]
+    fn test_distance() {
+        // a thousandth of a degree of latitude is about 111 meters
+        let meters = distance((59.33, 18.06), (59.331, 18.06));
+
+        assert!((meters - 111.2).abs() < 0.5, "{}", meters);
+    }
+
+    #[test]
+    fn test_zone_at() {
+        let zones = ha_zones();
+
+        assert_eq!(
+            zone_at(&zones, &coordinates(59.3301, 10.0)).map(Zone::name).as_deref(),
+            Some("home")
+        );
+        // 222 meters out, but with 150 meters of accuracy
+        assert_eq!(
+            zone_at(&zones, &coordinates(59.3320, 150.0)).map(Zone::name).as_deref(),
+            Some("home")
+        );
+        assert_eq!(
+            zone_at(&zones, &coordinates(59.3320, 10.0)).map(Zone::name).as_deref(),
+            Some("City")
+        );
+        // passive zones only exist for automations
+        assert_eq!(
+            zone_at(&zones, &coordinates(59.3400, 10.0)).map(Zone::name).as_deref(),
+            Some("City")
+        );
+        assert_eq!(zone_at(&zones, &coordinates(60.0, 10.0)), None);
+    }
+
+    #[test]
+    fn test_locate() {
+        let rules = zones();
+        let zones = ha_zones();
+        let away = coordinates(60.0, 24.6);
+
+        assert_eq!(
+            json!(locate(rules.first(), Some(away), &zones, true)),
+            json!({"location_name": "home"})
+        );
+        assert_eq!(
+            json!(locate(None, Some(away), &zones, true)),
+            json!({"gps": [60.0, 18.06], "gps_accuracy": 25})
+        );
+        assert_eq!(
+            json!(locate(None, Some(coordinates(59.3320, 10.0)), &zones, false)),
+            json!({"location_name": "City"})
+        );
+        assert_eq!(
+            json!(locate(None, Some(away), &zones, false)),
+            json!({"location_name": "not_home"})
+        );
+        assert_eq!(
+            json!(locate(None, None, &zones, true)),
+            json!({"location_name": "not_home"})
+        );
+    }
+}
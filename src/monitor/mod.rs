@@ -1,2 +1,122 @@
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::agent_state::{Sensor, SensorState, State};
+use crate::config::Monitors;
+
+pub mod active_window;
+pub mod bluetooth;
+pub mod commands;
+pub mod containers;
 pub mod webcam;
+pub mod files;
+pub mod inotify;
+pub mod location;
 pub mod microphone;
+pub mod mpris;
+pub mod network;
+pub mod power;
+pub mod session_state;
+pub mod sessions;
+pub mod system;
+pub mod systemd;
+pub mod updates;
+
+/// Monitors that publish more than a single value send their changed sensor states through this.
+pub type SensorSender = UnboundedSender<Vec<SensorState>>;
+
+/// Monitors that discover sensors while running, e.g. a headset being turned on, register them
+/// through this before sending their states.
+pub type RegistrationSender = UnboundedSender<Vec<Sensor>>;
+
+/// Sends the states that differ from the last sent ones, and remembers them for the next call.
+pub fn send_changes(sensor_tx: &SensorSender, last_states: &mut Vec<SensorState>, states: Vec<SensorState>) {
+    let changed: Vec<SensorState> = states
+        .iter()
+        .filter(|state| !last_states.contains(state))
+        .cloned()
+        .collect();
+    if !changed.is_empty() {
+        sensor_tx.send(changed).expect("Unable to send");
+        *last_states = states;
+    }
+}
+
+/// The sensors of a monitor, and whether it's turned on in the config file.
+pub struct MonitorSensors {
+    pub name: &'static str,
+    pub enabled: bool,
+    pub sensors: Vec<Sensor>,
+}
+
+/// Every monitor with the sensors it registers. Batteries are only known once they're read, so
+/// the power monitor has none while it's disabled.
+pub async fn monitor_sensors(monitors: &Monitors) -> Vec<MonitorSensors> {
+    let builtin = |unique_id: &str| State::new().get_sensor_by_unique_id(unique_id).into_iter().collect();
+    let power = if monitors.power.enabled {
+        match power::read_status(&monitors.power).await {
+            Ok(status) => power::sensors(&status),
+            Err(e) => {
+                println!("Failed to read power supplies: {}", e);
+                vec![]
+            }
+        }
+    } else {
+        vec![]
+    };
+    let monitor = |name, enabled, sensors| MonitorSensors { name, enabled, sensors };
+    vec![
+        monitor("webcam", true, builtin("webcam")),
+        monitor("microphone", true, builtin("microphone")),
+        monitor("mpris", true, mpris::sensors()),
+        monitor("session_state", true, session_state::sensors()),
+        monitor("active_window", true, active_window::sensors()),
+        monitor("system", monitors.system.enabled, system::SystemReader::new("/").sensors()),
+        monitor("power", monitors.power.enabled, power),
+        monitor("network", monitors.network.enabled, network::sensors()),
+        monitor("bluetooth", monitors.bluetooth.enabled, bluetooth::sensors()),
+        monitor("sessions", monitors.sessions.enabled, sessions::sensors()),
+        monitor("commands", !monitors.commands.is_empty(), commands::sensors(&monitors.commands)),
+        monitor("files", !monitors.files.is_empty(), files::sensors(&monitors.files)),
+        monitor("systemd", monitors.systemd.enabled, systemd::sensors(&monitors.systemd)),
+        monitor("containers", monitors.containers.enabled, containers::sensors(&monitors.containers)),
+        monitor("updates", monitors.updates.enabled, updates::sensors()),
+        // not a sensor, but a device_tracker that exists once the device is registered
+        monitor("location", monitors.location.enabled, vec![]),
+    ]
+}
+
+/// The sensors of the monitors that are turned on, to register at startup.
+pub async fn enabled_sensors(monitors: &Monitors) -> Vec<Sensor> {
+    monitor_sensors(monitors)
+        .await
+        .into_iter()
+        .filter(|monitor| monitor.enabled)
+        .flat_map(|monitor| monitor.sensors)
+        .collect()
+}
+
+#[cfg(test)]
+pub mod testing {
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+
+    pub struct Bus(Child);
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+        }
+    }
+
+    // Starts a private session bus, or returns None if dbus-daemon isn't installed
+    pub fn start_bus() -> Option<(Bus, String)> {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .ok()?;
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take()?).read_line(&mut address).ok()?;
+        Some((Bus(daemon), address.trim().to_string()))
+    }
+}
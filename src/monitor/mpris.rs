@@ -0,0 +1,412 @@
+// MPRIS -- https://specifications.freedesktop.org/mpris-spec/latest/
+use std::collections::HashMap;
+
+use futures::StreamExt;
+use serde_json::{json, Value};
+use tokio::select;
+use tokio::sync::broadcast::Receiver;
+use zbus::fdo::DBusProxy;
+use zbus::message::Type;
+use zbus::proxy::CacheProperties;
+use zbus::zvariant::{self, OwnedValue};
+use zbus::{Connection, MatchRule, MessageStream};
+
+use crate::agent_state::{Sensor, SensorState};
+use crate::connection::Command;
+use crate::monitor::{send_changes, SensorSender};
+
+const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+
+#[zbus::proxy(interface = "org.mpris.MediaPlayer2", default_path = "/org/mpris/MediaPlayer2")]
+trait MediaPlayer2 {
+    #[zbus(property)]
+    fn identity(&self) -> zbus::Result<String>;
+}
+
+#[zbus::proxy(interface = "org.mpris.MediaPlayer2.Player", default_path = "/org/mpris/MediaPlayer2")]
+trait Player {
+    fn play(&self) -> zbus::Result<()>;
+    fn pause(&self) -> zbus::Result<()>;
+    fn play_pause(&self) -> zbus::Result<()>;
+    fn stop(&self) -> zbus::Result<()>;
+    fn next(&self) -> zbus::Result<()>;
+    fn previous(&self) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn playback_status(&self) -> zbus::Result<String>;
+    #[zbus(property)]
+    fn metadata(&self) -> zbus::Result<HashMap<String, OwnedValue>>;
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Playback {
+    pub bus_name: String,
+    pub player: String,
+    pub status: String,
+    pub title: Option<String>,
+    pub artists: Vec<String>,
+    pub album: Option<String>,
+    pub art_url: Option<String>,
+    pub length_us: Option<i64>,
+}
+
+fn media_sensor(unique_id: &str, name: &str, icon: &str) -> Sensor {
+    Sensor {
+        name: name.to_string(),
+        state: SensorState {
+            unique_id: unique_id.to_string(),
+            sensor_type: "sensor".to_string(),
+            icon: icon.to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+pub fn sensors() -> Vec<Sensor> {
+    vec![
+        media_sensor("media_title", "Media Title", "mdi:music"),
+        media_sensor("media_artist", "Media Artist", "mdi:account-music"),
+        media_sensor("media_album", "Media Album", "mdi:album"),
+        media_sensor("media_playback_status", "Media Playback Status", "mdi:play-pause"),
+        media_sensor("media_player", "Media Player", "mdi:speaker"),
+    ]
+}
+
+pub fn sensor_states(playback: Option<&Playback>) -> Vec<SensorState> {
+    let optional = |value: Option<&String>| value.map_or(Value::Null, |v| json!(v));
+    let (title, artist, album, status, player) = match playback {
+        Some(playback) => (
+            optional(playback.title.as_ref()),
+            if playback.artists.is_empty() {
+                Value::Null
+            } else {
+                json!(playback.artists.join(", "))
+            },
+            optional(playback.album.as_ref()),
+            json!(playback.status),
+            json!(playback.player),
+        ),
+        None => (Value::Null, Value::Null, Value::Null, json!("Stopped"), Value::Null),
+    };
+
+    let mut states: Vec<SensorState> = sensors().into_iter().map(|sensor| sensor.state).collect();
+    for (state, value) in states.iter_mut().zip([title, artist, album, status, player]) {
+        state.value = value;
+    }
+    if let Some(playback) = playback {
+        let attributes = &mut states[0].attributes;
+        attributes.insert("artists".to_string(), json!(playback.artists));
+        attributes.insert("album".to_string(), json!(playback.album));
+        attributes.insert("art_url".to_string(), json!(playback.art_url));
+        attributes.insert(
+            "duration".to_string(),
+            json!(playback.length_us.map(|length| length / 1_000_000)),
+        );
+        attributes.insert("bus_name".to_string(), json!(playback.bus_name));
+        states[4].attributes.insert("bus_name".to_string(), json!(playback.bus_name));
+    }
+    states
+}
+
+fn metadata_string(metadata: &HashMap<String, OwnedValue>, key: &str) -> Option<String> {
+    match metadata.get(key).map(|value| &**value) {
+        Some(zvariant::Value::Str(s)) => Some(s.to_string()),
+        Some(zvariant::Value::ObjectPath(p)) => Some(p.to_string()),
+        _ => None,
+    }
+}
+
+fn metadata_strings(metadata: &HashMap<String, OwnedValue>, key: &str) -> Vec<String> {
+    match metadata.get(key).map(|value| &**value) {
+        Some(zvariant::Value::Array(array)) => array
+            .iter()
+            .filter_map(|value| match value {
+                zvariant::Value::Str(s) => Some(s.to_string()),
+                _ => None,
+            })
+            .collect(),
+        Some(zvariant::Value::Str(s)) => vec![s.to_string()],
+        _ => vec![],
+    }
+}
+
+fn metadata_i64(metadata: &HashMap<String, OwnedValue>, key: &str) -> Option<i64> {
+    match metadata.get(key).map(|value| &**value) {
+        Some(zvariant::Value::I64(n)) => Some(*n),
+        Some(zvariant::Value::U64(n)) => i64::try_from(*n).ok(),
+        Some(zvariant::Value::I32(n)) => Some(*n as i64),
+        Some(zvariant::Value::U32(n)) => Some(*n as i64),
+        _ => None,
+    }
+}
+
+async fn player_names(connection: &Connection) -> zbus::Result<Vec<String>> {
+    let names = DBusProxy::new(connection).await?.list_names().await?;
+    let mut players: Vec<String> = names
+        .into_iter()
+        .map(|name| name.to_string())
+        .filter(|name| name.starts_with(MPRIS_PREFIX))
+        .collect();
+    players.sort();
+    Ok(players)
+}
+
+async fn player_proxy<'a>(connection: &Connection, bus_name: &'a str) -> zbus::Result<PlayerProxy<'a>> {
+    PlayerProxy::builder(connection)
+        .destination(bus_name)?
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await
+}
+
+async fn read_player(connection: &Connection, bus_name: &str) -> zbus::Result<Playback> {
+    let player = player_proxy(connection, bus_name).await?;
+    let status = player.playback_status().await?;
+    let metadata = player.metadata().await.unwrap_or_default();
+    let identity = MediaPlayer2Proxy::builder(connection)
+        .destination(bus_name)?
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?
+        .identity()
+        .await
+        .unwrap_or_else(|_| bus_name.trim_start_matches(MPRIS_PREFIX).to_string());
+
+    Ok(Playback {
+        bus_name: bus_name.to_string(),
+        player: identity,
+        status,
+        title: metadata_string(&metadata, "xesam:title"),
+        artists: metadata_strings(&metadata, "xesam:artist"),
+        album: metadata_string(&metadata, "xesam:album"),
+        art_url: metadata_string(&metadata, "mpris:artUrl"),
+        length_us: metadata_i64(&metadata, "mpris:length"),
+    })
+}
+
+/// Reads all players on the bus and returns the most relevant one: the first playing player, then
+/// the first paused one, then whatever is left.
+pub async fn read_playback(connection: &Connection) -> zbus::Result<Option<Playback>> {
+    let mut players = vec![];
+    for bus_name in player_names(connection).await? {
+        // players may vanish between listing and reading them
+        if let Ok(playback) = read_player(connection, &bus_name).await {
+            players.push(playback);
+        }
+    }
+    let rank = |status: &str| match status {
+        "Playing" => 0,
+        "Paused" => 1,
+        _ => 2,
+    };
+    players.sort_by_key(|playback| rank(&playback.status));
+    Ok(players.into_iter().next())
+}
+
+/// Handles a `command_media` notification, e.g.
+/// `{"message": "command_media", "data": {"media_command": "play_pause", "media_player": "spotify"}}`.
+/// Without `media_player` the command goes to the currently relevant player.
+pub async fn handle_command(connection: &Connection, command: &Command) -> zbus::Result<()> {
+    if command.message != "command_media" {
+        return Ok(());
+    }
+    let target = match command.data["media_player"].as_str() {
+        Some(wanted) => {
+            let wanted = wanted.to_lowercase();
+            let mut target = None;
+            for bus_name in player_names(connection).await? {
+                if let Ok(playback) = read_player(connection, &bus_name).await {
+                    if playback.player.to_lowercase() == wanted
+                        || bus_name.trim_start_matches(MPRIS_PREFIX).to_lowercase() == wanted
+                    {
+                        target = Some(bus_name);
+                        break;
+                    }
+                }
+            }
+            target
+        }
+        None => read_playback(connection).await?.map(|playback| playback.bus_name),
+    };
+    let Some(bus_name) = target else {
+        println!("No media player to send {} to", command.data);
+        return Ok(());
+    };
+
+    let player = player_proxy(connection, &bus_name).await?;
+    match command.data["media_command"].as_str().unwrap_or_default() {
+        "play" => player.play().await,
+        "pause" => player.pause().await,
+        "play_pause" => player.play_pause().await,
+        "stop" => player.stop().await,
+        "next" => player.next().await,
+        "previous" => player.previous().await,
+        other => {
+            println!("Unknown media command {:?}", other);
+            Ok(())
+        }
+    }
+}
+
+async fn refresh(connection: &Connection, sensor_tx: &SensorSender, last_states: &mut Vec<SensorState>) {
+    let playback = match read_playback(connection).await {
+        Ok(playback) => playback,
+        Err(e) => {
+            println!("Failed to read media players: {}", e);
+            return;
+        }
+    };
+    send_changes(sensor_tx, last_states, sensor_states(playback.as_ref()));
+}
+
+async fn signal_stream(connection: &Connection) -> zbus::Result<futures::stream::SelectAll<MessageStream>> {
+    let properties_changed = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .path(MPRIS_PATH)?
+        .build();
+    let owner_changed = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .sender("org.freedesktop.DBus")?
+        .interface("org.freedesktop.DBus")?
+        .member("NameOwnerChanged")?
+        .arg0ns("org.mpris.MediaPlayer2")?
+        .build();
+    Ok(futures::stream::select_all([
+        MessageStream::for_match_rule(properties_changed, connection, None).await?,
+        MessageStream::for_match_rule(owner_changed, connection, None).await?,
+    ]))
+}
+
+pub async fn start(sensor_tx: SensorSender, mut commands: Receiver<Command>) {
+    let connection = match Connection::session().await {
+        Ok(connection) => connection,
+        Err(e) => {
+            println!("No session bus, not monitoring media players: {}", e);
+            return;
+        }
+    };
+    let mut signals = match signal_stream(&connection).await {
+        Ok(signals) => signals,
+        Err(e) => {
+            println!("Failed to subscribe to MPRIS signals, not monitoring media players: {}", e);
+            return;
+        }
+    };
+    let mut last_states = vec![];
+
+    refresh(&connection, &sensor_tx, &mut last_states).await;
+    loop {
+        select! {
+            Some(_) = signals.next() => {
+                refresh(&connection, &sensor_tx, &mut last_states).await;
+            },
+            Ok(command) = commands.recv() => {
+                if let Err(e) = handle_command(&connection, &command).await {
+                    println!("Failed to handle media command: {}", e);
+                }
+            },
+            else => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::testing::start_bus;
+    use std::sync::{Arc, Mutex};
+
+    struct FakeIdentity;
+
+    #[zbus::interface(name = "org.mpris.MediaPlayer2")]
+    impl FakeIdentity {
+        #[zbus(property)]
+        fn identity(&self) -> String {
+            "Fake Player".to_string()
+        }
+    }
+
+    struct FakePlayer {
+        status: Arc<Mutex<String>>,
+    }
+
+    #[zbus::interface(name = "org.mpris.MediaPlayer2.Player")]
+    impl FakePlayer {
+        fn play_pause(&self) {
+            let mut status = self.status.lock().unwrap();
+            *status = if *status == "Playing" { "Paused" } else { "Playing" }.to_string();
+        }
+
+        #[zbus(property)]
+        fn playback_status(&self) -> String {
+            self.status.lock().unwrap().clone()
+        }
+
+        #[zbus(property)]
+        fn metadata(&self) -> HashMap<String, OwnedValue> {
+            HashMap::from([
+                ("xesam:title".to_string(), OwnedValue::from(zvariant::Str::from("Song"))),
+                (
+                    "xesam:artist".to_string(),
+                    OwnedValue::try_from(zvariant::Value::from(vec!["Artist A", "Artist B"])).unwrap(),
+                ),
+                ("mpris:length".to_string(), OwnedValue::from(180_000_000i64)),
+            ])
+        }
+    }
+
+    #[test]
+    fn test_sensor_states_without_player() {
+        let states = sensor_states(None);
+
+        assert_eq!(states.len(), sensors().len());
+        assert_eq!(states[0].value, Value::Null);
+        assert_eq!(states[3].value, json!("Stopped"));
+    }
+
+    #[tokio::test]
+    async fn test_read_playback_and_command() {
+        let Some((_bus, address)) = start_bus() else {
+            return;
+        };
+        let status = Arc::new(Mutex::new("Paused".to_string()));
+        let _player = zbus::connection::Builder::address(address.as_str())
+            .unwrap()
+            .name("org.mpris.MediaPlayer2.fake")
+            .unwrap()
+            .serve_at(MPRIS_PATH, FakeIdentity)
+            .unwrap()
+            .serve_at(MPRIS_PATH, FakePlayer { status: status.clone() })
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let connection = zbus::connection::Builder::address(address.as_str())
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        let playback = read_playback(&connection).await.unwrap().unwrap();
+        assert_eq!(playback.player, "Fake Player");
+        assert_eq!(playback.status, "Paused");
+        assert_eq!(playback.title.as_deref(), Some("Song"));
+        assert_eq!(playback.artists, vec!["Artist A", "Artist B"]);
+        assert_eq!(playback.length_us, Some(180_000_000));
+
+        let states = sensor_states(Some(&playback));
+        assert_eq!(states[1].value, json!("Artist A, Artist B"));
+        assert_eq!(states[0].attributes["duration"], json!(180));
+
+        let command = Command {
+            message: "command_media".to_string(),
+            data: json!({ "media_command": "play_pause", "media_player": "fake player" }),
+        };
+        handle_command(&connection, &command).await.unwrap();
+        assert_eq!(*status.lock().unwrap(), "Playing");
+    }
+}
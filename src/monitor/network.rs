@@ -0,0 +1,660 @@
+// rtnetlink -- https://man7.org/linux/man-pages/man7/rtnetlink.7.html
+// NetworkManager -- https://networkmanager.dev/docs/api/latest/spec.html
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Error;
+use futures::StreamExt;
+use netlink_sys::protocols::NETLINK_ROUTE;
+use netlink_sys::{AsyncSocket, AsyncSocketExt, SocketAddr, TokioSocket};
+use nix::ifaddrs::getifaddrs;
+use nix::libc;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::process::Command;
+use tokio::select;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::task::JoinSet;
+use tokio::time::{interval, sleep, timeout};
+use zbus::message::Type;
+use zbus::proxy::CacheProperties;
+use zbus::zvariant::OwnedObjectPath;
+use zbus::{Connection, MatchRule, MessageStream};
+
+use crate::agent_state::{Sensor, SensorState};
+use crate::monitor::{send_changes, SensorSender};
+
+// changes come in bursts, e.g. a link going up followed by its addresses and routes
+const SETTLE: Duration = Duration::from_secs(1);
+// `iw` only asks the kernel, anything slower than this is stuck
+const IW_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkBackend {
+    Netlink,
+    NetworkManager,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub enabled: bool,
+    pub backend: NetworkBackend,
+    /// seconds between readings, for the signal strength which changes without any events
+    pub interval: u64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            backend: NetworkBackend::Netlink,
+            interval: 60,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Wifi {
+    pub interface: String,
+    pub ssid: Option<String>,
+    pub bssid: Option<String>,
+    pub signal_dbm: Option<i64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Vpn {
+    pub interface: String,
+    /// wireguard or openvpn
+    pub kind: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NetworkStatus {
+    pub wifi: Option<Wifi>,
+    /// the interface of the default route
+    pub primary_interface: Option<String>,
+    pub addresses: BTreeMap<String, Vec<IpAddr>>,
+    pub vpns: Vec<Vpn>,
+}
+
+/// Parses `iw dev <interface> link`.
+pub fn parse_iw_link(interface: &str, output: &str) -> Option<Wifi> {
+    let bssid = output
+        .lines()
+        .find_map(|line| line.strip_prefix("Connected to "))?
+        .split_whitespace()
+        .next()?;
+    let field = |name: &str| {
+        output
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(name))
+            .map(|value| value.trim().to_string())
+    };
+    Some(Wifi {
+        interface: interface.to_string(),
+        ssid: field("SSID:"),
+        bssid: Some(bssid.to_string()),
+        signal_dbm: field("signal:").and_then(|signal| signal.split_whitespace().next()?.parse().ok()),
+    })
+}
+
+/// Parses the default routes of /proc/net/route and /proc/net/ipv6_route, preferring the lowest metric.
+pub fn parse_default_route(route: &str, ipv6_route: &str) -> Option<String> {
+    let ipv4 = route.lines().skip(1).filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [interface, destination, _, flags, _, _, metric, mask, ..] = fields[..] else {
+            return None;
+        };
+        let up = u16::from_str_radix(flags, 16).ok()? & 1 == 1;
+        let default = up && destination == "00000000" && mask == "00000000";
+        default.then_some((metric.parse::<u32>().ok()?, interface))
+    });
+    let ipv6 = ipv6_route.lines().filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [destination, prefix, _, _, _, metric, _, _, _, interface] = fields[..] else {
+            return None;
+        };
+        let default = destination.chars().all(|c| c == '0') && prefix == "00" && interface != "lo";
+        default.then_some((u32::from_str_radix(metric, 16).ok()?, interface))
+    });
+    ipv4.min()
+        .or_else(|| ipv6.min())
+        .map(|(_, interface)| interface.to_string())
+}
+
+/// Reads `/proc` and `/sys` below `root`, which is `/` except in tests.
+pub struct NetworkReader {
+    root: PathBuf,
+}
+
+impl NetworkReader {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn read(&self, path: &str) -> Option<String> {
+        fs::read_to_string(self.root.join(path)).ok()
+    }
+
+    fn interfaces(&self) -> Vec<String> {
+        let Ok(entries) = fs::read_dir(self.root.join("sys/class/net")) else {
+            return vec![];
+        };
+        let mut interfaces: Vec<String> = entries
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .collect();
+        interfaces.sort();
+        interfaces
+    }
+
+    fn is_up(&self, interface: &str) -> bool {
+        self.read(&format!("sys/class/net/{}/flags", interface))
+            .and_then(|flags| i64::from_str_radix(flags.trim().trim_start_matches("0x"), 16).ok())
+            .is_some_and(|flags| flags & libc::IFF_UP as i64 != 0)
+    }
+
+    pub fn primary_interface(&self) -> Option<String> {
+        parse_default_route(
+            &self.read("proc/net/route").unwrap_or_default(),
+            &self.read("proc/net/ipv6_route").unwrap_or_default(),
+        )
+    }
+
+    pub fn wireless_interfaces(&self) -> Vec<String> {
+        self.interfaces()
+            .into_iter()
+            .filter(|interface| {
+                self.root
+                    .join("sys/class/net")
+                    .join(interface)
+                    .join("wireless")
+                    .exists()
+            })
+            .collect()
+    }
+
+    /// WireGuard interfaces say so in their uevent, OpenVPN uses tun/tap devices.
+    pub fn vpns(&self) -> Vec<Vpn> {
+        self.interfaces()
+            .into_iter()
+            .filter(|interface| self.is_up(interface))
+            .filter_map(|interface| {
+                let dir = format!("sys/class/net/{}", interface);
+                let kind = if self
+                    .read(&format!("{}/uevent", dir))
+                    .is_some_and(|uevent| uevent.lines().any(|line| line == "DEVTYPE=wireguard"))
+                {
+                    "wireguard"
+                } else if self.root.join(&dir).join("tun_flags").exists() {
+                    "openvpn"
+                } else {
+                    return None;
+                };
+                Some(Vpn {
+                    interface,
+                    kind: kind.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    /// Wi-Fi details come from `iw`, the same way the microphone monitor relies on `pactl`.
+    pub async fn wifi(&self) -> Option<Wifi> {
+        for interface in self.wireless_interfaces() {
+            let command = Command::new("iw")
+                .args(["dev", &interface, "link"])
+                .kill_on_drop(true)
+                .output();
+            let Ok(Ok(output)) = timeout(IW_TIMEOUT, command).await else {
+                continue;
+            };
+            if let Some(wifi) = parse_iw_link(&interface, &String::from_utf8_lossy(&output.stdout)) {
+                return Some(wifi);
+            }
+        }
+        None
+    }
+}
+
+/// Addresses of all interfaces except loopback, leaving out IPv6 link-local ones.
+pub fn addresses() -> BTreeMap<String, Vec<IpAddr>> {
+    let mut addresses: BTreeMap<String, Vec<IpAddr>> = BTreeMap::new();
+    let Ok(interfaces) = getifaddrs() else {
+        return addresses;
+    };
+    for interface in interfaces {
+        let Some(address) = interface.address else {
+            continue;
+        };
+        let ip = if let Some(ipv4) = address.as_sockaddr_in() {
+            IpAddr::V4(ipv4.ip())
+        } else if let Some(ipv6) = address.as_sockaddr_in6() {
+            IpAddr::V6(ipv6.ip())
+        } else {
+            continue;
+        };
+        let link_local = matches!(ip, IpAddr::V6(ipv6) if ipv6.segments()[0] & 0xffc0 == 0xfe80);
+        if ip.is_loopback() || link_local {
+            continue;
+        }
+        addresses.entry(interface.interface_name).or_default().push(ip);
+    }
+    addresses
+}
+
+pub async fn read_netlink(reader: &NetworkReader) -> NetworkStatus {
+    NetworkStatus {
+        wifi: reader.wifi().await,
+        primary_interface: reader.primary_interface(),
+        addresses: addresses(),
+        vpns: reader.vpns(),
+    }
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.NetworkManager",
+    default_service = "org.freedesktop.NetworkManager",
+    default_path = "/org/freedesktop/NetworkManager"
+)]
+trait NetworkManager {
+    #[zbus(property)]
+    fn primary_connection(&self) -> zbus::Result<OwnedObjectPath>;
+    #[zbus(property)]
+    fn active_connections(&self) -> zbus::Result<Vec<OwnedObjectPath>>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.NetworkManager.Connection.Active",
+    default_service = "org.freedesktop.NetworkManager"
+)]
+trait ActiveConnection {
+    #[zbus(property, name = "Type")]
+    fn connection_type(&self) -> zbus::Result<String>;
+    #[zbus(property)]
+    fn devices(&self) -> zbus::Result<Vec<OwnedObjectPath>>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.NetworkManager.Device",
+    default_service = "org.freedesktop.NetworkManager"
+)]
+trait Device {
+    #[zbus(property)]
+    fn interface(&self) -> zbus::Result<String>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.NetworkManager.Device.Wireless",
+    default_service = "org.freedesktop.NetworkManager"
+)]
+trait WirelessDevice {
+    #[zbus(property)]
+    fn active_access_point(&self) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.NetworkManager.AccessPoint",
+    default_service = "org.freedesktop.NetworkManager"
+)]
+trait AccessPoint {
+    #[zbus(property)]
+    fn ssid(&self) -> zbus::Result<Vec<u8>>;
+    #[zbus(property)]
+    fn hw_address(&self) -> zbus::Result<String>;
+    #[zbus(property)]
+    fn strength(&self) -> zbus::Result<u8>;
+}
+
+macro_rules! nm_proxy {
+    ($proxy:ident, $connection:expr, $path:expr) => {
+        $proxy::builder($connection)
+            .path($path.clone())?
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?
+    };
+}
+
+async fn nm_interfaces(connection: &Connection, active: &OwnedObjectPath) -> zbus::Result<Vec<String>> {
+    let active = nm_proxy!(ActiveConnectionProxy, connection, active);
+    let mut interfaces = vec![];
+    for device in active.devices().await? {
+        interfaces.push(nm_proxy!(DeviceProxy, connection, device).interface().await?);
+    }
+    Ok(interfaces)
+}
+
+async fn nm_wifi(connection: &Connection, active: &OwnedObjectPath) -> zbus::Result<Option<Wifi>> {
+    let active_proxy = nm_proxy!(ActiveConnectionProxy, connection, active);
+    if active_proxy.connection_type().await? != "802-11-wireless" {
+        return Ok(None);
+    }
+    let Some(device) = active_proxy.devices().await?.into_iter().next() else {
+        return Ok(None);
+    };
+    let interface = nm_proxy!(DeviceProxy, connection, device).interface().await?;
+    let access_point = nm_proxy!(WirelessDeviceProxy, connection, device)
+        .active_access_point()
+        .await?;
+    if access_point.as_str() == "/" {
+        return Ok(None);
+    }
+    let access_point = nm_proxy!(AccessPointProxy, connection, access_point);
+    Ok(Some(Wifi {
+        interface,
+        ssid: Some(String::from_utf8_lossy(&access_point.ssid().await?).to_string()),
+        bssid: Some(access_point.hw_address().await?),
+        // NetworkManager only knows a percentage, this is the inverse of how it calculates that
+        signal_dbm: Some(access_point.strength().await? as i64 / 2 - 100),
+    }))
+}
+
+pub async fn read_network_manager(connection: &Connection) -> zbus::Result<NetworkStatus> {
+    let manager = NetworkManagerProxy::builder(connection)
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?;
+    let primary = manager.primary_connection().await?;
+    let mut status = NetworkStatus {
+        addresses: addresses(),
+        ..Default::default()
+    };
+    if primary.as_str() != "/" {
+        status.primary_interface = nm_interfaces(connection, &primary).await?.into_iter().next();
+    }
+    for active in manager.active_connections().await? {
+        let kind = nm_proxy!(ActiveConnectionProxy, connection, active)
+            .connection_type()
+            .await?;
+        match kind.as_str() {
+            "802-11-wireless" if status.wifi.is_none() => status.wifi = nm_wifi(connection, &active).await?,
+            "wireguard" | "vpn" => {
+                for interface in nm_interfaces(connection, &active).await? {
+                    let kind = if kind == "vpn" { "openvpn" } else { "wireguard" };
+                    status.vpns.push(Vpn {
+                        interface,
+                        kind: kind.to_string(),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(status)
+}
+
+fn sensor(unique_id: &str, name: &str, icon: &str) -> Sensor {
+    Sensor {
+        name: name.to_string(),
+        state: SensorState {
+            unique_id: unique_id.to_string(),
+            sensor_type: "sensor".to_string(),
+            icon: icon.to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+pub fn sensors() -> Vec<Sensor> {
+    vec![
+        sensor("wifi_ssid", "Wi-Fi SSID", "mdi:wifi"),
+        sensor("wifi_bssid", "Wi-Fi BSSID", "mdi:access-point-network"),
+        Sensor {
+            device_class: Some("signal_strength".to_string()),
+            unit_of_measurement: Some("dBm".to_string()),
+            state_class: Some("measurement".to_string()),
+            ..sensor("wifi_signal_strength", "Wi-Fi Signal Strength", "mdi:wifi-strength-2")
+        },
+        sensor("primary_interface", "Primary Interface", "mdi:ethernet"),
+        sensor("local_ipv4", "Local IPv4 Address", "mdi:ip-network"),
+        sensor("local_ipv6", "Local IPv6 Address", "mdi:ip-network"),
+        Sensor {
+            name: "VPN".to_string(),
+            state: SensorState {
+                unique_id: "vpn".to_string(),
+                sensor_type: "binary_sensor".to_string(),
+                icon: "mdi:vpn".to_string(),
+                ..Default::default()
+            },
+            device_class: Some("connectivity".to_string()),
+            ..Default::default()
+        },
+    ]
+}
+
+/// The first address of the primary interface, or of any interface if there is no default route.
+fn local_address(status: &NetworkStatus, ipv4: bool) -> Value {
+    let primary = status
+        .primary_interface
+        .as_ref()
+        .and_then(|interface| status.addresses.get(interface));
+    primary
+        .into_iter()
+        .chain(status.addresses.values())
+        .flatten()
+        .find(|address| address.is_ipv4() == ipv4)
+        .map_or(Value::Null, |address| json!(address.to_string()))
+}
+
+pub fn sensor_states(status: &NetworkStatus) -> Vec<SensorState> {
+    let wifi = status.wifi.as_ref();
+    let addresses: BTreeMap<&String, Vec<String>> = status
+        .addresses
+        .iter()
+        .map(|(interface, addresses)| (interface, addresses.iter().map(IpAddr::to_string).collect()))
+        .collect();
+    let vpns: Vec<Value> = status
+        .vpns
+        .iter()
+        .map(|vpn| json!({ "interface": vpn.interface, "kind": vpn.kind }))
+        .collect();
+
+    let values = [
+        json!(wifi.and_then(|wifi| wifi.ssid.clone())),
+        json!(wifi.and_then(|wifi| wifi.bssid.clone())),
+        json!(wifi.and_then(|wifi| wifi.signal_dbm)),
+        json!(status.primary_interface),
+        local_address(status, true),
+        local_address(status, false),
+        json!(!status.vpns.is_empty()),
+    ];
+    let mut states: Vec<SensorState> = sensors().into_iter().map(|sensor| sensor.state).collect();
+    for (state, value) in states.iter_mut().zip(values) {
+        state.value = value;
+    }
+    if let Some(wifi) = wifi {
+        states[0]
+            .attributes
+            .insert("interface".to_string(), json!(wifi.interface));
+    }
+    states[4].attributes.insert("addresses".to_string(), json!(addresses));
+    states[5].attributes.insert("addresses".to_string(), json!(addresses));
+    states[6].attributes.insert("tunnels".to_string(), json!(vpns));
+    states
+}
+
+/// Signals every batch of link, address and route changes.
+pub async fn watch_netlink(event_tx: UnboundedSender<()>) -> Result<(), Error> {
+    let mut socket = TokioSocket::new(NETLINK_ROUTE)?;
+    let groups = libc::RTMGRP_LINK
+        | libc::RTMGRP_IPV4_IFADDR
+        | libc::RTMGRP_IPV4_ROUTE
+        | libc::RTMGRP_IPV6_IFADDR
+        | libc::RTMGRP_IPV6_ROUTE;
+    socket.socket_mut().bind(&SocketAddr::new(0, groups as u32))?;
+    loop {
+        // the messages themselves don't matter, everything is read again anyway
+        socket.recv_from_full().await?;
+        event_tx.send(())?;
+    }
+}
+
+async fn watch_network_manager(connection: Connection, event_tx: UnboundedSender<()>) -> Result<(), Error> {
+    let rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .sender("org.freedesktop.NetworkManager")?
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .build();
+    let mut signals = MessageStream::for_match_rule(rule, &connection, None).await?;
+    while signals.next().await.is_some() {
+        event_tx.send(())?;
+    }
+    Ok(())
+}
+
+pub async fn start(sensor_tx: SensorSender, config: NetworkConfig) {
+    if !config.enabled {
+        return;
+    }
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+    // the watchers stop when start is dropped
+    let mut watchers = JoinSet::new();
+    let connection = match config.backend {
+        NetworkBackend::Netlink => {
+            watchers.spawn(async move {
+                if let Err(e) = watch_netlink(event_tx).await {
+                    println!("Stopped watching netlink: {}", e);
+                }
+            });
+            None
+        }
+        NetworkBackend::NetworkManager => match Connection::system().await {
+            Ok(connection) => {
+                let watched = connection.clone();
+                watchers.spawn(async move {
+                    if let Err(e) = watch_network_manager(watched, event_tx).await {
+                        println!("Stopped watching NetworkManager: {}", e);
+                    }
+                });
+                Some(connection)
+            }
+            Err(e) => {
+                println!("No system bus, not monitoring NetworkManager: {}", e);
+                return;
+            }
+        },
+    };
+
+    let reader = NetworkReader::new("/");
+    let mut ticks = interval(Duration::from_secs(config.interval.max(1)));
+    let mut last_states = vec![];
+    loop {
+        select! {
+            _ = ticks.tick() => {},
+            Some(_) = event_rx.recv() => {
+                sleep(SETTLE).await;
+                while event_rx.try_recv().is_ok() {}
+            },
+        }
+        let status = match &connection {
+            Some(connection) => match read_network_manager(connection).await {
+                Ok(status) => status,
+                Err(e) => {
+                    println!("Failed to read NetworkManager: {}", e);
+                    continue;
+                }
+            },
+            None => read_netlink(&reader).await,
+        };
+        send_changes(&sensor_tx, &mut last_states, sensor_states(&status));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_iw_link() {
+        let output =
+            "Connected to 9c:53:22:aa:bb:cc (on wlp2s0)\n\tSSID: Home Network\n\tfreq: 5180\n\tsignal: -52 dBm\n";
+
+        let wifi = parse_iw_link("wlp2s0", output).unwrap();
+
+        assert_eq!(wifi.ssid.as_deref(), Some("Home Network"));
+        assert_eq!(wifi.bssid.as_deref(), Some("9c:53:22:aa:bb:cc"));
+        assert_eq!(wifi.signal_dbm, Some(-52));
+        assert_eq!(parse_iw_link("wlp2s0", "Not connected.\n"), None);
+    }
+
+    #[test]
+    fn test_parse_default_route() {
+        let route = "Iface\tDestination\tGateway\tFlags\tRefCnt\tUse\tMetric\tMask\tMTU\tWindow\tIRTT\n\
+                     wlp2s0\t00000000\t0102A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0\n\
+                     enp3s0\t00000000\t0102A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+                     enp3s0\t0002A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0\n";
+        let ipv6_route = "00000000000000000000000000000000 00 00000000000000000000000000000000 00 fe800000000000000000000000000001 00000400 00000001 00000000 00000003 wg0\n";
+
+        assert_eq!(parse_default_route(route, ipv6_route).as_deref(), Some("enp3s0"));
+        assert_eq!(parse_default_route("", ipv6_route).as_deref(), Some("wg0"));
+        assert_eq!(parse_default_route("", ""), None);
+    }
+
+    #[test]
+    fn test_vpns_from_sysfs() {
+        let root = tempdir().unwrap();
+        let write = |path: &str, content: &str| {
+            let path = root.path().join("sys/class/net").join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        };
+        write("wg0/uevent", "DEVTYPE=wireguard\nINTERFACE=wg0\n");
+        write("wg0/flags", "0x91\n");
+        write("tun0/tun_flags", "0x1001\n");
+        write("tun0/flags", "0x1090\n");
+        write("wlp2s0/wireless/.keep", "");
+        write("wlp2s0/flags", "0x1003\n");
+
+        let reader = NetworkReader::new(root.path());
+
+        assert_eq!(
+            reader.vpns(),
+            vec![Vpn {
+                interface: "wg0".to_string(),
+                kind: "wireguard".to_string()
+            }]
+        );
+        assert_eq!(reader.wireless_interfaces(), vec!["wlp2s0"]);
+    }
+
+    #[test]
+    fn test_sensor_states() {
+        let status = NetworkStatus {
+            wifi: Some(Wifi {
+                interface: "wlp2s0".to_string(),
+                ssid: Some("Office".to_string()),
+                bssid: None,
+                signal_dbm: Some(-61),
+            }),
+            primary_interface: Some("wlp2s0".to_string()),
+            addresses: BTreeMap::from([
+                ("docker0".to_string(), vec![IpAddr::V4(Ipv4Addr::new(172, 17, 0, 1))]),
+                (
+                    "wlp2s0".to_string(),
+                    vec![
+                        IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+                        IpAddr::V4(Ipv4Addr::new(10, 0, 0, 12)),
+                    ],
+                ),
+            ]),
+            vpns: vec![],
+        };
+
+        let states = sensor_states(&status);
+
+        assert_eq!(states[0].value, json!("Office"));
+        assert_eq!(states[2].value, json!(-61));
+        assert_eq!(states[4].value, json!("10.0.0.12"));
+        assert_eq!(states[5].value, json!("2001:db8::1"));
+        assert_eq!(states[6].value, json!(false));
+    }
+}
@@ -0,0 +1,523 @@
+// sysfs -- https://www.kernel.org/doc/Documentation/ABI/testing/sysfs-class-power
+// UPower -- https://upower.freedesktop.org/docs/Device.html
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Error;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::select;
+use tokio::time::interval;
+use zbus::message::Type;
+use zbus::proxy::CacheProperties;
+use zbus::zvariant::OwnedObjectPath;
+use zbus::{Connection, MatchRule, MessageStream};
+
+use crate::agent_state::{Sensor, SensorState};
+use crate::monitor::system::slug;
+use crate::monitor::{send_changes, RegistrationSender, SensorSender};
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerBackend {
+    Sysfs,
+    Upower,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PowerConfig {
+    pub enabled: bool,
+    pub backend: PowerBackend,
+    /// seconds between readings; UPower changes are picked up right away as well
+    pub interval: u64,
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            backend: PowerBackend::Sysfs,
+            interval: 60,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Battery {
+    /// e.g. BAT0 or hidpp_battery_0, used for the unique ids
+    pub id: String,
+    pub model: Option<String>,
+    /// wireless mice, headsets and the like, as opposed to batteries powering the computer
+    pub peripheral: bool,
+    pub level: Option<f64>,
+    /// charging, discharging, full, not_charging or unknown
+    pub state: String,
+    pub minutes_to_empty: Option<f64>,
+    pub minutes_to_full: Option<f64>,
+    /// full capacity relative to the design capacity, in percent
+    pub health: Option<f64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PowerStatus {
+    pub batteries: Vec<Battery>,
+    /// None on machines without a mains adapter
+    pub ac_online: Option<bool>,
+}
+
+fn round(value: f64) -> f64 {
+    (value * 10.0).round() / 10.0
+}
+
+fn read(dir: &Path, attribute: &str) -> Option<String> {
+    let value = fs::read_to_string(dir.join(attribute)).ok()?;
+    Some(value.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+fn read_number(dir: &Path, attribute: &str) -> Option<f64> {
+    read(dir, attribute)?.parse().ok()
+}
+
+fn battery_state(status: &str) -> String {
+    match status {
+        "Charging" => "charging",
+        "Discharging" => "discharging",
+        "Full" => "full",
+        "Not charging" => "not_charging",
+        _ => "unknown",
+    }
+    .to_string()
+}
+
+/// Batteries report either energy (µWh and µW) or charge (µAh and µA), both work the same way.
+fn sysfs_battery(id: &str, dir: &Path) -> Battery {
+    let state = battery_state(&read(dir, "status").unwrap_or_default());
+    let (now, full, design, rate) = match read_number(dir, "energy_now") {
+        Some(now) => (
+            Some(now),
+            read_number(dir, "energy_full"),
+            read_number(dir, "energy_full_design"),
+            read_number(dir, "power_now"),
+        ),
+        None => (
+            read_number(dir, "charge_now"),
+            read_number(dir, "charge_full"),
+            read_number(dir, "charge_full_design"),
+            read_number(dir, "current_now"),
+        ),
+    };
+    let rate = rate.map(f64::abs).filter(|rate| *rate > 0.0);
+    let level = read_number(dir, "capacity").or_else(|| match (now, full) {
+        (Some(now), Some(full)) if full > 0.0 => Some(round(100.0 * now / full)),
+        _ => None,
+    });
+    let minutes_to_empty = match (state.as_str(), now, rate) {
+        ("discharging", Some(now), Some(rate)) => Some(round(60.0 * now / rate)),
+        _ => None,
+    };
+    let minutes_to_full = match (state.as_str(), now, full, rate) {
+        ("charging", Some(now), Some(full), Some(rate)) => Some(round(60.0 * (full - now).max(0.0) / rate)),
+        _ => None,
+    };
+    let health = match (full, design) {
+        (Some(full), Some(design)) if design > 0.0 => Some(round(100.0 * full / design)),
+        _ => None,
+    };
+
+    Battery {
+        id: id.to_string(),
+        model: read(dir, "model_name"),
+        peripheral: read(dir, "scope").as_deref() == Some("Device"),
+        level,
+        state,
+        minutes_to_empty,
+        minutes_to_full,
+        health,
+    }
+}
+
+/// Reads `class/power_supply` below `sys`, which is `/sys` except in tests.
+pub fn read_sysfs(sys: &Path) -> PowerStatus {
+    let mut status = PowerStatus::default();
+    let Ok(entries) = fs::read_dir(sys.join("class/power_supply")) else {
+        return status;
+    };
+    let mut supplies: Vec<PathBuf> = entries.filter_map(|entry| Some(entry.ok()?.path())).collect();
+    supplies.sort();
+    for dir in supplies {
+        let id = dir.file_name().unwrap_or_default().to_string_lossy().to_string();
+        match read(&dir, "type").as_deref() {
+            Some("Battery") if read(&dir, "present").as_deref() != Some("0") => {
+                status.batteries.push(sysfs_battery(&id, &dir));
+            }
+            Some("Mains") => {
+                let online = read(&dir, "online").as_deref() == Some("1");
+                status.ac_online = Some(status.ac_online.unwrap_or(false) || online);
+            }
+            _ => {}
+        }
+    }
+    status
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.UPower",
+    default_service = "org.freedesktop.UPower",
+    default_path = "/org/freedesktop/UPower"
+)]
+trait UPower {
+    fn enumerate_devices(&self) -> zbus::Result<Vec<OwnedObjectPath>>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.UPower.Device",
+    default_service = "org.freedesktop.UPower"
+)]
+trait UPowerDevice {
+    #[zbus(property, name = "Type")]
+    fn device_type(&self) -> zbus::Result<u32>;
+    #[zbus(property)]
+    fn native_path(&self) -> zbus::Result<String>;
+    #[zbus(property)]
+    fn model(&self) -> zbus::Result<String>;
+    #[zbus(property)]
+    fn power_supply(&self) -> zbus::Result<bool>;
+    #[zbus(property)]
+    fn is_present(&self) -> zbus::Result<bool>;
+    #[zbus(property)]
+    fn online(&self) -> zbus::Result<bool>;
+    #[zbus(property)]
+    fn percentage(&self) -> zbus::Result<f64>;
+    #[zbus(property)]
+    fn state(&self) -> zbus::Result<u32>;
+    #[zbus(property)]
+    fn time_to_empty(&self) -> zbus::Result<i64>;
+    #[zbus(property)]
+    fn time_to_full(&self) -> zbus::Result<i64>;
+    #[zbus(property)]
+    fn capacity(&self) -> zbus::Result<f64>;
+}
+
+const UPOWER_LINE_POWER: u32 = 1;
+
+fn upower_state(state: u32) -> String {
+    match state {
+        1 | 5 => "charging",
+        2 | 3 | 6 => "discharging",
+        4 => "full",
+        _ => "unknown",
+    }
+    .to_string()
+}
+
+pub async fn read_upower(connection: &Connection) -> zbus::Result<PowerStatus> {
+    let mut status = PowerStatus::default();
+    for path in UPowerProxy::new(connection).await?.enumerate_devices().await? {
+        let device = UPowerDeviceProxy::builder(connection)
+            .path(path.clone())?
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        let device_type = device.device_type().await?;
+        if device_type == UPOWER_LINE_POWER {
+            let online = device.online().await.unwrap_or_default();
+            status.ac_online = Some(status.ac_online.unwrap_or(false) || online);
+            continue;
+        }
+        if !device.is_present().await.unwrap_or(true) {
+            continue;
+        }
+        // device paths look like /org/freedesktop/UPower/devices/battery_BAT0
+        let native_path = device.native_path().await.unwrap_or_default();
+        let id = match native_path.rsplit('/').next() {
+            Some(id) if !id.is_empty() => id.to_string(),
+            _ => path.as_str().rsplit('/').next().unwrap_or_default().to_string(),
+        };
+        let minutes = |seconds: i64| Some(round(seconds as f64 / 60.0)).filter(|_| seconds > 0);
+        status.batteries.push(Battery {
+            id,
+            model: device.model().await.ok().filter(|model| !model.is_empty()),
+            peripheral: !device.power_supply().await.unwrap_or(true),
+            level: device.percentage().await.ok(),
+            state: upower_state(device.state().await.unwrap_or_default()),
+            minutes_to_empty: minutes(device.time_to_empty().await.unwrap_or_default()),
+            minutes_to_full: minutes(device.time_to_full().await.unwrap_or_default()),
+            health: device
+                .capacity()
+                .await
+                .ok()
+                .filter(|capacity| *capacity > 0.0)
+                .map(round),
+        });
+    }
+    Ok(status)
+}
+
+fn battery_sensor(battery: &Battery, kind: &str, name: &str, icon: &str) -> Sensor {
+    let model = battery.model.clone().unwrap_or_else(|| battery.id.clone());
+    Sensor {
+        name: format!("{} {}", model, name),
+        state: SensorState {
+            unique_id: format!("battery_{}_{}", kind, slug(&battery.id)),
+            sensor_type: "sensor".to_string(),
+            icon: icon.to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+fn battery_sensors(battery: &Battery) -> Vec<(Sensor, Value)> {
+    let optional = |value: Option<f64>| value.map_or(Value::Null, |value| json!(value));
+    let mut sensors = vec![(
+        Sensor {
+            device_class: Some("battery".to_string()),
+            unit_of_measurement: Some("%".to_string()),
+            state_class: Some("measurement".to_string()),
+            ..battery_sensor(battery, "level", "Battery Level", "mdi:battery")
+        },
+        optional(battery.level),
+    )];
+    if battery.peripheral {
+        return sensors;
+    }
+    sensors.extend([
+        (
+            // not an enum sensor, mobile_app can't register the options that go with one
+            battery_sensor(battery, "state", "Battery State", "mdi:battery-charging"),
+            json!(battery.state),
+        ),
+        (
+            Sensor {
+                device_class: Some("duration".to_string()),
+                unit_of_measurement: Some("min".to_string()),
+                ..battery_sensor(battery, "time_to_empty", "Time To Empty", "mdi:battery-clock")
+            },
+            optional(battery.minutes_to_empty),
+        ),
+        (
+            Sensor {
+                device_class: Some("duration".to_string()),
+                unit_of_measurement: Some("min".to_string()),
+                ..battery_sensor(battery, "time_to_full", "Time To Full", "mdi:battery-clock")
+            },
+            optional(battery.minutes_to_full),
+        ),
+        (
+            Sensor {
+                unit_of_measurement: Some("%".to_string()),
+                state_class: Some("measurement".to_string()),
+                entity_category: Some("diagnostic".to_string()),
+                ..battery_sensor(battery, "health", "Battery Health", "mdi:battery-heart-variant")
+            },
+            optional(battery.health),
+        ),
+    ]);
+    sensors
+}
+
+fn all_sensors(status: &PowerStatus) -> Vec<(Sensor, Value)> {
+    let mut sensors: Vec<(Sensor, Value)> = status.batteries.iter().flat_map(battery_sensors).collect();
+    if let Some(online) = status.ac_online {
+        let ac = Sensor {
+            name: "AC Power".to_string(),
+            state: SensorState {
+                unique_id: "ac_power".to_string(),
+                sensor_type: "binary_sensor".to_string(),
+                icon: "mdi:power-plug".to_string(),
+                ..Default::default()
+            },
+            device_class: Some("plug".to_string()),
+            ..Default::default()
+        };
+        sensors.push((ac, json!(online)));
+    }
+    sensors
+}
+
+pub fn sensors(status: &PowerStatus) -> Vec<Sensor> {
+    all_sensors(status).into_iter().map(|(sensor, _)| sensor).collect()
+}
+
+pub fn sensor_states(status: &PowerStatus) -> Vec<SensorState> {
+    all_sensors(status)
+        .into_iter()
+        .map(|(sensor, value)| SensorState { value, ..sensor.state })
+        .collect()
+}
+
+pub async fn read_status(config: &PowerConfig) -> Result<PowerStatus, Error> {
+    match config.backend {
+        PowerBackend::Sysfs => Ok(read_sysfs(Path::new("/sys"))),
+        PowerBackend::Upower => Ok(read_upower(&Connection::system().await?).await?),
+    }
+}
+
+async fn upower_signals(connection: &Connection) -> zbus::Result<MessageStream> {
+    let rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .sender("org.freedesktop.UPower")?
+        .build();
+    MessageStream::for_match_rule(rule, connection, None).await
+}
+
+pub async fn start(sensor_tx: SensorSender, register_tx: RegistrationSender, config: PowerConfig) {
+    if !config.enabled {
+        return;
+    }
+    let connection = match config.backend {
+        PowerBackend::Sysfs => None,
+        PowerBackend::Upower => match Connection::system().await {
+            Ok(connection) => Some(connection),
+            Err(e) => {
+                println!("No system bus, not monitoring UPower: {}", e);
+                return;
+            }
+        },
+    };
+    let mut signals = match &connection {
+        Some(connection) => upower_signals(connection).await.ok(),
+        None => None,
+    };
+    let mut ticks = interval(Duration::from_secs(config.interval.max(1)));
+    let mut known: HashSet<String> = HashSet::new();
+    let mut last_states: Vec<SensorState> = vec![];
+    loop {
+        select! {
+            _ = ticks.tick() => {},
+            Some(_) = async { signals.as_mut()?.next().await } => {},
+        }
+        let status = match &connection {
+            Some(connection) => match read_upower(connection).await {
+                Ok(status) => status,
+                Err(e) => {
+                    println!("Failed to read power supplies from UPower: {}", e);
+                    continue;
+                }
+            },
+            None => read_sysfs(Path::new("/sys")),
+        };
+
+        let new_sensors: Vec<Sensor> = sensors(&status)
+            .into_iter()
+            .filter(|sensor| known.insert(sensor.state.unique_id.clone()))
+            .collect();
+        if !new_sensors.is_empty() {
+            register_tx.send(new_sensors).expect("Unable to send");
+        }
+        let mut states = sensor_states(&status);
+        // peripherals that went away are reported as unknown rather than keeping their last level
+        let gone: Vec<SensorState> = last_states
+            .iter()
+            .filter(|last| !states.iter().any(|state| state.unique_id == last.unique_id))
+            .map(|last| SensorState {
+                value: Value::Null,
+                ..last.clone()
+            })
+            .collect();
+        states.extend(gone);
+        send_changes(&sensor_tx, &mut last_states, states);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn write(dir: &Path, files: &[(&str, &str)]) {
+        fs::create_dir_all(dir).unwrap();
+        for (name, content) in files {
+            fs::write(dir.join(name), content).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_read_sysfs() {
+        let sys = tempdir().unwrap();
+        let supplies = sys.path().join("class/power_supply");
+        write(&supplies.join("AC"), &[("type", "Mains\n"), ("online", "1\n")]);
+        write(
+            &supplies.join("BAT0"),
+            &[
+                ("type", "Battery\n"),
+                ("status", "Charging\n"),
+                ("capacity", "80\n"),
+                ("energy_now", "40000000\n"),
+                ("energy_full", "50000000\n"),
+                ("energy_full_design", "57000000\n"),
+                ("power_now", "20000000\n"),
+                ("model_name", "5B10W13975\n"),
+            ],
+        );
+        write(
+            &supplies.join("hidpp_battery_0"),
+            &[
+                ("type", "Battery\n"),
+                ("scope", "Device\n"),
+                ("status", "Discharging\n"),
+                ("capacity", "55\n"),
+                ("model_name", "MX Master 3\n"),
+            ],
+        );
+
+        let status = read_sysfs(sys.path());
+
+        assert_eq!(status.ac_online, Some(true));
+        assert_eq!(status.batteries.len(), 2);
+        let laptop = &status.batteries[0];
+        assert_eq!(laptop.level, Some(80.0));
+        assert_eq!(laptop.state, "charging");
+        assert_eq!(laptop.minutes_to_full, Some(30.0));
+        assert_eq!(laptop.minutes_to_empty, None);
+        assert_eq!(laptop.health, Some(87.7));
+        assert!(!laptop.peripheral);
+        assert!(status.batteries[1].peripheral);
+    }
+
+    #[test]
+    fn test_charge_based_battery() {
+        let sys = tempdir().unwrap();
+        write(
+            &sys.path().join("class/power_supply/BAT1"),
+            &[
+                ("type", "Battery\n"),
+                ("status", "Discharging\n"),
+                ("charge_now", "2000000\n"),
+                ("charge_full", "4000000\n"),
+                ("current_now", "1000000\n"),
+            ],
+        );
+
+        let battery = &read_sysfs(sys.path()).batteries[0];
+
+        assert_eq!(battery.level, Some(50.0));
+        assert_eq!(battery.minutes_to_empty, Some(120.0));
+    }
+
+    #[test]
+    fn test_peripherals_only_report_their_level() {
+        let status = PowerStatus {
+            batteries: vec![Battery {
+                id: "hidpp_battery_0".to_string(),
+                model: Some("MX Master 3".to_string()),
+                peripheral: true,
+                level: Some(55.0),
+                ..Default::default()
+            }],
+            ac_online: None,
+        };
+
+        let sensors = sensors(&status);
+        let states = sensor_states(&status);
+
+        assert_eq!(sensors.len(), 1);
+        assert_eq!(sensors[0].name, "MX Master 3 Battery Level");
+        assert_eq!(states[0].unique_id, "battery_level_hidpp_battery_0");
+        assert_eq!(states[0].value, json!(55.0));
+    }
+}
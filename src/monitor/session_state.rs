@@ -0,0 +1,276 @@
+// logind -- https://www.freedesktop.org/software/systemd/man/latest/org.freedesktop.login1.html
+// screensaver -- https://specifications.freedesktop.org/idle-inhibit-spec/latest/
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::StreamExt;
+use serde_json::json;
+use tokio::select;
+use tokio::time::interval;
+use users::get_current_uid;
+use zbus::message::Type;
+use zbus::proxy::CacheProperties;
+use zbus::zvariant::OwnedObjectPath;
+use zbus::{Connection, MatchRule, MessageStream};
+
+use crate::agent_state::{Sensor, SensorState};
+use crate::monitor::{send_changes, SensorSender};
+
+const LOGIND_SERVICE: &str = "org.freedesktop.login1";
+// while idle the idle duration keeps growing, so it's refreshed on this interval
+const IDLE_REFRESH: Duration = Duration::from_secs(30);
+
+#[zbus::proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1"
+)]
+trait LogindManager {
+    fn get_user(&self, uid: u32) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[zbus::proxy(interface = "org.freedesktop.login1.User", default_service = "org.freedesktop.login1")]
+trait LogindUser {
+    #[zbus(property)]
+    fn display(&self) -> zbus::Result<(String, OwnedObjectPath)>;
+}
+
+#[zbus::proxy(interface = "org.freedesktop.login1.Session", default_service = "org.freedesktop.login1")]
+trait LogindSession {
+    #[zbus(property)]
+    fn locked_hint(&self) -> zbus::Result<bool>;
+    #[zbus(property)]
+    fn idle_hint(&self) -> zbus::Result<bool>;
+    #[zbus(property)]
+    fn idle_since_hint(&self) -> zbus::Result<u64>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.ScreenSaver",
+    default_service = "org.freedesktop.ScreenSaver",
+    default_path = "/org/freedesktop/ScreenSaver"
+)]
+trait ScreenSaver {
+    fn get_active(&self) -> zbus::Result<bool>;
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SessionStatus {
+    pub locked: bool,
+    pub idle: bool,
+    /// CLOCK_REALTIME timestamp in microseconds of when the session became idle
+    pub idle_since_us: Option<u64>,
+    pub screensaver_active: bool,
+}
+
+pub fn sensors() -> Vec<Sensor> {
+    vec![
+        Sensor {
+            name: "Screen Locked".to_string(),
+            state: SensorState {
+                value: json!(false),
+                unique_id: "screen_locked".to_string(),
+                sensor_type: "binary_sensor".to_string(),
+                icon: "mdi:monitor-lock".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        Sensor {
+            name: "Session Idle".to_string(),
+            state: SensorState {
+                value: json!(false),
+                unique_id: "session_idle".to_string(),
+                sensor_type: "binary_sensor".to_string(),
+                icon: "mdi:sleep".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        Sensor {
+            name: "Idle Time".to_string(),
+            state: SensorState {
+                value: json!(0),
+                unique_id: "idle_time".to_string(),
+                sensor_type: "sensor".to_string(),
+                icon: "mdi:timer-sand".to_string(),
+                ..Default::default()
+            },
+            device_class: Some("duration".to_string()),
+            unit_of_measurement: Some("s".to_string()),
+            state_class: Some("measurement".to_string()),
+            ..Default::default()
+        },
+    ]
+}
+
+pub fn sensor_states(status: &SessionStatus, now_us: u64) -> Vec<SensorState> {
+    let locked = status.locked || status.screensaver_active;
+    let idle = status.idle || status.screensaver_active;
+    let idle_seconds = match (idle, status.idle_since_us) {
+        (true, Some(since)) if since > 0 => now_us.saturating_sub(since) / 1_000_000,
+        _ => 0,
+    };
+
+    let mut states: Vec<SensorState> = sensors().into_iter().map(|sensor| sensor.state).collect();
+    states[0].value = json!(locked);
+    states[1].value = json!(idle);
+    states[2].value = json!(idle_seconds);
+    states
+}
+
+fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_micros() as u64)
+}
+
+/// Finds the logind session of this agent; `auto` only works when the agent runs inside the
+/// session, e.g. started from the desktop, so systemd user services fall back to the user's display.
+async fn session_path(system: &Connection) -> zbus::Result<OwnedObjectPath> {
+    let auto = OwnedObjectPath::try_from("/org/freedesktop/login1/session/auto")?;
+    let proxy = LogindSessionProxy::builder(system)
+        .path(auto.clone())?
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?;
+    if proxy.locked_hint().await.is_ok() {
+        return Ok(auto);
+    }
+    let user = LogindManagerProxy::new(system).await?.get_user(get_current_uid()).await?;
+    let (_, display) = LogindUserProxy::builder(system).path(user)?.build().await?.display().await?;
+    Ok(display)
+}
+
+async fn read_status(
+    logind: Option<&LogindSessionProxy<'_>>,
+    screensaver: Option<&ScreenSaverProxy<'_>>,
+) -> SessionStatus {
+    let mut status = SessionStatus::default();
+    if let Some(logind) = logind {
+        status.locked = logind.locked_hint().await.unwrap_or_default();
+        status.idle = logind.idle_hint().await.unwrap_or_default();
+        status.idle_since_us = logind.idle_since_hint().await.ok();
+    }
+    if let Some(screensaver) = screensaver {
+        status.screensaver_active = screensaver.get_active().await.unwrap_or_default();
+    }
+    status
+}
+
+async fn logind_session(system: &Connection) -> zbus::Result<(LogindSessionProxy<'static>, MessageStream)> {
+    let path = session_path(system).await?;
+    let rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .sender(LOGIND_SERVICE)?
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .path(path.clone())?
+        .build();
+    let signals = MessageStream::for_match_rule(rule, system, None).await?;
+    let proxy = LogindSessionProxy::builder(system)
+        .path(path)?
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?;
+    Ok((proxy, signals))
+}
+
+async fn screensaver(session: &Connection) -> zbus::Result<(ScreenSaverProxy<'static>, MessageStream)> {
+    let proxy = ScreenSaverProxy::new(session).await?;
+    proxy.get_active().await?;
+    let rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .interface("org.freedesktop.ScreenSaver")?
+        .member("ActiveChanged")?
+        .build();
+    let signals = MessageStream::for_match_rule(rule, session, None).await?;
+    Ok((proxy, signals))
+}
+
+pub async fn start(sensor_tx: SensorSender) {
+    let system = Connection::system().await;
+    let session = Connection::session().await;
+
+    let (logind, logind_signals) = match &system {
+        Ok(system) => match logind_session(system).await {
+            Ok((proxy, signals)) => (Some(proxy), Some(signals)),
+            Err(e) => {
+                println!("No logind session, not monitoring lock state: {}", e);
+                (None, None)
+            }
+        },
+        Err(e) => {
+            println!("No system bus, not monitoring lock state: {}", e);
+            (None, None)
+        }
+    };
+    let (screensaver, screensaver_signals) = match &session {
+        Ok(session) => match screensaver(session).await {
+            Ok((proxy, signals)) => (Some(proxy), Some(signals)),
+            Err(e) => {
+                println!("No screensaver, not monitoring it: {}", e);
+                (None, None)
+            }
+        },
+        Err(e) => {
+            println!("No session bus, not monitoring the screensaver: {}", e);
+            (None, None)
+        }
+    };
+    if logind.is_none() && screensaver.is_none() {
+        return;
+    }
+
+    let mut signals = futures::stream::select_all(logind_signals.into_iter().chain(screensaver_signals));
+    let mut refresh = interval(IDLE_REFRESH);
+    let mut last_states: Vec<SensorState> = vec![];
+    loop {
+        select! {
+            Some(_) = signals.next() => {},
+            _ = refresh.tick() => {},
+        }
+        let status = read_status(logind.as_ref(), screensaver.as_ref()).await;
+        send_changes(&sensor_tx, &mut last_states, sensor_states(&status, now_us()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sensor_states_active_session() {
+        let states = sensor_states(&SessionStatus::default(), 10_000_000);
+
+        assert_eq!(states[0].value, json!(false));
+        assert_eq!(states[1].value, json!(false));
+        assert_eq!(states[2].value, json!(0));
+    }
+
+    #[test]
+    fn test_sensor_states_idle_and_locked() {
+        let status = SessionStatus {
+            locked: true,
+            idle: true,
+            idle_since_us: Some(4_000_000),
+            screensaver_active: false,
+        };
+        let states = sensor_states(&status, 94_000_000);
+
+        assert_eq!(states[0].value, json!(true));
+        assert_eq!(states[1].value, json!(true));
+        assert_eq!(states[2].value, json!(90));
+    }
+
+    #[test]
+    fn test_sensor_states_screensaver_counts_as_locked() {
+        let status = SessionStatus {
+            screensaver_active: true,
+            ..Default::default()
+        };
+        let states = sensor_states(&status, 1);
+
+        assert_eq!(states[0].value, json!(true));
+        assert_eq!(states[1].value, json!(true));
+    }
+}
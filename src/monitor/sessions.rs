@@ -0,0 +1,400 @@
+// logind -- https://www.freedesktop.org/software/systemd/man/latest/org.freedesktop.login1.html
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::select;
+use zbus::message::Type;
+use zbus::proxy::CacheProperties;
+use zbus::zvariant::OwnedObjectPath;
+use zbus::{Connection, MatchRule, MessageStream};
+
+use crate::agent_state::{Sensor, SensorState};
+use crate::monitor::{send_changes, SensorSender};
+
+const LOGIND_SERVICE: &str = "org.freedesktop.login1";
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionsConfig {
+    pub enabled: bool,
+}
+
+impl Default for SessionsConfig {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1"
+)]
+trait LogindManager {
+    /// id, uid, user name, seat and object path of every session
+    fn list_sessions(&self) -> zbus::Result<Vec<(String, u32, String, String, OwnedObjectPath)>>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.login1.Session",
+    default_service = "org.freedesktop.login1"
+)]
+trait LogindSession {
+    #[zbus(property)]
+    fn type_(&self) -> zbus::Result<String>;
+    #[zbus(property)]
+    fn class(&self) -> zbus::Result<String>;
+    #[zbus(property)]
+    fn remote(&self) -> zbus::Result<bool>;
+    #[zbus(property)]
+    fn remote_host(&self) -> zbus::Result<String>;
+    #[zbus(property)]
+    fn state(&self) -> zbus::Result<String>;
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoginSession {
+    pub id: String,
+    pub user: String,
+    pub seat: Option<String>,
+    /// tty, x11, wayland, mir or unspecified (e.g. SSH without a terminal)
+    pub session_type: String,
+    /// user, greeter, lock-screen or, since systemd 256, manager for the user's service manager
+    pub class: String,
+    pub remote: bool,
+    pub remote_host: Option<String>,
+    /// online, active or closing
+    pub state: String,
+}
+
+impl LoginSession {
+    /// Greeters and the sessions of user service managers aren't someone logging in.
+    fn is_user(&self) -> bool {
+        self.class.starts_with("user")
+    }
+}
+
+pub fn sensors() -> Vec<Sensor> {
+    vec![
+        Sensor {
+            name: "Logged In Sessions".to_string(),
+            state: SensorState {
+                value: json!(0),
+                unique_id: "logged_in_sessions".to_string(),
+                sensor_type: "sensor".to_string(),
+                icon: "mdi:account-multiple".to_string(),
+                ..Default::default()
+            },
+            state_class: Some("measurement".to_string()),
+            ..Default::default()
+        },
+        Sensor {
+            name: "Remote Session".to_string(),
+            state: SensorState {
+                value: json!(false),
+                unique_id: "remote_session".to_string(),
+                sensor_type: "binary_sensor".to_string(),
+                icon: "mdi:remote-desktop".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    ]
+}
+
+pub fn sensor_states(sessions: &[LoginSession]) -> Vec<SensorState> {
+    let sessions: Vec<&LoginSession> = sessions
+        .iter()
+        .filter(|session| session.is_user() && session.state != "closing")
+        .collect();
+    let session_json = |session: &&LoginSession| {
+        json!({
+            "id": session.id,
+            "user": session.user,
+            "seat": session.seat,
+            "type": session.session_type,
+            "remote": session.remote,
+            "remote_host": session.remote_host,
+            "state": session.state,
+        })
+    };
+    let mut users: Vec<&str> = sessions.iter().map(|session| session.user.as_str()).collect();
+    users.sort();
+    users.dedup();
+    let remote: Vec<Value> = sessions
+        .iter()
+        .filter(|session| session.remote)
+        .map(session_json)
+        .collect();
+
+    let mut states: Vec<SensorState> = sensors().into_iter().map(|sensor| sensor.state).collect();
+    states[0].value = json!(sessions.len());
+    states[0].attributes.insert("users".to_string(), json!(users));
+    states[0].attributes.insert(
+        "sessions".to_string(),
+        json!(sessions.iter().map(session_json).collect::<Vec<Value>>()),
+    );
+    states[1].value = json!(!remote.is_empty());
+    states[1].attributes.insert("sessions".to_string(), json!(remote));
+    states
+}
+
+async fn read_session(
+    connection: &Connection,
+    (id, _uid, user, seat, path): (String, u32, String, String, OwnedObjectPath),
+) -> zbus::Result<LoginSession> {
+    let proxy = LogindSessionProxy::builder(connection)
+        .path(path)?
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?;
+    let remote_host = proxy.remote_host().await?;
+    Ok(LoginSession {
+        id,
+        user,
+        seat: (!seat.is_empty()).then_some(seat),
+        session_type: proxy.type_().await?,
+        class: proxy.class().await?,
+        remote: proxy.remote().await?,
+        remote_host: (!remote_host.is_empty()).then_some(remote_host),
+        state: proxy.state().await?,
+    })
+}
+
+pub async fn read_sessions(connection: &Connection) -> zbus::Result<Vec<LoginSession>> {
+    let manager = LogindManagerProxy::new(connection).await?;
+    let mut sessions = vec![];
+    for session in manager.list_sessions().await? {
+        // a session can close between listing and reading it
+        if let Ok(session) = read_session(connection, session).await {
+            sessions.push(session);
+        }
+    }
+    sessions.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(sessions)
+}
+
+async fn signal_stream(connection: &Connection) -> zbus::Result<futures::stream::SelectAll<MessageStream>> {
+    let rule = |member| {
+        Ok::<_, zbus::Error>(
+            MatchRule::builder()
+                .msg_type(Type::Signal)
+                .sender(LOGIND_SERVICE)?
+                .interface("org.freedesktop.login1.Manager")?
+                .member(member)?
+                .build(),
+        )
+    };
+    Ok(futures::stream::select_all([
+        MessageStream::for_match_rule(rule("SessionNew")?, connection, None).await?,
+        MessageStream::for_match_rule(rule("SessionRemoved")?, connection, None).await?,
+    ]))
+}
+
+async fn refresh(connection: &Connection, sensor_tx: &SensorSender, last_states: &mut Vec<SensorState>) {
+    match read_sessions(connection).await {
+        Ok(sessions) => send_changes(sensor_tx, last_states, sensor_states(&sessions)),
+        Err(e) => println!("Failed to read logind sessions: {}", e),
+    }
+}
+
+pub async fn start(sensor_tx: SensorSender, config: SessionsConfig) {
+    if !config.enabled {
+        return;
+    }
+    let connection = match Connection::system().await {
+        Ok(connection) => connection,
+        Err(e) => {
+            println!("No system bus, not monitoring sessions: {}", e);
+            return;
+        }
+    };
+    let mut signals = match signal_stream(&connection).await {
+        Ok(signals) => signals,
+        Err(e) => {
+            println!("Failed to subscribe to logind signals: {}", e);
+            return;
+        }
+    };
+    let mut last_states = vec![];
+
+    refresh(&connection, &sensor_tx, &mut last_states).await;
+    loop {
+        select! {
+            Some(_) = signals.next() => {
+                refresh(&connection, &sensor_tx, &mut last_states).await;
+            },
+            else => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::testing::start_bus;
+    use std::time::Duration;
+    use tokio::time::timeout;
+    use zbus::object_server::SignalEmitter;
+    use zbus::zvariant::ObjectPath;
+
+    struct FakeManager {
+        sessions: Vec<(String, u32, String, String, OwnedObjectPath)>,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.login1.Manager")]
+    impl FakeManager {
+        fn list_sessions(&self) -> Vec<(String, u32, String, String, OwnedObjectPath)> {
+            self.sessions.clone()
+        }
+
+        #[zbus(signal)]
+        async fn session_new(emitter: &SignalEmitter<'_>, id: &str, path: ObjectPath<'_>) -> zbus::Result<()>;
+    }
+
+    struct FakeSession {
+        session_type: &'static str,
+        class: &'static str,
+        remote_host: &'static str,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.login1.Session")]
+    impl FakeSession {
+        #[zbus(property)]
+        fn type_(&self) -> String {
+            self.session_type.to_string()
+        }
+
+        #[zbus(property)]
+        fn class(&self) -> String {
+            self.class.to_string()
+        }
+
+        #[zbus(property)]
+        fn remote(&self) -> bool {
+            !self.remote_host.is_empty()
+        }
+
+        #[zbus(property)]
+        fn remote_host(&self) -> String {
+            self.remote_host.to_string()
+        }
+
+        #[zbus(property)]
+        fn state(&self) -> String {
+            "active".to_string()
+        }
+    }
+
+    fn listed(id: &str, user: &str, seat: &str) -> (String, u32, String, String, OwnedObjectPath) {
+        let path = format!("/org/freedesktop/login1/session/_3{}", id);
+        (
+            id.to_string(),
+            1000,
+            user.to_string(),
+            seat.to_string(),
+            OwnedObjectPath::try_from(path).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_sensor_states() {
+        let session = |id: &str, user: &str, class: &str, remote: bool| LoginSession {
+            id: id.to_string(),
+            user: user.to_string(),
+            class: class.to_string(),
+            remote,
+            state: "active".to_string(),
+            ..Default::default()
+        };
+        let sessions = [
+            session("1", "alex", "user", false),
+            session("2", "sam", "user", true),
+            session("3", "alex", "user", false),
+            session("c1", "gdm", "greeter", false),
+            session("4", "alex", "manager", false),
+        ];
+
+        let states = sensor_states(&sessions);
+        assert_eq!(states[0].value, json!(3));
+        assert_eq!(states[0].attributes["users"], json!(["alex", "sam"]));
+        assert_eq!(states[1].value, json!(true));
+        assert_eq!(states[1].attributes["sessions"][0]["user"], json!("sam"));
+
+        let states = sensor_states(&sessions[3..]);
+        assert_eq!(states[0].value, json!(0));
+        assert_eq!(states[1].value, json!(false));
+    }
+
+    #[tokio::test]
+    async fn test_read_sessions_and_signals() {
+        let Some((_bus, address)) = start_bus() else {
+            return;
+        };
+        let logind = zbus::connection::Builder::address(address.as_str())
+            .unwrap()
+            .name(LOGIND_SERVICE)
+            .unwrap()
+            .serve_at(
+                "/org/freedesktop/login1",
+                FakeManager {
+                    sessions: vec![listed("2", "alex", "seat0")],
+                },
+            )
+            .unwrap()
+            .serve_at(
+                "/org/freedesktop/login1/session/_32",
+                FakeSession {
+                    session_type: "wayland",
+                    class: "user",
+                    remote_host: "",
+                },
+            )
+            .unwrap()
+            .serve_at(
+                "/org/freedesktop/login1/session/_37",
+                FakeSession {
+                    session_type: "tty",
+                    class: "user",
+                    remote_host: "192.168.1.20",
+                },
+            )
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let connection = zbus::connection::Builder::address(address.as_str())
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let mut signals = signal_stream(&connection).await.unwrap();
+
+        let sessions = read_sessions(&connection).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].seat.as_deref(), Some("seat0"));
+        assert_eq!(sessions[0].session_type, "wayland");
+        assert!(!sessions[0].remote);
+
+        let manager = logind
+            .object_server()
+            .interface::<_, FakeManager>("/org/freedesktop/login1")
+            .await
+            .unwrap();
+        manager.get_mut().await.sessions.push(listed("7", "sam", ""));
+        let path = ObjectPath::try_from("/org/freedesktop/login1/session/_37").unwrap();
+        FakeManager::session_new(manager.signal_emitter(), "7", path)
+            .await
+            .unwrap();
+
+        timeout(Duration::from_secs(5), signals.next()).await.unwrap();
+        let states = sensor_states(&read_sessions(&connection).await.unwrap());
+        assert_eq!(states[0].value, json!(2));
+        assert_eq!(states[1].value, json!(true));
+        assert_eq!(
+            states[1].attributes["sessions"][0]["remote_host"],
+            json!("192.168.1.20")
+        );
+        assert_eq!(states[1].attributes["sessions"][0]["seat"], Value::Null);
+    }
+}
@@ -0,0 +1,552 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::DateTime;
+use nix::sys::statvfs::statvfs;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::time::interval;
+
+use crate::agent_state::{Sensor, SensorState};
+use crate::monitor::SensorSender;
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SystemConfig {
+    pub enabled: bool,
+    /// seconds between readings
+    pub interval: u64,
+    /// minimum change in percentage points before cpu, memory, swap and disk usage are sent
+    pub percent_threshold: f64,
+    pub load_threshold: f64,
+    /// minimum change in °C before a temperature is sent
+    pub temperature_threshold: f64,
+}
+
+impl Default for SystemConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval: 30,
+            percent_threshold: 1.0,
+            load_threshold: 0.1,
+            temperature_threshold: 1.0,
+        }
+    }
+}
+
+impl SystemConfig {
+    fn threshold(&self, unique_id: &str) -> f64 {
+        if unique_id.starts_with("load_") {
+            self.load_threshold
+        } else if unique_id.starts_with("temperature_") {
+            self.temperature_threshold
+        } else if unique_id.ends_with("_usage") || unique_id.starts_with("disk_usage_") {
+            self.percent_threshold
+        } else {
+            0.0
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CpuTimes {
+    pub idle: u64,
+    pub total: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mount {
+    pub device: String,
+    pub mount_point: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Temperature {
+    pub unique_id: String,
+    pub name: String,
+    pub celsius: f64,
+}
+
+/// Reads `/proc` and `/sys` below `root`, which is `/` except in tests.
+pub struct SystemReader {
+    root: PathBuf,
+}
+
+fn round(value: f64, decimals: i32) -> f64 {
+    let factor = 10f64.powi(decimals);
+    (value * factor).round() / factor
+}
+
+/// Turns a mount point or hwmon label into something usable in a unique id, `/` becoming `root`.
+pub fn slug(name: &str) -> String {
+    let slug: String = name
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let slug = slug.trim_matches('_').to_string();
+    if slug.is_empty() {
+        "root".to_string()
+    } else {
+        slug
+    }
+}
+
+/// /proc/mounts escapes spaces and friends as octal, e.g. `\040`.
+fn unescape_mount_point(mount_point: &str) -> String {
+    let mut result = String::new();
+    let mut chars = mount_point.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let octal: String = chars.by_ref().take(3).collect();
+            match u8::from_str_radix(&octal, 8) {
+                Ok(byte) => result.push(byte as char),
+                Err(_) => {
+                    result.push(c);
+                    result.push_str(&octal);
+                }
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+pub fn cpu_usage(previous: CpuTimes, current: CpuTimes) -> Option<f64> {
+    let total = current.total.checked_sub(previous.total)?;
+    let idle = current.idle.checked_sub(previous.idle)?;
+    if total == 0 {
+        return None;
+    }
+    Some(round(100.0 * (total - idle.min(total)) as f64 / total as f64, 1))
+}
+
+fn sensor(unique_id: &str, name: &str, icon: &str) -> Sensor {
+    Sensor {
+        name: name.to_string(),
+        state: SensorState {
+            unique_id: unique_id.to_string(),
+            sensor_type: "sensor".to_string(),
+            icon: icon.to_string(),
+            ..Default::default()
+        },
+        state_class: Some("measurement".to_string()),
+        entity_category: Some("diagnostic".to_string()),
+        ..Default::default()
+    }
+}
+
+fn percent_sensor(unique_id: &str, name: &str, icon: &str) -> Sensor {
+    Sensor {
+        unit_of_measurement: Some("%".to_string()),
+        ..sensor(unique_id, name, icon)
+    }
+}
+
+fn disk_sensor(mount: &Mount) -> Sensor {
+    percent_sensor(
+        &format!("disk_usage_{}", slug(&mount.mount_point)),
+        &format!("Disk Usage {}", mount.mount_point),
+        "mdi:harddisk",
+    )
+}
+
+fn temperature_sensor(temperature: &Temperature) -> Sensor {
+    Sensor {
+        device_class: Some("temperature".to_string()),
+        unit_of_measurement: Some("°C".to_string()),
+        ..sensor(&temperature.unique_id, &temperature.name, "mdi:thermometer")
+    }
+}
+
+impl SystemReader {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn read(&self, path: &str) -> Option<String> {
+        fs::read_to_string(self.root.join(path)).ok()
+    }
+
+    pub fn cpu_times(&self) -> Option<CpuTimes> {
+        let stat = self.read("proc/stat")?;
+        let line = stat.lines().find(|line| line.starts_with("cpu "))?;
+        let fields: Vec<u64> = line.split_whitespace().skip(1).filter_map(|f| f.parse().ok()).collect();
+        // user nice system idle iowait irq softirq steal; guest time is already part of user
+        let total = fields.iter().take(8).sum();
+        let idle = fields.get(3)? + fields.get(4).unwrap_or(&0);
+        Some(CpuTimes { idle, total })
+    }
+
+    pub fn boot_time(&self) -> Option<i64> {
+        let stat = self.read("proc/stat")?;
+        stat.lines().find_map(|line| line.strip_prefix("btime ")?.trim().parse().ok())
+    }
+
+    pub fn load_averages(&self) -> Option<[f64; 3]> {
+        let loadavg = self.read("proc/loadavg")?;
+        let mut fields = loadavg.split_whitespace().map(|f| f.parse::<f64>().ok());
+        Some([fields.next()??, fields.next()??, fields.next()??])
+    }
+
+    /// Returns the kB values of /proc/meminfo.
+    pub fn meminfo(&self) -> HashMap<String, u64> {
+        let meminfo = self.read("proc/meminfo").unwrap_or_default();
+        meminfo
+            .lines()
+            .filter_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                let value = value.split_whitespace().next()?.parse().ok()?;
+                Some((key.to_string(), value))
+            })
+            .collect()
+    }
+
+    pub fn uptime(&self) -> Option<f64> {
+        self.read("proc/uptime")?.split_whitespace().next()?.parse().ok()
+    }
+
+    /// Mounts of block devices, skipping read-only images like snaps.
+    pub fn mounts(&self) -> Vec<Mount> {
+        let mounts = self.read("proc/mounts").unwrap_or_default();
+        let mut result: Vec<Mount> = vec![];
+        for line in mounts.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [device, mount_point, fs_type, ..] = fields[..] else {
+                continue;
+            };
+            if !device.starts_with("/dev/") || fs_type == "squashfs" {
+                continue;
+            }
+            // bind mounts show up as the same device again
+            if result.iter().any(|mount| mount.device == device) {
+                continue;
+            }
+            result.push(Mount {
+                device: device.to_string(),
+                mount_point: unescape_mount_point(mount_point),
+            });
+        }
+        result
+    }
+
+    pub fn temperatures(&self) -> Vec<Temperature> {
+        let mut temperatures = vec![];
+        let Ok(hwmons) = fs::read_dir(self.root.join("sys/class/hwmon")) else {
+            return temperatures;
+        };
+        let mut hwmons: Vec<PathBuf> = hwmons.filter_map(|entry| Some(entry.ok()?.path())).collect();
+        hwmons.sort();
+        for hwmon in hwmons {
+            let chip = fs::read_to_string(hwmon.join("name")).unwrap_or_else(|_| "hwmon".to_string());
+            let chip = chip.trim();
+            let Ok(files) = fs::read_dir(&hwmon) else { continue };
+            let mut inputs: Vec<String> = files
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .filter(|name| name.starts_with("temp") && name.ends_with("_input"))
+                .collect();
+            inputs.sort();
+            for input in inputs {
+                let sensor = input.trim_end_matches("_input");
+                let Some(millidegrees) = read_number(&hwmon.join(&input)) else {
+                    continue;
+                };
+                let label = fs::read_to_string(hwmon.join(format!("{}_label", sensor)))
+                    .map(|label| label.trim().to_string())
+                    .unwrap_or_else(|_| sensor.to_string());
+                temperatures.push(Temperature {
+                    unique_id: format!("temperature_{}_{}", slug(chip), slug(&label)),
+                    name: format!("Temperature {} {}", chip, label),
+                    celsius: round(millidegrees / 1000.0, 1),
+                });
+            }
+        }
+        temperatures
+    }
+
+    pub fn sensors(&self) -> Vec<Sensor> {
+        let mut sensors = vec![
+            percent_sensor("cpu_usage", "CPU Usage", "mdi:cpu-64-bit"),
+            sensor("load_1m", "Load (1m)", "mdi:chip"),
+            sensor("load_5m", "Load (5m)", "mdi:chip"),
+            sensor("load_15m", "Load (15m)", "mdi:chip"),
+            percent_sensor("memory_usage", "Memory Usage", "mdi:memory"),
+            percent_sensor("swap_usage", "Swap Usage", "mdi:memory"),
+            Sensor {
+                device_class: Some("duration".to_string()),
+                unit_of_measurement: Some("h".to_string()),
+                state_class: Some("total_increasing".to_string()),
+                ..sensor("uptime", "Uptime", "mdi:timer-outline")
+            },
+            Sensor {
+                device_class: Some("timestamp".to_string()),
+                state_class: None,
+                ..sensor("last_boot", "Last Boot", "mdi:restart")
+            },
+        ];
+        sensors.extend(self.mounts().iter().map(disk_sensor));
+        sensors.extend(self.temperatures().iter().map(temperature_sensor));
+        sensors
+    }
+
+    /// Reads every sensor; cpu usage needs the cpu times of the previous reading.
+    pub fn sensor_states(&self, previous_cpu: Option<CpuTimes>) -> (Vec<SensorState>, Option<CpuTimes>) {
+        let cpu = self.cpu_times();
+        let mut values: HashMap<String, Value> = HashMap::new();
+        let mut attributes: HashMap<String, Value> = HashMap::new();
+
+        if let (Some(previous), Some(current)) = (previous_cpu, cpu) {
+            if let Some(usage) = cpu_usage(previous, current) {
+                values.insert("cpu_usage".to_string(), json!(usage));
+            }
+        }
+        if let Some([load_1m, load_5m, load_15m]) = self.load_averages() {
+            values.insert("load_1m".to_string(), json!(load_1m));
+            values.insert("load_5m".to_string(), json!(load_5m));
+            values.insert("load_15m".to_string(), json!(load_15m));
+        }
+        let meminfo = self.meminfo();
+        for (unique_id, total, available) in [
+            ("memory_usage", "MemTotal", "MemAvailable"),
+            ("swap_usage", "SwapTotal", "SwapFree"),
+        ] {
+            let (Some(&total), Some(&available)) = (meminfo.get(total), meminfo.get(available)) else {
+                continue;
+            };
+            let usage = if total == 0 {
+                0.0
+            } else {
+                round(100.0 * total.saturating_sub(available) as f64 / total as f64, 1)
+            };
+            values.insert(unique_id.to_string(), json!(usage));
+            attributes.insert(
+                unique_id.to_string(),
+                json!({ "total_mib": total / 1024, "available_mib": available / 1024 }),
+            );
+        }
+        if let Some(uptime) = self.uptime() {
+            values.insert("uptime".to_string(), json!(round(uptime / 3600.0, 1)));
+        }
+        if let Some(boot) = self.boot_time().and_then(|boot| DateTime::from_timestamp(boot, 0)) {
+            values.insert("last_boot".to_string(), json!(boot.to_rfc3339()));
+        }
+        for mount in self.mounts() {
+            let Some((usage, total_gib, free_gib)) = disk_usage(Path::new(&mount.mount_point)) else {
+                continue;
+            };
+            let unique_id = disk_sensor(&mount).state.unique_id;
+            values.insert(unique_id.clone(), json!(usage));
+            attributes.insert(
+                unique_id,
+                json!({ "device": mount.device, "total_gib": total_gib, "free_gib": free_gib }),
+            );
+        }
+        for temperature in self.temperatures() {
+            values.insert(temperature.unique_id, json!(temperature.celsius));
+        }
+
+        let states = self
+            .sensors()
+            .into_iter()
+            .filter_map(|sensor| {
+                let mut state = sensor.state;
+                state.value = values.remove(&state.unique_id)?;
+                if let Some(Value::Object(attributes)) = attributes.remove(&state.unique_id) {
+                    state.attributes = attributes;
+                }
+                Some(state)
+            })
+            .collect();
+        (states, cpu)
+    }
+}
+
+fn read_number(path: &Path) -> Option<f64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Usage like `df` reports it, relative to what non-root users can use.
+fn disk_usage(mount_point: &Path) -> Option<(f64, f64, f64)> {
+    let stat = statvfs(mount_point).ok()?;
+    let fragment = stat.fragment_size() as f64;
+    let used = stat.blocks().saturating_sub(stat.blocks_free()) as f64;
+    let available = stat.blocks_available() as f64;
+    if used + available == 0.0 {
+        return None;
+    }
+    let gib = |blocks: f64| round(blocks * fragment / (1024.0 * 1024.0 * 1024.0), 1);
+    Some((
+        round(100.0 * used / (used + available), 1),
+        gib(stat.blocks() as f64),
+        gib(available),
+    ))
+}
+
+fn is_significant(last: Option<&SensorState>, state: &SensorState, threshold: f64) -> bool {
+    let Some(last) = last else {
+        return true;
+    };
+    match (last.value.as_f64(), state.value.as_f64()) {
+        (Some(last_value), Some(value)) if threshold > 0.0 => (value - last_value).abs() >= threshold,
+        _ => last.value != state.value || last.attributes.keys().ne(state.attributes.keys()),
+    }
+}
+
+/// Filters out changes that are below the configured thresholds, remembering what was sent.
+pub fn significant_changes(
+    config: &SystemConfig,
+    last_sent: &mut HashMap<String, SensorState>,
+    states: Vec<SensorState>,
+) -> Vec<SensorState> {
+    let changed: Vec<SensorState> = states
+        .into_iter()
+        .filter(|state| {
+            is_significant(
+                last_sent.get(&state.unique_id),
+                state,
+                config.threshold(&state.unique_id),
+            )
+        })
+        .collect();
+    for state in &changed {
+        last_sent.insert(state.unique_id.clone(), state.clone());
+    }
+    changed
+}
+
+pub async fn start(sensor_tx: SensorSender, config: SystemConfig) {
+    if !config.enabled {
+        return;
+    }
+    let reader = SystemReader::new("/");
+    let mut ticks = interval(Duration::from_secs(config.interval.max(1)));
+    let mut last_sent = HashMap::new();
+    let mut cpu = None;
+    loop {
+        ticks.tick().await;
+        let (states, cpu_times) = reader.sensor_states(cpu);
+        cpu = cpu_times;
+        let changed = significant_changes(&config, &mut last_sent, states);
+        if !changed.is_empty() {
+            sensor_tx.send(changed).expect("Unable to send");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::{tempdir, TempDir};
+
+    fn fixture() -> TempDir {
+        let root = tempdir().unwrap();
+        let write = |path: &str, content: &str| {
+            let path = root.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        };
+        write(
+            "proc/stat",
+            "cpu  100 0 100 700 100 0 0 0 0 0\ncpu0 100 0 100 700 100 0 0 0 0 0\nbtime 1700000000\n",
+        );
+        write("proc/loadavg", "0.52 0.58 0.59 1/467 12345\n");
+        write(
+            "proc/meminfo",
+            "MemTotal:       16000000 kB\nMemFree:         1000000 kB\nMemAvailable:    4000000 kB\nSwapTotal:             0 kB\nSwapFree:              0 kB\n",
+        );
+        write("proc/uptime", "7200.00 14000.00\n");
+        write(
+            "proc/mounts",
+            &format!(
+                "proc /proc proc rw 0 0\n/dev/nvme0n1p2 {} ext4 rw 0 0\n/dev/nvme0n1p2 /bind ext4 rw 0 0\n/dev/loop0 /snap/core squashfs ro 0 0\n",
+                root.path().display()
+            ),
+        );
+        write("sys/class/hwmon/hwmon0/name", "coretemp\n");
+        write("sys/class/hwmon/hwmon0/temp1_input", "45500\n");
+        write("sys/class/hwmon/hwmon0/temp1_label", "Package id 0\n");
+        write("sys/class/hwmon/hwmon1/name", "nvme\n");
+        write("sys/class/hwmon/hwmon1/temp1_input", "38850\n");
+        root
+    }
+
+    fn value(states: &[SensorState], unique_id: &str) -> Value {
+        states
+            .iter()
+            .find(|state| state.unique_id == unique_id)
+            .map(|state| state.value.clone())
+            .unwrap_or(Value::Null)
+    }
+
+    #[test]
+    fn test_sensors_from_fixture() {
+        let root = fixture();
+        let reader = SystemReader::new(root.path());
+
+        let sensors = reader.sensors();
+        let unique_ids: Vec<&str> = sensors.iter().map(|sensor| sensor.state.unique_id.as_str()).collect();
+
+        assert!(unique_ids.contains(&"cpu_usage"));
+        assert!(unique_ids.contains(&format!("disk_usage_{}", slug(&root.path().display().to_string())).as_str()));
+        assert!(unique_ids.contains(&"temperature_coretemp_package_id_0"));
+        assert!(unique_ids.contains(&"temperature_nvme_temp1"));
+        assert_eq!(reader.mounts().len(), 1);
+    }
+
+    #[test]
+    fn test_sensor_states_from_fixture() {
+        let root = fixture();
+        let reader = SystemReader::new(root.path());
+        let previous = CpuTimes { idle: 400, total: 500 };
+
+        let (states, cpu) = reader.sensor_states(Some(previous));
+
+        assert_eq!(cpu, Some(CpuTimes { idle: 800, total: 1000 }));
+        assert_eq!(value(&states, "cpu_usage"), json!(20.0));
+        assert_eq!(value(&states, "load_5m"), json!(0.58));
+        assert_eq!(value(&states, "memory_usage"), json!(75.0));
+        assert_eq!(value(&states, "swap_usage"), json!(0.0));
+        assert_eq!(value(&states, "uptime"), json!(2.0));
+        assert_eq!(value(&states, "last_boot"), json!("2023-11-14T22:13:20+00:00"));
+        assert_eq!(value(&states, "temperature_coretemp_package_id_0"), json!(45.5));
+    }
+
+    #[test]
+    fn test_cpu_usage_needs_previous_reading() {
+        let root = fixture();
+        let (states, _) = SystemReader::new(root.path()).sensor_states(None);
+
+        assert_eq!(value(&states, "cpu_usage"), Value::Null);
+    }
+
+    #[test]
+    fn test_significant_changes() {
+        let config = SystemConfig::default();
+        let mut last_sent = HashMap::new();
+        let state = |unique_id: &str, value: f64| SensorState {
+            value: json!(value),
+            unique_id: unique_id.to_string(),
+            ..Default::default()
+        };
+
+        let first = significant_changes(&config, &mut last_sent, vec![state("cpu_usage", 10.0)]);
+        let small = significant_changes(&config, &mut last_sent, vec![state("cpu_usage", 10.5)]);
+        let large = significant_changes(&config, &mut last_sent, vec![state("cpu_usage", 11.2)]);
+
+        assert_eq!(first.len(), 1);
+        assert!(small.is_empty());
+        assert_eq!(large.len(), 1);
+        assert_eq!(last_sent["cpu_usage"].value, json!(11.2));
+    }
+
+    #[test]
+    fn test_unescape_mount_point() {
+        assert_eq!(unescape_mount_point("/media/My\\040Disk"), "/media/My Disk");
+        assert_eq!(slug("/media/My Disk"), "media_my_disk");
+        assert_eq!(slug("/"), "root");
+    }
+}
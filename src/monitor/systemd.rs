@@ -0,0 +1,426 @@
+// systemd D-Bus API -- https://www.freedesktop.org/software/systemd/man/latest/org.freedesktop.systemd1.html
+use anyhow::{anyhow, Error};
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::select;
+use tokio::sync::broadcast::Receiver;
+use zbus::message::Type;
+use zbus::proxy::CacheProperties;
+use zbus::zvariant::OwnedObjectPath;
+use zbus::{Connection, MatchRule, MessageStream};
+
+use crate::agent_state::{Sensor, SensorState};
+use crate::connection::Command;
+use crate::monitor::system::slug;
+use crate::monitor::{send_changes, SensorSender};
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Manager {
+    #[default]
+    System,
+    User,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Start,
+    Stop,
+    Restart,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UnitConfig {
+    /// e.g. jellyfin.service
+    pub name: String,
+    #[serde(default)]
+    pub manager: Manager,
+    /// what Home Assistant may do with the unit, nothing unless listed
+    #[serde(default)]
+    pub actions: Vec<Action>,
+}
+
+impl UnitConfig {
+    fn unique_id(&self) -> String {
+        let manager = match self.manager {
+            Manager::System => "",
+            Manager::User => "user_",
+        };
+        format!("systemd_{}{}", manager, slug(&self.name))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SystemdConfig {
+    pub enabled: bool,
+    pub units: Vec<UnitConfig>,
+}
+
+impl Default for SystemdConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            units: vec![],
+        }
+    }
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.systemd1.Manager",
+    default_service = "org.freedesktop.systemd1",
+    default_path = "/org/freedesktop/systemd1"
+)]
+trait SystemdManager {
+    fn subscribe(&self) -> zbus::Result<()>;
+    fn load_unit(&self, name: &str) -> zbus::Result<OwnedObjectPath>;
+    fn start_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+    fn stop_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+    fn restart_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.systemd1.Unit",
+    default_service = "org.freedesktop.systemd1"
+)]
+trait SystemdUnit {
+    #[zbus(property)]
+    fn active_state(&self) -> zbus::Result<String>;
+    #[zbus(property)]
+    fn sub_state(&self) -> zbus::Result<String>;
+    #[zbus(property)]
+    fn load_state(&self) -> zbus::Result<String>;
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UnitState {
+    /// active, reloading, inactive, failed, activating or deactivating
+    pub active_state: String,
+    /// unit type specific, e.g. running, exited or dead for services
+    pub sub_state: String,
+    /// loaded, not-found, masked and so on
+    pub load_state: String,
+}
+
+/// The bus connections of the system manager and the user's own manager.
+pub struct Managers {
+    pub system: Option<Connection>,
+    pub user: Option<Connection>,
+}
+
+impl Managers {
+    fn get(&self, manager: Manager) -> Option<&Connection> {
+        match manager {
+            Manager::System => self.system.as_ref(),
+            Manager::User => self.user.as_ref(),
+        }
+    }
+}
+
+pub async fn read_unit(connection: &Connection, name: &str) -> zbus::Result<UnitState> {
+    // unlike GetUnit, LoadUnit also works for units that aren't loaded, e.g. stopped ones
+    let path = SystemdManagerProxy::new(connection).await?.load_unit(name).await?;
+    let unit = SystemdUnitProxy::builder(connection)
+        .path(path)?
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?;
+    Ok(UnitState {
+        active_state: unit.active_state().await?,
+        sub_state: unit.sub_state().await?,
+        load_state: unit.load_state().await?,
+    })
+}
+
+pub fn sensors(config: &SystemdConfig) -> Vec<Sensor> {
+    config
+        .units
+        .iter()
+        .flat_map(|unit| {
+            let sensor = |suffix: &str, name: String, icon: &str| Sensor {
+                name,
+                state: SensorState {
+                    unique_id: format!("{}{}", unit.unique_id(), suffix),
+                    sensor_type: "sensor".to_string(),
+                    icon: icon.to_string(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            [
+                sensor("", format!("{} State", unit.name), "mdi:cog"),
+                sensor("_sub_state", format!("{} Sub-State", unit.name), "mdi:cog-outline"),
+            ]
+        })
+        .collect()
+}
+
+pub fn sensor_states(config: &SystemdConfig, units: &[Option<UnitState>]) -> Vec<SensorState> {
+    let mut states: Vec<SensorState> = sensors(config).into_iter().map(|sensor| sensor.state).collect();
+    for ((unit, state), pair) in config.units.iter().zip(units).zip(states.chunks_mut(2)) {
+        let actions: Vec<_> = unit
+            .actions
+            .iter()
+            .map(|action| format!("{:?}", action).to_lowercase())
+            .collect();
+        for sensor_state in pair.iter_mut() {
+            sensor_state.attributes.insert("unit".to_string(), json!(unit.name));
+            sensor_state.attributes.insert(
+                "manager".to_string(),
+                json!(format!("{:?}", unit.manager).to_lowercase()),
+            );
+        }
+        pair[0].attributes.insert("actions".to_string(), json!(actions));
+        if let Some(state) = state {
+            pair[0].value = json!(state.active_state);
+            pair[0]
+                .attributes
+                .insert("load_state".to_string(), json!(state.load_state));
+            pair[1].value = json!(state.sub_state);
+        }
+    }
+    states
+}
+
+async fn read_units(managers: &Managers, config: &SystemdConfig) -> Vec<Option<UnitState>> {
+    let mut units = vec![];
+    for unit in &config.units {
+        let state = match managers.get(unit.manager) {
+            Some(connection) => match read_unit(connection, &unit.name).await {
+                Ok(state) => Some(state),
+                Err(e) => {
+                    println!("Failed to read {}: {}", unit.name, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        units.push(state);
+    }
+    units
+}
+
+/// Handles a `command_systemd` notification, e.g.
+/// `{"message": "command_systemd", "data": {"unit": "jellyfin.service", "action": "restart"}}`.
+/// Only configured units can be controlled, and only with the actions listed for them. A unit
+/// configured for both managers needs `"manager": "user"` or `"system"` to pick one. A notification
+/// rather than a button or switch, because mobile_app only registers sensors and binary sensors.
+pub async fn handle_command(managers: &Managers, config: &SystemdConfig, command: &Command) -> Result<(), Error> {
+    if command.message != "command_systemd" {
+        return Ok(());
+    }
+    let name = command.data["unit"].as_str().unwrap_or_default();
+    let action: Action = serde_json::from_value(command.data["action"].clone())?;
+    let manager: Option<Manager> = serde_json::from_value(command.data["manager"].clone())?;
+    let unit = config
+        .units
+        .iter()
+        .find(|unit| unit.name == name && manager.is_none_or(|manager| manager == unit.manager))
+        .ok_or_else(|| anyhow!("{} is not a configured unit", name))?;
+    if !unit.actions.contains(&action) {
+        return Err(anyhow!("{:?} is not allowed for {}", action, name));
+    }
+    let connection = managers
+        .get(unit.manager)
+        .ok_or_else(|| anyhow!("no connection to the {:?} manager", unit.manager))?;
+    let manager = SystemdManagerProxy::new(connection).await?;
+    match action {
+        Action::Start => manager.start_unit(name, "replace").await?,
+        Action::Stop => manager.stop_unit(name, "replace").await?,
+        Action::Restart => manager.restart_unit(name, "replace").await?,
+    };
+    Ok(())
+}
+
+async fn signal_stream(connection: &Connection) -> zbus::Result<MessageStream> {
+    // systemd only announces changes while somebody is subscribed
+    SystemdManagerProxy::new(connection).await?.subscribe().await?;
+    let rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .sender("org.freedesktop.systemd1")?
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .arg(0, "org.freedesktop.systemd1.Unit")?
+        .build();
+    MessageStream::for_match_rule(rule, connection, None).await
+}
+
+async fn connect(manager: Manager, config: &SystemdConfig) -> Option<Connection> {
+    if !config.units.iter().any(|unit| unit.manager == manager) {
+        return None;
+    }
+    let connection = match manager {
+        Manager::System => Connection::system().await,
+        Manager::User => Connection::session().await,
+    };
+    connection
+        .map_err(|e| println!("Not monitoring {:?} units: {}", manager, e))
+        .ok()
+}
+
+pub async fn start(sensor_tx: SensorSender, mut commands: Receiver<Command>, config: SystemdConfig) {
+    if !config.enabled || config.units.is_empty() {
+        return;
+    }
+    let managers = Managers {
+        system: connect(Manager::System, &config).await,
+        user: connect(Manager::User, &config).await,
+    };
+    let mut streams = vec![];
+    for connection in managers.system.iter().chain(&managers.user) {
+        match signal_stream(connection).await {
+            Ok(stream) => streams.push(stream),
+            Err(e) => println!("Failed to subscribe to systemd: {}", e),
+        }
+    }
+    let mut signals = futures::stream::select_all(streams);
+    let mut last_states = vec![];
+
+    send_changes(
+        &sensor_tx,
+        &mut last_states,
+        sensor_states(&config, &read_units(&managers, &config).await),
+    );
+    loop {
+        select! {
+            Some(_) = signals.next() => {},
+            Ok(command) = commands.recv() => {
+                if let Err(e) = handle_command(&managers, &config, &command).await {
+                    println!("Failed to handle systemd command: {}", e);
+                }
+                continue;
+            },
+        }
+        send_changes(
+            &sensor_tx,
+            &mut last_states,
+            sensor_states(&config, &read_units(&managers, &config).await),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::testing::start_bus;
+    use std::sync::{Arc, Mutex};
+
+    const UNIT_PATH: &str = "/org/freedesktop/systemd1/unit/jellyfin_2eservice";
+
+    struct FakeManager {
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.systemd1.Manager")]
+    impl FakeManager {
+        fn subscribe(&self) {}
+
+        fn load_unit(&self, _name: &str) -> OwnedObjectPath {
+            OwnedObjectPath::try_from(UNIT_PATH).unwrap()
+        }
+
+        fn restart_unit(&self, name: &str, mode: &str) -> OwnedObjectPath {
+            self.calls.lock().unwrap().push(format!("restart {} {}", name, mode));
+            OwnedObjectPath::try_from("/org/freedesktop/systemd1/job/1").unwrap()
+        }
+    }
+
+    struct FakeUnit;
+
+    #[zbus::interface(name = "org.freedesktop.systemd1.Unit")]
+    impl FakeUnit {
+        #[zbus(property)]
+        fn active_state(&self) -> String {
+            "active".to_string()
+        }
+
+        #[zbus(property)]
+        fn sub_state(&self) -> String {
+            "running".to_string()
+        }
+
+        #[zbus(property)]
+        fn load_state(&self) -> String {
+            "loaded".to_string()
+        }
+    }
+
+    fn config() -> SystemdConfig {
+        toml::from_str(
+            r#"
+            [[units]]
+            name = "jellyfin.service"
+            actions = ["restart"]
+
+            [[units]]
+            name = "syncthing.service"
+            manager = "user"
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn command(data: serde_json::Value) -> Command {
+        Command {
+            message: "command_systemd".to_string(),
+            data,
+        }
+    }
+
+    #[test]
+    fn test_sensors() {
+        let sensors = sensors(&config());
+
+        assert_eq!(sensors.len(), 4);
+        assert_eq!(sensors[0].state.unique_id, "systemd_jellyfin_service");
+        assert_eq!(sensors[3].state.unique_id, "systemd_user_syncthing_service_sub_state");
+    }
+
+    #[tokio::test]
+    async fn test_read_and_control_units() {
+        let Some((_bus, address)) = start_bus() else {
+            return;
+        };
+        let calls = Arc::new(Mutex::new(vec![]));
+        let _systemd = zbus::connection::Builder::address(address.as_str())
+            .unwrap()
+            .name("org.freedesktop.systemd1")
+            .unwrap()
+            .serve_at("/org/freedesktop/systemd1", FakeManager { calls: calls.clone() })
+            .unwrap()
+            .serve_at(UNIT_PATH, FakeUnit)
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let connection = zbus::connection::Builder::address(address.as_str())
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let config = config();
+        let managers = Managers {
+            system: Some(connection),
+            user: None,
+        };
+
+        let states = sensor_states(&config, &read_units(&managers, &config).await);
+        assert_eq!(states[0].value, json!("active"));
+        assert_eq!(states[0].attributes["actions"], json!(["restart"]));
+        assert_eq!(states[1].value, json!("running"));
+        assert_eq!(states[2].value, serde_json::Value::Null);
+
+        let restart = command(json!({ "unit": "jellyfin.service", "action": "restart" }));
+        handle_command(&managers, &config, &restart).await.unwrap();
+        assert_eq!(*calls.lock().unwrap(), vec!["restart jellyfin.service replace"]);
+
+        let stop = command(json!({ "unit": "jellyfin.service", "action": "stop" }));
+        assert!(handle_command(&managers, &config, &stop).await.is_err());
+        let unknown = command(json!({ "unit": "sshd.service", "action": "restart" }));
+        assert!(handle_command(&managers, &config, &unknown).await.is_err());
+        assert_eq!(calls.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,434 @@
+use std::io;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use nix::libc;
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::process::Command;
+use tokio::select;
+use tokio::sync::broadcast::Receiver;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, timeout};
+
+use crate::agent_state::{Sensor, SensorState};
+use crate::connection;
+use crate::monitor::{send_changes, SensorSender};
+
+// mirrors can be slow, but a check that hangs shouldn't block the next one forever
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+// an update waiting for a password nobody enters gives up eventually, so the next one can run
+const UPDATE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+// keeps the attributes a reasonable size on a machine that hasn't been updated in a while
+const MAX_LISTED: usize = 100;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PackageManager {
+    Apt,
+    Dnf,
+    Pacman,
+    Flatpak,
+}
+
+const ALL: [PackageManager; 4] = [
+    PackageManager::Apt,
+    PackageManager::Dnf,
+    PackageManager::Pacman,
+    PackageManager::Flatpak,
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingUpdate {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl PackageManager {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PackageManager::Apt => "apt",
+            PackageManager::Dnf => "dnf",
+            PackageManager::Pacman => "pacman",
+            PackageManager::Flatpak => "flatpak",
+        }
+    }
+
+    /// The program and arguments that list the pending updates, none of which need root.
+    fn check_command(&self) -> (&'static str, &'static [&'static str]) {
+        match self {
+            PackageManager::Apt => ("apt", &["list", "--upgradable"]),
+            PackageManager::Dnf => ("dnf", &["check-update", "--quiet"]),
+            // from pacman-contrib, it syncs a copy of the database instead of the real one
+            PackageManager::Pacman => ("checkupdates", &[]),
+            PackageManager::Flatpak => ("flatpak", &["remote-ls", "--updates", "--columns=application,version"]),
+        }
+    }
+
+    fn is_installed(&self) -> bool {
+        let (program, _) = self.check_command();
+        std::env::var_os("PATH")
+            .is_some_and(|path| std::env::split_paths(&path).any(|dir| Path::new(&dir).join(program).is_file()))
+    }
+
+    pub fn parse(&self, output: &str) -> Vec<PendingUpdate> {
+        let lines = output.lines().map(str::trim).filter(|line| !line.is_empty());
+        match self {
+            // firefox/jammy-updates 125.0+build3-0ubuntu0.22.04.1 amd64 [upgradable from: 124.0]
+            PackageManager::Apt => lines
+                .filter(|line| line.contains("[upgradable from"))
+                .filter_map(|line| {
+                    let mut fields = line.split_whitespace();
+                    let name = fields.next()?.split('/').next()?;
+                    Some(PendingUpdate {
+                        name: name.to_string(),
+                        version: fields.next().map(str::to_string),
+                    })
+                })
+                .collect(),
+            // firefox.x86_64    125.0-1.fc40    updates
+            PackageManager::Dnf => lines
+                .take_while(|line| !line.starts_with("Obsoleting"))
+                .filter_map(|line| {
+                    let fields: Vec<&str> = line.split_whitespace().collect();
+                    let [package, version, _repository] = fields[..] else {
+                        return None;
+                    };
+                    let name = package.rsplit_once('.').map_or(package, |(name, _arch)| name);
+                    Some(PendingUpdate {
+                        name: name.to_string(),
+                        version: Some(version.to_string()),
+                    })
+                })
+                .collect(),
+            // firefox 124.0-1 -> 125.0-1
+            PackageManager::Pacman => lines
+                .filter_map(|line| {
+                    let mut fields = line.split_whitespace();
+                    let name = fields.next()?;
+                    Some(PendingUpdate {
+                        name: name.to_string(),
+                        version: fields.last().map(str::to_string),
+                    })
+                })
+                .collect(),
+            // org.mozilla.firefox	125.0
+            PackageManager::Flatpak => lines
+                .filter_map(|line| {
+                    let mut fields = line.split('\t');
+                    let name = fields.next()?.trim();
+                    Some(PendingUpdate {
+                        name: name.to_string(),
+                        version: fields
+                            .next()
+                            .map(str::trim)
+                            .filter(|version| !version.is_empty())
+                            .map(str::to_string),
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpdatesConfig {
+    pub enabled: bool,
+    /// seconds between checks
+    pub interval: u64,
+    /// the package managers to check, all installed ones when left out
+    pub managers: Option<Vec<PackageManager>>,
+    /// run with `sh -c` on a `command_update` notification, e.g. `pkexec apt-get upgrade -y`. Not a
+    /// button, mobile_app only registers sensors and binary sensors.
+    pub update_command: Option<String>,
+}
+
+impl Default for UpdatesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval: 6 * 60 * 60,
+            managers: None,
+            update_command: None,
+        }
+    }
+}
+
+impl UpdatesConfig {
+    pub fn managers(&self) -> Vec<PackageManager> {
+        match &self.managers {
+            Some(managers) => managers.clone(),
+            None => ALL.into_iter().filter(PackageManager::is_installed).collect(),
+        }
+    }
+}
+
+/// Checking for updates refreshes metadata and can take a while, so it shouldn't get in the way.
+fn low_priority(command: &mut Command) -> &mut Command {
+    // SAFETY: setpriority is async-signal-safe, and only touches the child about to exec
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setpriority(libc::PRIO_PROCESS, 0, 19) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        })
+    }
+}
+
+pub async fn check(manager: PackageManager) -> Result<Vec<PendingUpdate>, Error> {
+    let (program, args) = manager.check_command();
+    let mut command = Command::new(program);
+    command.args(args).stdin(Stdio::null()).kill_on_drop(true);
+    let output = timeout(CHECK_TIMEOUT, low_priority(&mut command).output())
+        .await
+        .map_err(|_| anyhow!("{} took too long", program))??;
+    // dnf exits with 100 and checkupdates with 2 to say there are or aren't any updates
+    let expected = match manager {
+        PackageManager::Dnf => [0, 100],
+        PackageManager::Pacman => [0, 2],
+        _ => [0, 0],
+    };
+    if !output.status.code().is_some_and(|code| expected.contains(&code)) {
+        return Err(anyhow!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(manager.parse(&String::from_utf8_lossy(&output.stdout)))
+}
+
+pub fn sensors() -> Vec<Sensor> {
+    vec![Sensor {
+        name: "Pending Updates".to_string(),
+        state: SensorState {
+            unique_id: "updates_pending".to_string(),
+            sensor_type: "sensor".to_string(),
+            icon: "mdi:package-up".to_string(),
+            ..Default::default()
+        },
+        state_class: Some("measurement".to_string()),
+        ..Default::default()
+    }]
+}
+
+/// The total count, with the packages per manager as attributes. Managers that couldn't be
+/// checked are left out of the count and listed under `failed`.
+pub fn sensor_states(results: &[(PackageManager, Result<Vec<PendingUpdate>, Error>)]) -> Vec<SensorState> {
+    let mut state = sensors().remove(0).state;
+    let mut total = 0;
+    let mut failed = vec![];
+    for (manager, result) in results {
+        match result {
+            Ok(updates) => {
+                total += updates.len();
+                let listed: Vec<String> = updates
+                    .iter()
+                    .take(MAX_LISTED)
+                    .map(|update| match &update.version {
+                        Some(version) => format!("{} {}", update.name, version),
+                        None => update.name.clone(),
+                    })
+                    .collect();
+                state.attributes.insert(manager.name().to_string(), json!(listed));
+            }
+            Err(_) => failed.push(manager.name()),
+        }
+    }
+    if failed.len() < results.len() {
+        state.value = json!(total);
+    }
+    if !failed.is_empty() {
+        state.attributes.insert("failed".to_string(), json!(failed));
+    }
+    vec![state]
+}
+
+type CheckResults = Vec<(PackageManager, Result<Vec<PendingUpdate>, Error>)>;
+
+async fn check_all(managers: Vec<PackageManager>) -> CheckResults {
+    let mut results = vec![];
+    for manager in managers {
+        let result = check(manager).await;
+        if let Err(e) = &result {
+            println!("Failed to check {} for updates: {}", manager.name(), e);
+        }
+        results.push((manager, result));
+    }
+    results
+}
+
+async fn run_update(update_command: String) -> Result<(), Error> {
+    let mut command = Command::new("sh");
+    command
+        .args(["-c", &update_command])
+        .stdin(Stdio::null())
+        // a group of its own, so a timeout also ends whatever the shell started, e.g. pkexec
+        .process_group(0)
+        .kill_on_drop(true);
+    let mut child = low_priority(&mut command).spawn()?;
+    let group = child.id().map(|pid| Pid::from_raw(pid as i32));
+    match timeout(UPDATE_TIMEOUT, child.wait()).await {
+        Ok(status) => {
+            let status = status?;
+            if !status.success() {
+                return Err(anyhow!("{} exited with {}", update_command, status));
+            }
+            Ok(())
+        }
+        Err(_) => {
+            if let Some(group) = group {
+                _ = killpg(group, Signal::SIGKILL);
+            }
+            Err(anyhow!(
+                "{} didn't finish within {} minutes",
+                update_command,
+                UPDATE_TIMEOUT.as_secs() / 60
+            ))
+        }
+    }
+}
+
+pub async fn start(sensor_tx: SensorSender, mut commands: Receiver<connection::Command>, config: UpdatesConfig) {
+    if !config.enabled {
+        return;
+    }
+    let managers = config.managers();
+    if managers.is_empty() {
+        println!("No package managers found, not checking for updates");
+        return;
+    }
+    let mut ticks = interval(Duration::from_secs(config.interval.max(60)));
+    let mut last_states = vec![];
+    // both run on their own, so commands are still read while a slow mirror is being checked
+    let mut update: Option<JoinHandle<Result<(), Error>>> = None;
+    let mut check: Option<JoinHandle<CheckResults>> = None;
+    // an update finished during a check, which may have seen the packages from before it
+    let mut recheck = false;
+    loop {
+        select! {
+            _ = ticks.tick() => {
+                if check.is_some() {
+                    continue;
+                }
+            },
+            Ok(command) = commands.recv() => {
+                if command.message != "command_update" {
+                    continue;
+                }
+                let Some(update_command) = &config.update_command else {
+                    println!("Ignoring command_update, there's no update_command configured");
+                    continue;
+                };
+                if update.is_some() {
+                    println!("Ignoring command_update, an update is running already");
+                    continue;
+                }
+                update = Some(tokio::spawn(run_update(update_command.clone())));
+                continue;
+            },
+            Some(result) = async { Some(update.as_mut()?.await) } => {
+                update = None;
+                match result {
+                    Ok(Ok(())) => println!("Updated"),
+                    Ok(Err(e)) => println!("Failed to update: {}", e),
+                    Err(e) => println!("Failed to update: {}", e),
+                }
+                if check.is_some() {
+                    recheck = true;
+                    continue;
+                }
+            },
+            Some(result) = async { Some(check.as_mut()?.await) } => {
+                check = None;
+                match result {
+                    Ok(results) => send_changes(&sensor_tx, &mut last_states, sensor_states(&results)),
+                    Err(e) => println!("Failed to check for updates: {}", e),
+                }
+                if !recheck {
+                    continue;
+                }
+                recheck = false;
+            },
+        }
+        check = Some(tokio::spawn(check_all(managers.clone())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn test_parse_apt() {
+        let output = "Listing...\n\
+                      firefox/jammy-updates 125.0+build3-0ubuntu0.22.04.1 amd64 [upgradable from: 124.0]\n\
+                      libc6/jammy-security 2.35-0ubuntu3.7 amd64 [upgradable from: 2.35-0ubuntu3.6]\n";
+
+        let updates = PackageManager::Apt.parse(output);
+
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].name, "firefox");
+        assert_eq!(updates[1].version.as_deref(), Some("2.35-0ubuntu3.7"));
+    }
+
+    #[test]
+    fn test_parse_dnf() {
+        let output = "\nfirefox.x86_64    125.0-1.fc40    updates\n\
+                      kernel-core.x86_64    6.8.7-300.fc40    updates\n\
+                      Obsoleting Packages\n\
+                      grub2-tools.x86_64    1:2.06-121.fc40    updates\n";
+
+        let updates = PackageManager::Dnf.parse(output);
+
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[1].name, "kernel-core");
+        assert_eq!(updates[1].version.as_deref(), Some("6.8.7-300.fc40"));
+    }
+
+    #[test]
+    fn test_parse_pacman_and_flatpak() {
+        let pacman = PackageManager::Pacman.parse("firefox 124.0-1 -> 125.0-1\nlinux 6.8.6 -> 6.8.7\n");
+        let flatpak = PackageManager::Flatpak.parse("org.mozilla.firefox\t125.0\norg.gtk.Gtk3theme.Adwaita\t\n");
+
+        assert_eq!(pacman[0].version.as_deref(), Some("125.0-1"));
+        assert_eq!(pacman.len(), 2);
+        assert_eq!(flatpak[0].name, "org.mozilla.firefox");
+        assert_eq!(flatpak[1].version, None);
+    }
+
+    #[test]
+    fn test_sensor_states() {
+        let updates = PackageManager::Pacman.parse("firefox 124.0-1 -> 125.0-1\nlinux 6.8.6 -> 6.8.7\n");
+        let results = vec![
+            (PackageManager::Pacman, Ok(updates)),
+            (PackageManager::Flatpak, Err(anyhow!("no remotes"))),
+        ];
+
+        let states = sensor_states(&results);
+
+        assert_eq!(states[0].value, json!(2));
+        assert_eq!(
+            states[0].attributes["pacman"],
+            json!(["firefox 125.0-1", "linux 6.8.7"])
+        );
+        assert_eq!(states[0].attributes["failed"], json!(["flatpak"]));
+        assert_eq!(
+            sensor_states(&[(PackageManager::Apt, Err(anyhow!("locked")))])[0].value,
+            Value::Null
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_update() {
+        assert!(run_update("true".to_string()).await.is_ok());
+        assert!(run_update("exit 100".to_string()).await.is_err());
+    }
+}
@@ -0,0 +1,369 @@
+// Sits between the monitors and the webhook, so a busy sensor can't flood Home Assistant's
+// recorder: changes are batched into one update_sensor_states call, throttled per sensor and
+// overall, and numeric ones that barely moved are dropped.
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::future::pending;
+
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::time::{sleep_until, Duration, Instant};
+
+use crate::agent_state::SensorState;
+
+// how long to hold off after Home Assistant couldn't be reached
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerConfig {
+    /// milliseconds to wait for more changes before sending them together
+    pub batch_window_ms: u64,
+    /// states sent per minute at most, 0 for no limit. Binary sensors go first.
+    pub max_per_minute: u32,
+    /// seconds between two states of the same sensor, unless overridden in `sensors`
+    pub min_interval: u64,
+    /// how much a numeric state has to move before it's sent, unless overridden in `sensors`
+    pub deadband: f64,
+    /// by unique id
+    pub sensors: HashMap<String, SensorSchedule>,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            batch_window_ms: 500,
+            max_per_minute: 120,
+            min_interval: 0,
+            deadband: 0.0,
+            sensors: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SensorSchedule {
+    pub min_interval: Option<u64>,
+    pub deadband: Option<f64>,
+}
+
+impl SchedulerConfig {
+    fn min_interval(&self, unique_id: &str) -> Duration {
+        let seconds = self.sensors.get(unique_id).and_then(|sensor| sensor.min_interval);
+        Duration::from_secs(seconds.unwrap_or(self.min_interval))
+    }
+
+    fn deadband(&self, unique_id: &str) -> f64 {
+        let deadband = self.sensors.get(unique_id).and_then(|sensor| sensor.deadband);
+        deadband.unwrap_or(self.deadband)
+    }
+}
+
+struct Pending {
+    state: SensorState,
+    queued_at: Instant,
+}
+
+struct Sent {
+    state: SensorState,
+    at: Instant,
+}
+
+pub struct Scheduler {
+    config: SchedulerConfig,
+    pending: HashMap<String, Pending>,
+    sent: HashMap<String, Sent>,
+    /// what's left of the rate limit, refilled continuously up to `max_per_minute`
+    tokens: f64,
+    refilled_at: Instant,
+    retry_at: Option<Instant>,
+}
+
+/// Whether `new` is the same as `old` but for a number that moved less than `deadband`.
+fn within_deadband(old: &SensorState, new: &SensorState, deadband: f64) -> bool {
+    if deadband <= 0.0 || old.attributes != new.attributes || old.icon != new.icon {
+        return false;
+    }
+    let number = |value: &Value| match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(string) => string.parse::<f64>().ok(),
+        _ => None,
+    };
+    match (number(&old.value), number(&new.value)) {
+        (Some(old), Some(new)) => (new - old).abs() < deadband,
+        _ => false,
+    }
+}
+
+impl Scheduler {
+    pub fn new(config: SchedulerConfig, now: Instant) -> Self {
+        Self {
+            tokens: config.max_per_minute as f64,
+            config,
+            pending: HashMap::new(),
+            sent: HashMap::new(),
+            refilled_at: now,
+            retry_at: None,
+        }
+    }
+
+    /// Applies a new config, keeping what's pending and when each sensor was last sent.
+    pub fn reconfigure(&mut self, config: SchedulerConfig) {
+        self.tokens = self.tokens.min(config.max_per_minute as f64);
+        self.config = config;
+    }
+
+    /// Queues states to be sent, replacing older ones of the same sensors that are still waiting.
+    pub fn push(&mut self, states: Vec<SensorState>, now: Instant) {
+        for state in states {
+            let unique_id = state.unique_id.clone();
+            let deadband = self.config.deadband(&unique_id);
+            if let Some(sent) = self.sent.get(&unique_id) {
+                if sent.state == state || within_deadband(&sent.state, &state, deadband) {
+                    // back to (about) what Home Assistant has, whatever was waiting is moot
+                    self.pending.remove(&unique_id);
+                    continue;
+                }
+            }
+            let queued_at = self.pending.get(&unique_id).map_or(now, |pending| pending.queued_at);
+            self.pending.insert(unique_id, Pending { state, queued_at });
+        }
+    }
+
+    /// When a pending state is past its sensor's minimum interval.
+    fn eligible_at(&self, pending: &Pending) -> Instant {
+        let unique_id = &pending.state.unique_id;
+        match self.sent.get(unique_id) {
+            Some(sent) => pending.queued_at.max(sent.at + self.config.min_interval(unique_id)),
+            None => pending.queued_at,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let max = self.config.max_per_minute as f64;
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * max / 60.0).min(max);
+        self.refilled_at = now;
+    }
+
+    /// When the next batch should be sent, if there's anything to send.
+    pub fn due(&self) -> Option<Instant> {
+        let window = Duration::from_millis(self.config.batch_window_ms);
+        let mut due = self.pending.values().map(|pending| self.eligible_at(pending)).min()? + window;
+        if let Some(retry_at) = self.retry_at {
+            due = due.max(retry_at);
+        }
+        let max = self.config.max_per_minute as f64;
+        if max > 0.0 && self.tokens < 1.0 {
+            let refill = Duration::from_secs_f64((1.0 - self.tokens) * 60.0 / max);
+            due = due.max(self.refilled_at + refill);
+        }
+        Some(due)
+    }
+
+    /// Waits for the next batch to be due, forever if nothing is pending.
+    pub async fn wait(&self) {
+        match self.due() {
+            Some(due) => sleep_until(due).await,
+            None => pending().await,
+        }
+    }
+
+    /// Takes the states that can be sent now, binary sensors first, then the longest waiting.
+    pub fn take(&mut self, now: Instant) -> Vec<SensorState> {
+        if self.retry_at.is_some_and(|retry_at| retry_at > now) {
+            return vec![];
+        }
+        self.retry_at = None;
+        self.refill(now);
+        let mut ready: Vec<&Pending> = self
+            .pending
+            .values()
+            .filter(|pending| self.eligible_at(pending) <= now)
+            .collect();
+        ready.sort_by_key(|pending| (Reverse(pending.state.sensor_type == "binary_sensor"), pending.queued_at));
+        if self.config.max_per_minute > 0 {
+            ready.truncate(self.tokens.max(0.0) as usize);
+        }
+        let unique_ids: Vec<String> = ready.iter().map(|pending| pending.state.unique_id.clone()).collect();
+        let states: Vec<SensorState> = unique_ids
+            .iter()
+            .filter_map(|unique_id| self.pending.remove(unique_id))
+            .map(|pending| pending.state)
+            .collect();
+        if self.config.max_per_minute > 0 {
+            self.tokens -= states.len() as f64;
+        }
+        states
+    }
+
+    /// Remembers taken states Home Assistant got, for the minimum intervals and deadbands.
+    pub fn sent(&mut self, states: &[SensorState], now: Instant) {
+        for state in states {
+            let sent = Sent {
+                state: state.clone(),
+                at: now,
+            };
+            self.sent.insert(state.unique_id.clone(), sent);
+        }
+    }
+
+    /// Queues taken states again after they couldn't be sent, unless newer ones came in, and holds
+    /// off for a while.
+    pub fn failed(&mut self, states: Vec<SensorState>, now: Instant) {
+        for state in states {
+            self.pending
+                .entry(state.unique_id.clone())
+                .or_insert(Pending { state, queued_at: now });
+        }
+        self.retry_at = Some(now + RETRY_DELAY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn state(unique_id: &str, sensor_type: &str, value: Value) -> SensorState {
+        SensorState {
+            value,
+            unique_id: unique_id.to_string(),
+            sensor_type: sensor_type.to_string(),
+            icon: "mdi:chip".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn config() -> SchedulerConfig {
+        SchedulerConfig {
+            batch_window_ms: 500,
+            max_per_minute: 0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_batching() {
+        let start = Instant::now();
+        let mut scheduler = Scheduler::new(config(), start);
+        assert_eq!(scheduler.due(), None);
+
+        scheduler.push(vec![state("cpu_usage", "sensor", json!(10))], start);
+        scheduler.push(
+            vec![
+                state("cpu_usage", "sensor", json!(12)),
+                state("memory_usage", "sensor", json!(40)),
+            ],
+            start + Duration::from_millis(200),
+        );
+        assert_eq!(scheduler.due(), Some(start + Duration::from_millis(500)));
+
+        let mut batch = scheduler.take(start + Duration::from_millis(500));
+        batch.sort_by(|a, b| a.unique_id.cmp(&b.unique_id));
+        assert_eq!(
+            batch,
+            vec![
+                state("cpu_usage", "sensor", json!(12)),
+                state("memory_usage", "sensor", json!(40))
+            ]
+        );
+        assert_eq!(scheduler.due(), None);
+    }
+
+    #[test]
+    fn test_min_interval_and_deadband() {
+        let start = Instant::now();
+        let mut config = config();
+        config.deadband = 1.0;
+        config.sensors.insert(
+            "cpu_usage".to_string(),
+            SensorSchedule {
+                min_interval: Some(30),
+                deadband: Some(5.0),
+            },
+        );
+        let mut scheduler = Scheduler::new(config, start);
+        scheduler.push(vec![state("cpu_usage", "sensor", json!(10))], start);
+        let batch = scheduler.take(start + Duration::from_secs(1));
+        scheduler.sent(&batch, start + Duration::from_secs(1));
+
+        // too close to what was sent
+        scheduler.push(
+            vec![state("cpu_usage", "sensor", json!(14.5))],
+            start + Duration::from_secs(2),
+        );
+        assert_eq!(scheduler.due(), None);
+
+        // far enough, but has to wait for the minimum interval, and is replaced meanwhile
+        scheduler.push(
+            vec![state("cpu_usage", "sensor", json!(20))],
+            start + Duration::from_secs(3),
+        );
+        scheduler.push(
+            vec![state("cpu_usage", "sensor", json!(25))],
+            start + Duration::from_secs(4),
+        );
+        assert_eq!(scheduler.take(start + Duration::from_secs(5)), vec![]);
+        assert_eq!(scheduler.due(), Some(start + Duration::from_millis(31_500)));
+        assert_eq!(
+            scheduler.take(start + Duration::from_millis(31_500)),
+            vec![state("cpu_usage", "sensor", json!(25))]
+        );
+
+        // strings aren't numbers
+        scheduler.push(vec![state("active_window", "sensor", json!("firefox"))], start);
+        let batch = scheduler.take(start + Duration::from_secs(1));
+        scheduler.sent(&batch, start);
+        scheduler.push(vec![state("active_window", "sensor", json!("kitty"))], start);
+        assert!(scheduler.due().is_some());
+    }
+
+    #[test]
+    fn test_rate_limit_prefers_binary_sensors() {
+        let start = Instant::now();
+        let mut config = config();
+        config.max_per_minute = 2;
+        let mut scheduler = Scheduler::new(config, start);
+        scheduler.push(
+            vec![
+                state("cpu_usage", "sensor", json!(10)),
+                state("memory_usage", "sensor", json!(40)),
+            ],
+            start,
+        );
+        scheduler.push(
+            vec![state("webcam", "binary_sensor", json!(true))],
+            start + Duration::from_millis(100),
+        );
+
+        let batch = scheduler.take(start + Duration::from_secs(1));
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch[0], state("webcam", "binary_sensor", json!(true)));
+
+        // one state every 30 seconds from now on
+        assert_eq!(scheduler.due(), Some(start + Duration::from_secs(31)));
+        assert_eq!(scheduler.take(start + Duration::from_secs(31)).len(), 1);
+    }
+
+    #[test]
+    fn test_failed() {
+        let start = Instant::now();
+        let mut scheduler = Scheduler::new(config(), start);
+        scheduler.push(vec![state("webcam", "binary_sensor", json!(true))], start);
+        let batch = scheduler.take(start + Duration::from_secs(1));
+        scheduler.push(
+            vec![state("webcam", "binary_sensor", json!(false))],
+            start + Duration::from_secs(2),
+        );
+        scheduler.failed(batch, start + Duration::from_secs(2));
+
+        assert_eq!(scheduler.take(start + Duration::from_secs(3)), vec![]);
+        assert_eq!(scheduler.due(), Some(start + Duration::from_secs(2) + RETRY_DELAY));
+        assert_eq!(
+            scheduler.take(start + Duration::from_secs(2) + RETRY_DELAY),
+            vec![state("webcam", "binary_sensor", json!(false))]
+        );
+    }
+}
@@ -0,0 +1,239 @@
+// systemd.service -- https://www.freedesktop.org/software/systemd/man/latest/systemd.service.html
+// sd_notify -- https://www.freedesktop.org/software/systemd/man/latest/sd_notify.html
+use std::env;
+use std::fs::{self, OpenOptions, Permissions};
+use std::io::{self, Write};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::{self, Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+
+use crate::config::Config;
+
+pub const UNIT_NAME: &str = "ha-agent-rs.service";
+
+/// A user unit that restarts the agent when it fails or stops pinging the watchdog, and keeps it
+/// from writing anywhere but next to its state file and the files and pipes it mirrors to.
+pub fn unit_file(
+    exe: &Path,
+    env_file: &Path,
+    state_file: &Path,
+    config_file: &Path,
+    mirror_paths: &[PathBuf],
+    watchdog_sec: u64,
+) -> String {
+    let state_dir = state_file.parent().unwrap_or(Path::new("/"));
+    let mut writable = vec![format!("\"{}\"", state_dir.display())];
+    for dir in mirror_paths.iter().filter_map(|path| path.parent()) {
+        // the - lets the service start while a mirror's directory doesn't exist (yet)
+        let dir = format!("\"-{}\"", dir.display());
+        if !writable.contains(&dir) {
+            writable.push(dir);
+        }
+    }
+    format!(
+        "[Unit]
+Description=Home Assistant Agent
+After=graphical-session.target
+
+[Service]
+Type=notify
+NotifyAccess=main
+ExecStart=\"{exe}\" --state-file \"{state_file}\" --config \"{config_file}\" run
+ExecReload=kill -HUP $MAINPID
+EnvironmentFile={env_file}
+Restart=on-failure
+RestartSec=10
+WatchdogSec={watchdog_sec}
+
+# commands run by the agent can't gain privileges either, remove this if an
+# update_command uses sudo or pkexec
+NoNewPrivileges=yes
+ProtectSystem=strict
+ProtectHome=read-only
+ReadWritePaths={writable}
+PrivateTmp=yes
+ProtectKernelTunables=yes
+ProtectKernelModules=yes
+ProtectControlGroups=yes
+RestrictSUIDSGID=yes
+RestrictRealtime=yes
+LockPersonality=yes
+MemoryDenyWriteExecute=yes
+SystemCallArchitectures=native
+
+[Install]
+WantedBy=default.target
+",
+        exe = exe.display(),
+        state_file = state_file.display(),
+        config_file = config_file.display(),
+        env_file = env_file.display(),
+        writable = writable.join(" "),
+    )
+}
+
+/// Relative and empty values don't count, as per the XDG Base Directory spec.
+fn config_home() -> Result<PathBuf, Error> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .ok_or(anyhow!("Neither XDG_CONFIG_HOME nor HOME is set"))
+}
+
+/// Writes the unit, and the URL, token and TLS settings to an environment file only the user can
+/// read.
+pub fn install(config: &Config, watchdog_sec: u64) -> Result<(), Error> {
+    let config_home = config_home()?;
+    let env_file = config_home.join("ha-agent-rs").join("env");
+    let unit_path = config_home.join("systemd").join("user").join(UNIT_NAME);
+    let mirror_paths = config
+        .monitors
+        .mirrors
+        .iter()
+        .filter_map(|mirror| mirror.path.as_ref())
+        .map(path::absolute)
+        .collect::<Result<Vec<_>, _>>()?;
+    let unit = unit_file(
+        &env::current_exe()?,
+        &env_file,
+        &path::absolute(&config.state_file)?,
+        &path::absolute(&config.config_file)?,
+        &mirror_paths,
+        watchdog_sec,
+    );
+
+    fs::create_dir_all(config_home.join("ha-agent-rs"))?;
+    let mut credentials = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&env_file)?;
+    // the mode only applies to new files
+    credentials.set_permissions(Permissions::from_mode(0o600))?;
+    writeln!(credentials, "HASS_URL={}", config.hass_url)?;
+    writeln!(credentials, "HASS_TOKEN={}", config.hass_token)?;
+    writeln!(credentials, "HAARS_WINDOW_TITLE={}", config.window_title)?;
+    let transport = &config.transport;
+    let paths = [
+        ("HAARS_CA_FILE", &transport.ca_file),
+        ("HAARS_CLIENT_CERT", &transport.client_cert),
+        ("HAARS_CLIENT_KEY", &transport.client_key),
+    ];
+    for (name, path) in paths {
+        if let Some(path) = path {
+            writeln!(credentials, "{}={}", name, path::absolute(path)?.display())?;
+        }
+    }
+    if transport.insecure {
+        writeln!(credentials, "HAARS_INSECURE=1")?;
+    }
+    println!("Wrote {}", env_file.display());
+
+    fs::create_dir_all(config_home.join("systemd").join("user"))?;
+    fs::write(&unit_path, unit)?;
+    println!("Wrote {}", unit_path.display());
+    println!(
+        "Start it with: systemctl --user daemon-reload && systemctl --user enable --now {}",
+        UNIT_NAME
+    );
+    Ok(())
+}
+
+fn send(socket: &str, state: &str) -> io::Result<()> {
+    let address = match socket.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+/// Tells systemd about the agent, e.g. `READY=1` or `STATUS=...`. Does nothing when it wasn't
+/// started by systemd.
+pub fn notify(state: &str) {
+    if let Some(socket) = env::var_os("NOTIFY_SOCKET") {
+        if let Err(e) = send(&socket.to_string_lossy(), state) {
+            println!("Failed to notify systemd: {}", e);
+        }
+    }
+}
+
+/// Half the watchdog timeout, so a ping that's a little late doesn't get the agent restarted.
+fn parse_watchdog(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid.parse() != Ok(own_pid)) {
+        return None;
+    }
+    let usec: u64 = usec?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// How often to send `WATCHDOG=1`, if systemd expects it.
+pub fn watchdog_interval() -> Option<Duration> {
+    parse_watchdog(
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+        env::var("WATCHDOG_PID").ok().as_deref(),
+        process::id(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_unit_file() {
+        let unit = unit_file(
+            Path::new("/home/alex/.cargo/bin/ha-agent-rs"),
+            Path::new("/home/alex/.config/ha-agent-rs/env"),
+            Path::new("/home/alex/.local/state/haars.json"),
+            Path::new("/home/alex/haars.toml"),
+            &[
+                PathBuf::from("/run/user/1000/ha-waybar"),
+                PathBuf::from("/run/user/1000/ha-i3bar"),
+            ],
+            30,
+        );
+
+        assert!(unit.contains("ExecStart=\"/home/alex/.cargo/bin/ha-agent-rs\" --state-file"));
+        assert!(unit.contains("WatchdogSec=30\n"));
+        assert!(unit.contains("ReadWritePaths=\"/home/alex/.local/state\" \"-/run/user/1000\"\n"));
+        assert!(unit.contains("EnvironmentFile=/home/alex/.config/ha-agent-rs/env\n"));
+    }
+
+    #[test]
+    fn test_send() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        send(path.to_str().unwrap(), "READY=1").unwrap();
+
+        let mut buffer = [0; 64];
+        let length = socket.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"READY=1");
+    }
+
+    #[test]
+    fn test_parse_watchdog() {
+        assert_eq!(
+            parse_watchdog(Some("60000000"), None, 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_watchdog(Some("60000000"), Some("42"), 42),
+            Some(Duration::from_secs(30))
+        );
+        // meant for another process, e.g. the shell the agent was started from
+        assert_eq!(parse_watchdog(Some("60000000"), Some("7"), 42), None);
+        assert_eq!(parse_watchdog(None, None, 42), None);
+        assert_eq!(parse_watchdog(Some("0"), None, 42), None);
+    }
+}